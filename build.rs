@@ -1,16 +1,258 @@
+use std::collections::BTreeMap;
 use std::env;
-use anyhow::*;
-use fs_extra::{copy_items, dir::CopyOptions};
+use std::fs;
+use std::path::{Path, PathBuf};
+use anyhow::{bail, Context, Result};
 
-fn main() -> Result<()> {
-	println!("cargo:rerun-if-changed=res/*");
+// The modules shared with the renderer profile themselves, which doesn't apply here
+macro_rules! profile_scope {
+	($($args:tt)*) => {};
+}
+
+#[path = "src/bounds.rs"]
+#[allow(dead_code)]
+mod bounds;
+
+#[path = "src/hash.rs"]
+mod hash;
+
+#[path = "src/loader.rs"]
+#[allow(dead_code)]
+mod loader;
+
+#[path = "src/mesh.rs"]
+#[allow(dead_code)]
+mod mesh;
+
+#[path = "src/mesh_cache.rs"]
+#[allow(dead_code)]
+mod mesh_cache;
+
+#[path = "src/preprocessor.rs"]
+#[allow(dead_code)]
+mod preprocessor;
+
+#[path = "src/shader_interface.rs"]
+#[allow(dead_code)]
+mod shader_interface;
+
+#[path = "src/shader_validation.rs"]
+mod shader_validation;
+
+use preprocessor::Defines;
+
+/// Every way of picking at most one define from each group, starting with picking none.
+fn define_combinations(groups: &[&[&'static str]]) -> Vec<Vec<&'static str>> {
+	let mut combinations = vec![Vec::new()];
+	for group in groups {
+		let mut extended = Vec::new();
+		for combination in &combinations {
+			extended.push(combination.clone());
+			for define in group.iter() {
+				let mut with = combination.clone();
+				with.push(*define);
+				extended.push(with);
+			}
+		}
+		combinations = extended;
+	}
+	combinations
+}
+
+/// Expands every top level shader with each combination of defines the renderer can load
+/// it with, as listed in `shader_interface`, so broken includes and conditionals fail the
+/// build instead of at runtime. Each variant is then validated with naga and checked
+/// against the interface.
+fn check_shaders(out_dir: &Path) -> Result<()> {
+	let shader_dir = Path::new("src/shaders");
+	println!("cargo:rerun-if-changed={}", shader_dir.display());
+	println!("cargo:rerun-if-changed=src/preprocessor.rs");
+	println!("cargo:rerun-if-changed=src/shader_interface.rs");
+	println!("cargo:rerun-if-changed=src/shader_validation.rs");
+
+	let shaders_out = out_dir.join("shaders");
+	std::fs::create_dir_all(&shaders_out)?;
+
+	for entry in glob::glob("src/shaders/**/*.wgsl")? {
+		println!("cargo:rerun-if-changed={}", entry?.display());
+	}
+
+	for entry in glob::glob("src/shaders/*.wgsl")? {
+		let path = entry?;
+		let name = path.file_name().context("Shader has no file name")?.to_string_lossy();
+		let source = std::fs::read_to_string(&path)?;
+
+		let interface = shader_interface::SHADERS
+			.iter()
+			.find(|interface| interface.name == name)
+			.with_context(|| format!("{} has no entry in src/shader_interface.rs", name))?;
+
+		for toggle in preprocessor::conditionals(&source) {
+			if !interface.defines.iter().any(|group| group.contains(&toggle.as_str())) {
+				bail!(
+					"{} checks {}, which its entry in src/shader_interface.rs doesn't list",
+					name,
+					toggle
+				);
+			}
+		}
+
+		for combination in define_combinations(interface.defines) {
+			let variant = if combination.is_empty() {
+				String::from("default")
+			} else {
+				combination.join("-").to_lowercase()
+			};
+			let defines = combination
+				.iter()
+				.fold(Defines::new(), |defines, define| defines.with(define));
+			let expanded = preprocessor::preprocess(&name, &defines, |file| {
+				std::fs::read_to_string(shader_dir.join(file))
+					.with_context(|| format!("Could not read {}", file))
+			})
+			.with_context(|| format!("Failed to preprocess {} ({})", name, variant))?;
+
+			let stem = path.file_stem().context("Shader has no file stem")?.to_string_lossy();
+			let expanded_path = shaders_out.join(format!("{}.{}.wgsl", stem, variant));
+			std::fs::write(&expanded_path, &expanded)?;
 
+			shader_validation::validate(&expanded, interface).with_context(|| {
+				format!("{} ({}) is invalid, expanded source at {:?}", name, variant, expanded_path)
+			})?;
+		}
+	}
+
+	Ok(())
+}
+
+/// A resource's size and content hash, keyed in the manifest by its path in `resources/`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+struct ManifestEntry {
+	size: u64,
+	hash: u64,
+}
+
+/// Reads the manifest of the last build, which is empty if there wasn't one.
+fn read_manifest(path: &Path) -> BTreeMap<String, ManifestEntry> {
+	let text = fs::read_to_string(path).unwrap_or_default();
+	text.lines()
+		.filter(|line| !line.starts_with('#'))
+		.filter_map(|line| {
+			let mut fields = line.split('\t');
+			let key = fields.next()?;
+			let size = fields.next()?.parse().ok()?;
+			let hash = u64::from_str_radix(fields.next()?, 16).ok()?;
+			Some((key.to_string(), ManifestEntry { size, hash }))
+		})
+		.collect()
+}
+
+fn write_manifest(path: &Path, manifest: &BTreeMap<String, ManifestEntry>) -> Result<()> {
+	let mut text = String::from("# path\tsize\tfnv1a\n");
+	for (key, entry) in manifest {
+		text.push_str(&format!("{}\t{}\t{:016x}\n", key, entry.size, entry.hash));
+	}
+	fs::write(path, text).with_context(|| format!("Could not write {:?}", path))
+}
+
+fn walk(dir: &Path, files: &mut Vec<PathBuf>) -> Result<()> {
+	for entry in fs::read_dir(dir).with_context(|| format!("Could not read {:?}", dir))? {
+		let path = entry?.path();
+		if path.is_dir() {
+			walk(&path, files)?;
+		} else {
+			files.push(path);
+		}
+	}
+	Ok(())
+}
+
+/// Builds the mesh cache of each OBJ in `objs` that doesn't have a fresh one, so even the
+/// first load skips parsing. Textures aren't preprocessed, as the renderer has no use for
+/// mipmaps yet.
+fn preprocess_meshes(objs: &[PathBuf]) -> Result<()> {
+	for obj in objs {
+		if let Ok(Some(_)) = mesh_cache::read(obj) {
+			continue;
+		}
+		let (meshes, materials, mtl_libs) = mesh::parse_obj(obj, &loader::Progress::default())
+			.with_context(|| format!("Could not preprocess {:?}", obj))?;
+		mesh_cache::write(obj, &mtl_libs, &meshes, &materials)?;
+	}
+	Ok(())
+}
+
+/// Copies `resources/` into `OUT_DIR`, where the renderer loads it from. Only files whose
+/// contents changed since the last build are copied, and files deleted from `resources/`
+/// are deleted from the copy. Anything else in the copy, like mesh caches written at
+/// runtime, is left alone. Writes `asset_manifest.txt` listing every file's hash. With
+/// the `preprocess-assets` feature, the copied OBJs' mesh caches are built too.
+fn build_assets(out_dir: &Path) -> Result<()> {
+	let source_dir = Path::new("resources");
+	// The directory itself, so adding or removing a file reruns this too
+	println!("cargo:rerun-if-changed={}", source_dir.display());
+	for shared in ["bounds", "hash", "loader", "mesh", "mesh_cache"].iter() {
+		println!("cargo:rerun-if-changed=src/{}.rs", shared);
+	}
+
+	let mut files = Vec::new();
+	walk(source_dir, &mut files)?;
+	files.sort();
+
+	let manifest_path = out_dir.join("asset_manifest.txt");
+	let previous = read_manifest(&manifest_path);
+	let target_dir = out_dir.join("resources");
+	let mut manifest = BTreeMap::new();
+	let mut objs = Vec::new();
+
+	for file in files {
+		println!("cargo:rerun-if-changed={}", file.display());
+		let relative = file.strip_prefix(source_dir)?;
+		let key = relative
+			.components()
+			.map(|component| component.as_os_str().to_string_lossy())
+			.collect::<Vec<_>>()
+			.join("/");
+
+		let bytes = fs::read(&file).with_context(|| format!("Could not read {:?}", file))?;
+		let entry = ManifestEntry {
+			size: bytes.len() as u64,
+			hash: hash::fnv1a(&bytes),
+		};
+		let target = target_dir.join(relative);
+		if previous.get(&key) != Some(&entry) || !target.exists() {
+			if let Some(parent) = target.parent() {
+				fs::create_dir_all(parent)?;
+			}
+			fs::write(&target, &bytes).with_context(|| format!("Could not copy {:?}", file))?;
+		}
+		if target.extension().is_some_and(|extension| extension == "obj") {
+			objs.push(target);
+		}
+		manifest.insert(key, entry);
+	}
+
+	for key in previous.keys().filter(|key| !manifest.contains_key(*key)) {
+		let stale = target_dir.join(key);
+		// Along with the mesh cache, if it was a model
+		for stale in [mesh_cache::cache_path(&stale), stale].iter() {
+			if stale.exists() {
+				fs::remove_file(stale).with_context(|| format!("Could not remove {:?}", stale))?;
+			}
+		}
+	}
+
+	if env::var_os("CARGO_FEATURE_PREPROCESS_ASSETS").is_some() {
+		preprocess_meshes(&objs)?;
+	}
+
+	write_manifest(&manifest_path, &manifest)
+}
+
+fn main() -> Result<()> {
 	let out_dir = env::var("OUT_DIR")?;
-	let mut copy_options = CopyOptions::new();
-	copy_options.overwrite = true;
-    let mut paths_to_copy = Vec::new();
-    paths_to_copy.push("resources/");
-    copy_items(&paths_to_copy, out_dir, &copy_options)?;
+	check_shaders(Path::new(&out_dir))?;
+	build_assets(Path::new(&out_dir))?;
 
 	Ok(())
 }
\ No newline at end of file
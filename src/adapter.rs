@@ -0,0 +1,177 @@
+use anyhow::{bail, Context, Result};
+
+/// Which adapter to render with.
+#[derive(Clone, Debug, PartialEq)]
+pub enum AdapterSelector {
+    /// The best adapter able to present to the window, ranked by power preference.
+    Auto,
+    /// Index into the list printed by `--list-adapters`.
+    Index(usize),
+    /// The first adapter whose name contains this, ignoring case.
+    Name(String),
+    /// The best adapter on this backend.
+    Backend(wgpu::Backend),
+}
+
+impl AdapterSelector {
+    pub fn parse(value: &str) -> Self {
+        if let Ok(index) = value.parse() {
+            return Self::Index(index);
+        }
+
+        match parse_backend(value) {
+            Some(backend) => Self::Backend(backend),
+            None => Self::Name(value.to_owned()),
+        }
+    }
+
+    fn matches(&self, index: usize, info: &wgpu::AdapterInfo) -> bool {
+        match self {
+            Self::Auto => true,
+            Self::Index(i) => *i == index,
+            Self::Name(name) => info.name.to_lowercase().contains(&name.to_lowercase()),
+            Self::Backend(backend) => info.backend == *backend,
+        }
+    }
+}
+
+#[derive(Clone)]
+pub struct AdapterOptions {
+    pub selector: AdapterSelector,
+    pub power_preference: wgpu::PowerPreference,
+    /// Lets automatic selection pick a software rasteriser such as llvmpipe or lavapipe.
+    /// Adapters chosen by index, name or backend are always allowed.
+    pub allow_software: bool,
+}
+
+impl Default for AdapterOptions {
+    fn default() -> Self {
+        Self {
+            selector: AdapterSelector::Auto,
+            power_preference: wgpu::PowerPreference::HighPerformance,
+            allow_software: false,
+        }
+    }
+}
+
+fn parse_backend(value: &str) -> Option<wgpu::Backend> {
+    Some(match value.to_lowercase().as_str() {
+        "vulkan" => wgpu::Backend::Vulkan,
+        "metal" => wgpu::Backend::Metal,
+        "dx12" => wgpu::Backend::Dx12,
+        "dx11" => wgpu::Backend::Dx11,
+        "gl" => wgpu::Backend::Gl,
+        _ => return None,
+    })
+}
+
+fn is_software(info: &wgpu::AdapterInfo) -> bool {
+    info.device_type == wgpu::DeviceType::Cpu
+}
+
+/// Lower is better.
+fn rank(device_type: wgpu::DeviceType, power_preference: wgpu::PowerPreference) -> u32 {
+    use wgpu::DeviceType::*;
+
+    match (power_preference, device_type) {
+        (wgpu::PowerPreference::HighPerformance, DiscreteGpu) => 0,
+        (wgpu::PowerPreference::HighPerformance, IntegratedGpu) => 1,
+        (wgpu::PowerPreference::LowPower, IntegratedGpu) => 0,
+        (wgpu::PowerPreference::LowPower, DiscreteGpu) => 1,
+        (_, VirtualGpu) => 2,
+        (_, Other) => 3,
+        (_, Cpu) => 4,
+    }
+}
+
+/// One line summary of an adapter, as used by `--list-adapters` and the startup report.
+pub fn describe(index: usize, info: &wgpu::AdapterInfo) -> String {
+    format!(
+        "[{}] {} ({:?}, {:?}, vendor {:#06x}, device {:#06x})",
+        index, info.name, info.backend, info.device_type, info.vendor, info.device
+    )
+}
+
+fn describe_limits(limits: &wgpu::Limits) -> String {
+    format!(
+        "{} bind groups, 2D textures up to {}, {} vertex buffers, {} vertex attributes",
+        limits.max_bind_groups,
+        limits.max_texture_dimension_2d,
+        limits.max_vertex_buffers,
+        limits.max_vertex_attributes,
+    )
+}
+
+/// Prints every adapter on `backends` with its features and limits.
+pub fn list(backends: wgpu::BackendBit) {
+    let instance = wgpu::Instance::new(backends);
+    let mut found = false;
+
+    for (index, adapter) in instance.enumerate_adapters(backends).enumerate() {
+        found = true;
+        println!("{}", describe(index, &adapter.get_info()));
+        println!("    features: {:?}", adapter.features());
+        println!("    limits: {}", describe_limits(&adapter.limits()));
+    }
+
+    if !found {
+        println!("No adapters found");
+    }
+}
+
+/// Picks an adapter able to present to `surface`, logging what was found and why it was
+/// chosen.
+pub fn select(
+    instance: &wgpu::Instance,
+    surface: &wgpu::Surface,
+    backends: wgpu::BackendBit,
+    options: &AdapterOptions,
+) -> Result<wgpu::Adapter> {
+    let adapters: Vec<_> = instance.enumerate_adapters(backends).collect();
+    if adapters.is_empty() {
+        bail!("No adapters found for backends {:?}", backends);
+    }
+
+    log::info!("Found {} adapter(s):", adapters.len());
+    let mut candidates = Vec::new();
+    for (index, adapter) in adapters.iter().enumerate() {
+        let info = adapter.get_info();
+        let skipped = if !options.selector.matches(index, &info) {
+            Some("not selected")
+        } else if adapter.get_swap_chain_preferred_format(surface).is_none() {
+            Some("can't present to the window")
+        } else if options.selector == AdapterSelector::Auto
+            && is_software(&info)
+            && !options.allow_software
+        {
+            Some("software, pass --allow-software to use it")
+        } else {
+            None
+        };
+
+        match skipped {
+            Some(reason) => log::info!("  {}: {}", describe(index, &info), reason),
+            None => {
+                log::info!("  {}", describe(index, &info));
+                candidates.push((index, info));
+            }
+        }
+    }
+
+    let (index, info) = candidates
+        .into_iter()
+        .min_by_key(|(index, info)| (rank(info.device_type, options.power_preference), *index))
+        .with_context(|| format!("No usable adapter matches {:?}", options.selector))?;
+
+    let adapter = adapters.into_iter().nth(index).unwrap();
+    let limits = adapter.limits();
+    log::info!("Using adapter {}", describe(index, &info));
+    log::info!("  features: {:?}", adapter.features());
+    log::info!("  limits: {}", describe_limits(&limits));
+    log::debug!("  all limits: {:#?}", limits);
+    if is_software(&info) {
+        log::warn!("Rendering on a software adapter, expect it to be slow");
+    }
+
+    Ok(adapter)
+}
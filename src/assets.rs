@@ -0,0 +1,210 @@
+//! Textures and materials shared between everything that uses them. Each is loaded once
+//! and handed out as a reference counted handle, and stays cached until it's evicted.
+//! Images aren't kept once uploaded. Should the device be lost, their files are decoded again.
+
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::rc::Rc;
+
+use anyhow::*;
+
+use crate::model::Material;
+use crate::texture::Texture;
+
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+enum TextureSource {
+    /// Canonical path of an image file.
+    File(PathBuf),
+    /// A single texel of a colour.
+    Colour([u8; 4]),
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+struct TextureKey {
+    source: TextureSource,
+    is_normal_map: bool,
+}
+
+/// Materials are named within their MTL file, and exporters reuse names like `Material`
+/// in every file they write.
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+struct MaterialKey {
+    /// Canonical path of the MTL file, or empty for built in materials.
+    library: PathBuf,
+    name: String,
+}
+
+/// An image read and decoded off the render thread, waiting to be uploaded.
+pub struct DecodedImage {
+    /// Canonical, so the same file is recognised however it was reached.
+    pub path: PathBuf,
+    pub image: image::DynamicImage,
+}
+
+impl DecodedImage {
+    pub fn open(path: &Path) -> Result<Self> {
+        profile_scope!("DecodedImage::open", "{}", path.display());
+        let path = path
+            .canonicalize()
+            .with_context(|| format!("Could not find image {:?}", path))?;
+        let image = image::open(&path).with_context(|| format!("Could not decode {:?}", path))?;
+        Ok(Self { path, image })
+    }
+}
+
+/// How many of each asset are cached, and how many of those are in use.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct AssetStats {
+    pub textures: usize,
+    pub textures_in_use: usize,
+    pub materials: usize,
+    pub materials_in_use: usize,
+}
+
+#[derive(Default)]
+pub struct AssetCache {
+    textures: HashMap<TextureKey, Rc<Texture>>,
+    materials: HashMap<MaterialKey, Rc<Material>>,
+}
+
+impl AssetCache {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// The image file at the canonical `path` as a texture, uploaded the first time it's
+    /// asked for with `is_normal_map`. Uploaded from `image` when that's given, otherwise
+    /// the file is decoded again. Later requests get the same texture.
+    pub fn texture(
+        &mut self,
+        device: &wgpu::Device,
+        queue: &wgpu::Queue,
+        path: &Path,
+        image: Option<&DecodedImage>,
+        is_normal_map: bool,
+    ) -> Result<Rc<Texture>> {
+        let key = TextureKey {
+            source: TextureSource::File(path.to_path_buf()),
+            is_normal_map,
+        };
+        if let Some(texture) = self.textures.get(&key) {
+            return Ok(Rc::clone(texture));
+        }
+
+        let texture = match image {
+            Some(image) => Self::upload_image(device, queue, image, is_normal_map)?,
+            None => Self::upload(device, queue, &key)?,
+        };
+        let texture = Rc::new(texture);
+        self.textures.insert(key, Rc::clone(&texture));
+        Ok(texture)
+    }
+
+    /// A one texel texture of `colour`, for materials missing a map.
+    pub fn colour_texture(
+        &mut self,
+        device: &wgpu::Device,
+        queue: &wgpu::Queue,
+        colour: [u8; 4],
+        is_normal_map: bool,
+    ) -> Result<Rc<Texture>> {
+        let key = TextureKey {
+            source: TextureSource::Colour(colour),
+            is_normal_map,
+        };
+        if let Some(texture) = self.textures.get(&key) {
+            return Ok(Rc::clone(texture));
+        }
+
+        let texture = Rc::new(Self::upload(device, queue, &key)?);
+        self.textures.insert(key, Rc::clone(&texture));
+        Ok(texture)
+    }
+
+    fn upload(device: &wgpu::Device, queue: &wgpu::Queue, key: &TextureKey) -> Result<Texture> {
+        match &key.source {
+            TextureSource::File(path) => {
+                let image = DecodedImage::open(path)?;
+                Self::upload_image(device, queue, &image, key.is_normal_map)
+            }
+            TextureSource::Colour(colour) => {
+                let label = format!("Colour texture {:?}", colour);
+                Texture::from_colour(device, queue, *colour, &label, key.is_normal_map)
+            }
+        }
+    }
+
+    fn upload_image(
+        device: &wgpu::Device,
+        queue: &wgpu::Queue,
+        image: &DecodedImage,
+        is_normal_map: bool,
+    ) -> Result<Texture> {
+        Texture::from_image(device, queue, &image.image, image.path.to_str(), is_normal_map)
+    }
+
+    /// Uploads every cached texture again, to `device` in place of the lost one they were
+    /// on, decoding their files again on this thread. Materials are dropped rather than
+    /// rebuilt, they are made again from their model's data as it's uploaded.
+    pub fn reupload(&mut self, device: &wgpu::Device, queue: &wgpu::Queue) -> Result<()> {
+        profile_scope!("AssetCache::reupload");
+        self.materials.clear();
+        let keys = self.textures.keys().cloned().collect::<Vec<_>>();
+        for key in keys {
+            let texture = Rc::new(Self::upload(device, queue, &key)?);
+            self.textures.insert(key, texture);
+        }
+        Ok(())
+    }
+
+    /// The material `name` from the MTL file at the canonical path `library`, built with
+    /// `load` the first time it's asked for. `load` gets the cache back to share textures
+    /// through.
+    pub fn material<F>(&mut self, library: &Path, name: &str, load: F) -> Result<Rc<Material>>
+    where
+        F: FnOnce(&mut Self) -> Result<Material>,
+    {
+        let key = MaterialKey {
+            library: library.to_path_buf(),
+            name: name.to_string(),
+        };
+        if let Some(material) = self.materials.get(&key) {
+            return Ok(Rc::clone(material));
+        }
+
+        let material = Rc::new(load(self)?);
+        self.materials.insert(key, Rc::clone(&material));
+        Ok(material)
+    }
+
+    /// Drops every asset only the cache still holds. Materials go first, so the textures
+    /// they were keeping alive go too. Returns how many assets were evicted.
+    pub fn evict_unused(&mut self) -> usize {
+        let before = self.materials.len() + self.textures.len();
+        self.materials
+            .retain(|_, material| Rc::strong_count(material) > 1);
+        self.textures.retain(|_, texture| Rc::strong_count(texture) > 1);
+        let evicted = before - self.materials.len() - self.textures.len();
+        if evicted > 0 {
+            log::info!("Evicted {} unused assets", evicted);
+        }
+        evicted
+    }
+
+    pub fn stats(&self) -> AssetStats {
+        AssetStats {
+            textures: self.textures.len(),
+            textures_in_use: self
+                .textures
+                .values()
+                .filter(|texture| Rc::strong_count(texture) > 1)
+                .count(),
+            materials: self.materials.len(),
+            materials_in_use: self
+                .materials
+                .values()
+                .filter(|material| Rc::strong_count(material) > 1)
+                .count(),
+        }
+    }
+}
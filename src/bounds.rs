@@ -0,0 +1,82 @@
+use cgmath::{EuclideanSpace, Matrix4, Point3, Transform};
+
+/// An axis aligned bounding box.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Aabb {
+    pub min: Point3<f32>,
+    pub max: Point3<f32>,
+}
+
+impl Aabb {
+    /// Bounds of nothing, which any point or box can be added to.
+    pub fn empty() -> Self {
+        Self {
+            min: Point3::new(f32::INFINITY, f32::INFINITY, f32::INFINITY),
+            max: Point3::new(f32::NEG_INFINITY, f32::NEG_INFINITY, f32::NEG_INFINITY),
+        }
+    }
+
+    pub fn from_points<I: IntoIterator<Item = Point3<f32>>>(points: I) -> Self {
+        let mut aabb = Self::empty();
+        for point in points {
+            aabb.add_point(point);
+        }
+        aabb
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.min.x > self.max.x || self.min.y > self.max.y || self.min.z > self.max.z
+    }
+
+    pub fn add_point(&mut self, point: Point3<f32>) {
+        self.min = Point3::new(
+            self.min.x.min(point.x),
+            self.min.y.min(point.y),
+            self.min.z.min(point.z),
+        );
+        self.max = Point3::new(
+            self.max.x.max(point.x),
+            self.max.y.max(point.y),
+            self.max.z.max(point.z),
+        );
+    }
+
+    pub fn union(&self, other: &Aabb) -> Aabb {
+        let mut aabb = *self;
+        if !other.is_empty() {
+            aabb.add_point(other.min);
+            aabb.add_point(other.max);
+        }
+        aabb
+    }
+
+    pub fn centre(&self) -> Point3<f32> {
+        self.min.midpoint(self.max)
+    }
+
+    /// The eight corners, with bit 0 of the index picking x, bit 1 y and bit 2 z from
+    /// `max` rather than `min`.
+    pub fn corners(&self) -> [Point3<f32>; 8] {
+        let mut corners = [self.min; 8];
+        for (i, corner) in corners.iter_mut().enumerate() {
+            *corner = Point3::new(
+                if i & 1 == 0 { self.min.x } else { self.max.x },
+                if i & 2 == 0 { self.min.y } else { self.max.y },
+                if i & 4 == 0 { self.min.z } else { self.max.z },
+            );
+        }
+        corners
+    }
+
+    /// The axis aligned bounds of this box once moved by `transform`.
+    pub fn transformed(&self, transform: &Matrix4<f32>) -> Aabb {
+        if self.is_empty() {
+            return *self;
+        }
+        Self::from_points(
+            self.corners()
+                .iter()
+                .map(|corner| transform.transform_point(*corner)),
+        )
+    }
+}
@@ -1,5 +1,8 @@
+use cgmath::{InnerSpace, SquareMatrix, Transform};
 use winit::event::{ElementState, KeyboardInput, VirtualKeyCode, WindowEvent};
 
+use crate::raycast::Ray;
+
 #[rustfmt::skip]
 pub const OPENGL_TO_WGPU_MATRIX: cgmath::Matrix4<f32> = cgmath::Matrix4::new(
     1.0, 0.0, 0.0, 0.0,
@@ -24,7 +27,22 @@ impl Camera {
 
         let proj = cgmath::perspective(cgmath::Deg(self.fovy), self.aspect, self.znear, self.zfar);
 
-        return OPENGL_TO_WGPU_MATRIX * proj * view;
+        OPENGL_TO_WGPU_MATRIX * proj * view
+    }
+
+    /// The ray from the eye through the centre of pixel `(x, y)` of a `width` by `height`
+    /// view, starting on the near plane.
+    pub fn screen_ray(&self, x: f32, y: f32, width: u32, height: u32) -> Option<Ray> {
+        let inverse = self.build_view_projection_matrix().invert()?;
+        let ndc_x = (x + 0.5) / width as f32 * 2.0 - 1.0;
+        let ndc_y = 1.0 - (y + 0.5) / height as f32 * 2.0;
+
+        let near = inverse.transform_point(cgmath::Point3::new(ndc_x, ndc_y, 0.0));
+        let far = inverse.transform_point(cgmath::Point3::new(ndc_x, ndc_y, 1.0));
+        Some(Ray {
+            origin: near,
+            direction: (far - near).normalize(),
+        })
     }
 }
 
@@ -96,7 +114,6 @@ impl CameraController {
     }
 
     pub fn update_camera(&self, camera: &mut Camera) {
-        use cgmath::InnerSpace;
         let forward = camera.target - camera.eye;
         let forward_norm = forward.normalize();
         let forward_mag = forward.magnitude();
@@ -123,11 +140,11 @@ impl CameraController {
         }
 
         if self.up_pressed {
-            camera.eye = camera.eye + camera.up * self.speed;
+            camera.eye += camera.up * self.speed;
         }
 
         if self.down_pressed {
-            camera.eye = camera.eye - camera.up * self.speed;
+            camera.eye -= camera.up * self.speed;
         }
     }
 }
@@ -0,0 +1,240 @@
+use std::path::PathBuf;
+
+use anyhow::*;
+
+use crate::adapter::AdapterSelector;
+use crate::state::Options;
+
+/// Frame rate cap used when toggling the limiter on without `--max-fps`.
+pub const DEFAULT_MAX_FPS: u32 = 60;
+
+pub const USAGE: &str = "\
+Usage: learn-wgpu [OPTIONS] [MODEL]
+
+Renders MODEL, an OBJ file holding one or more objects, as a grid of instances.
+Defaults to the bundled cube.
+
+Options:
+    --width <PIXELS>          Window width [default: 1920]
+    --height <PIXELS>         Window height [default: 1080]
+    --fullscreen              Open a borderless fullscreen window
+    --backend <BACKEND>       vulkan, metal, dx12, dx11, gl, primary or all [default: primary]
+    --adapter <ADAPTER>       Adapter index, name or backend, see --list-adapters
+    --power-preference <PREF> high or low, used to rank adapters [default: high]
+    --allow-software          Let automatic adapter selection pick a software rasteriser
+    --list-adapters           Print the available adapters and exit
+    --present-mode <MODE>     fifo, mailbox or immediate, cycle with P [default: fifo]
+    --max-fps <FPS>           Cap the frame rate on the CPU, toggle with L [default: uncapped]
+    --msaa <SAMPLES>          Multisample count, 1, 2, 4 or 8 [default: 1]
+    --frames <COUNT>          Render COUNT frames once the model has loaded, then exit
+    --pick <X,Y>              Print what is under pixel X,Y once the model has loaded
+    --lose-device <FRAME>     Simulate losing the device after FRAME frames, to test recovery
+    --skybox <PATH|none>      Equirectangular HDR image or directory of cube faces
+    --trace <FILE>            Profile the CPU and write a Chrome trace to FILE on exit
+    --watch-shaders           Load shaders from src/shaders and reload them on change
+    -h, --help                Print this message
+";
+
+pub struct Args {
+    pub width: u32,
+    pub height: u32,
+    pub fullscreen: bool,
+    pub frames: Option<u64>,
+    pub pick: Option<(u32, u32)>,
+    pub lose_device: Option<u64>,
+    pub max_fps: Option<u32>,
+    pub list_adapters: bool,
+    pub trace: Option<PathBuf>,
+    pub options: Options,
+}
+
+impl Default for Args {
+    fn default() -> Self {
+        Self {
+            width: 1920,
+            height: 1080,
+            fullscreen: false,
+            frames: None,
+            pick: None,
+            lose_device: None,
+            max_fps: None,
+            list_adapters: false,
+            trace: None,
+            options: Options::default(),
+        }
+    }
+}
+
+impl Args {
+    /// Parses the process arguments. Returns `None` when only help was asked for.
+    pub fn parse() -> Result<Option<Self>> {
+        Self::parse_from(std::env::args().skip(1))
+    }
+
+    pub fn parse_from<I: IntoIterator<Item = String>>(args: I) -> Result<Option<Self>> {
+        let mut parsed = Self::default();
+        let mut args = args.into_iter();
+
+        while let Some(arg) = args.next() {
+            let mut value = || {
+                args.next()
+                    .with_context(|| format!("{} needs a value", arg))
+            };
+
+            match arg.as_str() {
+                "-h" | "--help" => return Ok(None),
+                "--width" => parsed.width = parse_number(&arg, &value()?)?,
+                "--height" => parsed.height = parse_number(&arg, &value()?)?,
+                "--fullscreen" => parsed.fullscreen = true,
+                "--backend" => parsed.options.backends = parse_backend(&value()?)?,
+                "--adapter" => parsed.options.adapter.selector = AdapterSelector::parse(&value()?),
+                "--power-preference" => {
+                    parsed.options.adapter.power_preference = parse_power_preference(&value()?)?
+                }
+                "--allow-software" => parsed.options.adapter.allow_software = true,
+                "--list-adapters" => parsed.list_adapters = true,
+                "--present-mode" => parsed.options.present_mode = parse_present_mode(&value()?)?,
+                "--msaa" => {
+                    parsed.options.sample_count = match parse_number(&arg, &value()?)? {
+                        count @ 1 | count @ 2 | count @ 4 | count @ 8 => count,
+                        count => bail!("--msaa must be 1, 2, 4 or 8, not {}", count),
+                    }
+                }
+                "--max-fps" => {
+                    parsed.max_fps = match parse_number(&arg, &value()?)? {
+                        0 => bail!("--max-fps must be greater than 0"),
+                        fps => Some(fps),
+                    }
+                }
+                "--frames" => parsed.frames = Some(parse_number(&arg, &value()?)?),
+                "--pick" => parsed.pick = Some(parse_pixel(&arg, &value()?)?),
+                "--lose-device" => parsed.lose_device = Some(parse_number(&arg, &value()?)?),
+                "--skybox" => {
+                    parsed.options.skybox = match value()?.as_str() {
+                        "none" => None,
+                        path => Some(PathBuf::from(path)),
+                    }
+                }
+                "--trace" => parsed.trace = Some(PathBuf::from(value()?)),
+                "--watch-shaders" => parsed.options.watch_shaders = true,
+                flag if flag.starts_with('-') => bail!("Unknown option {}", flag),
+                path => {
+                    if parsed.options.model.is_some() {
+                        bail!("Only one model can be given, got {} as well", path);
+                    }
+                    parsed.options.model = Some(PathBuf::from(path));
+                }
+            }
+        }
+
+        if parsed.width == 0 || parsed.height == 0 {
+            bail!("The window size must not be zero");
+        }
+
+        Ok(Some(parsed))
+    }
+}
+
+fn parse_number<T: std::str::FromStr>(arg: &str, value: &str) -> Result<T> {
+    value
+        .parse()
+        .map_err(|_| anyhow!("{} expects a number, got {}", arg, value))
+}
+
+fn parse_pixel(arg: &str, value: &str) -> Result<(u32, u32)> {
+    let (x, y) = value
+        .split_once(',')
+        .with_context(|| format!("{} expects X,Y, got {}", arg, value))?;
+    Ok((parse_number(arg, x.trim())?, parse_number(arg, y.trim())?))
+}
+
+fn parse_backend(value: &str) -> Result<wgpu::BackendBit> {
+    Ok(match value.to_lowercase().as_str() {
+        "vulkan" => wgpu::BackendBit::VULKAN,
+        "metal" => wgpu::BackendBit::METAL,
+        "dx12" => wgpu::BackendBit::DX12,
+        "dx11" => wgpu::BackendBit::DX11,
+        "gl" => wgpu::BackendBit::GL,
+        "primary" => wgpu::BackendBit::PRIMARY,
+        "all" => wgpu::BackendBit::all(),
+        _ => bail!("Unknown backend {}", value),
+    })
+}
+
+fn parse_power_preference(value: &str) -> Result<wgpu::PowerPreference> {
+    Ok(match value.to_lowercase().as_str() {
+        "high" => wgpu::PowerPreference::HighPerformance,
+        "low" => wgpu::PowerPreference::LowPower,
+        _ => bail!("Unknown power preference {}", value),
+    })
+}
+
+fn parse_present_mode(value: &str) -> Result<wgpu::PresentMode> {
+    Ok(match value.to_lowercase().as_str() {
+        "fifo" => wgpu::PresentMode::Fifo,
+        "mailbox" => wgpu::PresentMode::Mailbox,
+        "immediate" => wgpu::PresentMode::Immediate,
+        _ => bail!("Unknown present mode {}", value),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(args: &[&str]) -> Result<Option<Args>> {
+        Args::parse_from(args.iter().map(|arg| arg.to_string()))
+    }
+
+    #[test]
+    fn defaults() {
+        let args = parse(&[]).unwrap().unwrap();
+        assert_eq!((args.width, args.height), (1920, 1080));
+        assert_eq!(args.frames, None);
+        assert_eq!(args.options.sample_count, 1);
+        assert_eq!(args.options.model, None);
+    }
+
+    #[test]
+    fn options_and_model() {
+        let args = parse(&[
+            "--width", "640", "--height", "480", "--msaa", "4", "--frames", "3", "--pick", "10, 20",
+            "--max-fps", "30", "--present-mode", "Mailbox", "--skybox", "none", "--lose-device",
+            "2", "model.obj",
+        ])
+        .unwrap()
+        .unwrap();
+        assert_eq!((args.width, args.height), (640, 480));
+        assert_eq!(args.options.sample_count, 4);
+        assert_eq!(args.frames, Some(3));
+        assert_eq!(args.pick, Some((10, 20)));
+        assert_eq!(args.lose_device, Some(2));
+        assert_eq!(args.max_fps, Some(30));
+        assert_eq!(args.options.present_mode, wgpu::PresentMode::Mailbox);
+        assert_eq!(args.options.skybox, None);
+        assert_eq!(args.options.model, Some(PathBuf::from("model.obj")));
+    }
+
+    #[test]
+    fn help() {
+        assert!(parse(&["--width", "640", "--help"]).unwrap().is_none());
+    }
+
+    #[test]
+    fn rejects_bad_arguments() {
+        let bad: &[&[&str]] = &[
+            &["--width"],
+            &["--width", "wide"],
+            &["--width", "0"],
+            &["--msaa", "3"],
+            &["--max-fps", "0"],
+            &["--pick", "10"],
+            &["--backend", "glide"],
+            &["--unknown"],
+            &["a.obj", "b.obj"],
+        ];
+        for args in bad {
+            assert!(parse(args).is_err(), "{:?}", args);
+        }
+    }
+}
@@ -0,0 +1,301 @@
+//! Immediate mode debug geometry. Lines, boxes, spheres, frusta and axes are queued in
+//! world space during a frame and drawn as a line list at the end of the scene pass.
+
+use anyhow::Result;
+use cgmath::{Matrix4, Point3, SquareMatrix, Transform, Vector3};
+
+use crate::bounds::Aabb;
+use crate::preprocessor::Defines;
+use crate::shader::{self, ShaderLibrary};
+use crate::shader_interface;
+use crate::texture::Texture;
+
+pub const RED: [f32; 4] = [1.0, 0.2, 0.2, 1.0];
+pub const GREEN: [f32; 4] = [0.2, 1.0, 0.2, 1.0];
+pub const BLUE: [f32; 4] = [0.3, 0.4, 1.0, 1.0];
+pub const YELLOW: [f32; 4] = [1.0, 1.0, 0.2, 1.0];
+pub const WHITE: [f32; 4] = [1.0, 1.0, 1.0, 1.0];
+
+/// Segments in each circle of a wire sphere.
+const SPHERE_SEGMENTS: u32 = 32;
+
+/// Pairs of box corners joined by an edge, indexed as in [`Aabb::corners`].
+const BOX_EDGES: [(usize, usize); 12] = [
+    (0, 1),
+    (2, 3),
+    (4, 5),
+    (6, 7),
+    (0, 2),
+    (1, 3),
+    (4, 6),
+    (5, 7),
+    (0, 4),
+    (1, 5),
+    (2, 6),
+    (3, 7),
+];
+
+#[repr(C)]
+#[derive(Copy, Clone, Debug, bytemuck::Pod, bytemuck::Zeroable)]
+struct LineVertex {
+    position: [f32; 3],
+    colour: [f32; 4],
+}
+
+const _: [(); std::mem::size_of::<LineVertex>()] =
+    [(); shader_interface::stride(shader_interface::DEBUG_LINE_VERTEX)];
+
+impl LineVertex {
+    const ATTRIBUTES: [wgpu::VertexAttribute; shader_interface::DEBUG_LINE_VERTEX.len()] =
+        shader::vertex_attributes(shader_interface::DEBUG_LINE_VERTEX);
+
+    fn desc<'a>() -> wgpu::VertexBufferLayout<'a> {
+        wgpu::VertexBufferLayout {
+            array_stride: std::mem::size_of::<LineVertex>() as wgpu::BufferAddress,
+            step_mode: wgpu::InputStepMode::Vertex,
+            attributes: &Self::ATTRIBUTES,
+        }
+    }
+}
+
+pub struct DebugDraw {
+    depth_test: bool,
+    /// Lines hidden behind the scene.
+    tested: Vec<LineVertex>,
+    /// Lines drawn over everything.
+    overlay: Vec<LineVertex>,
+    vertex_buffer: wgpu::Buffer,
+    vertex_capacity: usize,
+    tested_pipeline: wgpu::RenderPipeline,
+    overlay_pipeline: wgpu::RenderPipeline,
+}
+
+impl DebugDraw {
+    pub fn new(
+        device: &wgpu::Device,
+        uniform_bind_group_layout: &wgpu::BindGroupLayout,
+        colour_format: wgpu::TextureFormat,
+        sample_count: u32,
+    ) -> Result<Self> {
+        let layout = device.create_pipeline_layout(&wgpu::PipelineLayoutDescriptor {
+            label: Some("Debug line pipeline layout"),
+            bind_group_layouts: &[uniform_bind_group_layout],
+            push_constant_ranges: &[],
+        });
+
+        let shader = device.create_shader_module(
+            &ShaderLibrary::embedded()
+                .load("debug_line.wgsl", &Defines::new())?,
+        );
+
+        let create_pipeline = |label, depth_compare| {
+            device.create_render_pipeline(&wgpu::RenderPipelineDescriptor {
+                label: Some(label),
+                layout: Some(&layout),
+                vertex: wgpu::VertexState {
+                    module: &shader,
+                    entry_point: "main",
+                    buffers: &[LineVertex::desc()],
+                },
+                fragment: Some(wgpu::FragmentState {
+                    module: &shader,
+                    entry_point: "main",
+                    targets: &[wgpu::ColorTargetState {
+                        format: colour_format,
+                        blend: Some(wgpu::BlendState::ALPHA_BLENDING),
+                        write_mask: wgpu::ColorWrite::ALL,
+                    }],
+                }),
+                primitive: wgpu::PrimitiveState {
+                    topology: wgpu::PrimitiveTopology::LineList,
+                    strip_index_format: None,
+                    front_face: wgpu::FrontFace::Ccw,
+                    cull_mode: None,
+                    polygon_mode: wgpu::PolygonMode::Fill,
+                    clamp_depth: false,
+                    conservative: false,
+                },
+                // Lines never write depth, so they can't hide each other or anything drawn
+                // after them
+                depth_stencil: Some(wgpu::DepthStencilState {
+                    format: Texture::DEPTH_FORMAT,
+                    depth_write_enabled: false,
+                    depth_compare,
+                    stencil: wgpu::StencilState::default(),
+                    bias: wgpu::DepthBiasState::default(),
+                }),
+                multisample: wgpu::MultisampleState {
+                    count: sample_count,
+                    mask: !0,
+                    alpha_to_coverage_enabled: false,
+                },
+            })
+        };
+        let tested_pipeline =
+            create_pipeline("Debug line pipeline", wgpu::CompareFunction::LessEqual);
+        let overlay_pipeline =
+            create_pipeline("Debug overlay line pipeline", wgpu::CompareFunction::Always);
+
+        let vertex_capacity = 1024;
+        let vertex_buffer = Self::create_vertex_buffer(device, vertex_capacity);
+
+        Ok(Self {
+            depth_test: true,
+            tested: Vec::new(),
+            overlay: Vec::new(),
+            vertex_buffer,
+            vertex_capacity,
+            tested_pipeline,
+            overlay_pipeline,
+        })
+    }
+
+    fn create_vertex_buffer(device: &wgpu::Device, capacity: usize) -> wgpu::Buffer {
+        device.create_buffer(&wgpu::BufferDescriptor {
+            label: Some("Debug line vertex buffer"),
+            size: (capacity * std::mem::size_of::<LineVertex>()) as wgpu::BufferAddress,
+            usage: wgpu::BufferUsage::VERTEX | wgpu::BufferUsage::COPY_DST,
+            mapped_at_creation: false,
+        })
+    }
+
+    /// Drops everything queued and turns depth testing back on. Called at the start of each
+    /// frame, so lines queued for a frame that was never drawn don't pile up.
+    pub fn clear(&mut self) {
+        self.tested.clear();
+        self.overlay.clear();
+        self.depth_test = true;
+    }
+
+    /// Whether lines queued from now on are hidden by the scene in front of them, or drawn
+    /// over it. On by default, and again at the start of each frame.
+    pub fn set_depth_test(&mut self, enabled: bool) {
+        self.depth_test = enabled;
+    }
+
+    pub fn line(&mut self, from: Point3<f32>, to: Point3<f32>, colour: [f32; 4]) {
+        let lines = if self.depth_test {
+            &mut self.tested
+        } else {
+            &mut self.overlay
+        };
+        lines.push(LineVertex {
+            position: from.into(),
+            colour,
+        });
+        lines.push(LineVertex {
+            position: to.into(),
+            colour,
+        });
+    }
+
+    fn edges(&mut self, corners: &[Point3<f32>; 8], colour: [f32; 4]) {
+        for &(a, b) in BOX_EDGES.iter() {
+            self.line(corners[a], corners[b], colour);
+        }
+    }
+
+    pub fn aabb(&mut self, aabb: &Aabb, colour: [f32; 4]) {
+        self.edges(&aabb.corners(), colour);
+    }
+
+    /// Draws `aabb` moved by `transform`, so a rotated box stays tight around its contents.
+    pub fn oriented_box(&mut self, aabb: &Aabb, transform: &Matrix4<f32>, colour: [f32; 4]) {
+        let mut corners = aabb.corners();
+        for corner in corners.iter_mut() {
+            *corner = transform.transform_point(*corner);
+        }
+        self.edges(&corners, colour);
+    }
+
+    /// Three circles, one around each axis.
+    pub fn sphere(&mut self, centre: Point3<f32>, radius: f32, colour: [f32; 4]) {
+        let point = |axis: usize, angle: f32| {
+            let (sin, cos) = angle.sin_cos();
+            let offset = match axis {
+                0 => Vector3::new(0.0, cos, sin),
+                1 => Vector3::new(cos, 0.0, sin),
+                _ => Vector3::new(cos, sin, 0.0),
+            };
+            centre + offset * radius
+        };
+
+        let step = std::f32::consts::TAU / SPHERE_SEGMENTS as f32;
+        for axis in 0..3 {
+            for segment in 0..SPHERE_SEGMENTS {
+                self.line(
+                    point(axis, segment as f32 * step),
+                    point(axis, (segment + 1) as f32 * step),
+                    colour,
+                );
+            }
+        }
+    }
+
+    /// The volume seen through `view_proj`, found by taking the corners of clip space back
+    /// into world space.
+    pub fn frustum(&mut self, view_proj: &Matrix4<f32>, colour: [f32; 4]) {
+        let inverse = match view_proj.invert() {
+            Some(inverse) => inverse,
+            None => return,
+        };
+        let clip_space = Aabb {
+            min: Point3::new(-1.0, -1.0, 0.0),
+            max: Point3::new(1.0, 1.0, 1.0),
+        };
+        self.oriented_box(&clip_space, &inverse, colour);
+    }
+
+    /// X, Y and Z axes of `transform` in red, green and blue, each `size` long.
+    pub fn axes(&mut self, transform: &Matrix4<f32>, size: f32) {
+        let origin = transform.transform_point(Point3::new(0.0, 0.0, 0.0));
+        for (axis, colour) in [
+            (Vector3::unit_x(), RED),
+            (Vector3::unit_y(), GREEN),
+            (Vector3::unit_z(), BLUE),
+        ]
+        .iter()
+        {
+            let end = transform.transform_point(Point3::new(0.0, 0.0, 0.0) + axis * size);
+            self.line(origin, end, *colour);
+        }
+    }
+
+    /// Draws everything queued this frame into the scene pass, then clears the queue.
+    pub fn flush<'a>(
+        &'a mut self,
+        device: &wgpu::Device,
+        queue: &wgpu::Queue,
+        render_pass: &mut wgpu::RenderPass<'a>,
+        uniform_bind_group: &'a wgpu::BindGroup,
+    ) {
+        let tested = self.tested.len() as u32;
+        let overlay = self.overlay.len() as u32;
+        self.tested.append(&mut self.overlay);
+        let vertices = std::mem::take(&mut self.tested);
+        self.depth_test = true;
+        if vertices.is_empty() {
+            return;
+        }
+
+        if vertices.len() > self.vertex_capacity {
+            self.vertex_capacity = vertices.len().next_power_of_two();
+            self.vertex_buffer = Self::create_vertex_buffer(device, self.vertex_capacity);
+        }
+        queue.write_buffer(&self.vertex_buffer, 0, bytemuck::cast_slice(&vertices));
+        // Keep the allocation for next frame
+        self.tested = vertices;
+        self.tested.clear();
+
+        render_pass.set_bind_group(0, uniform_bind_group, &[]);
+        render_pass.set_vertex_buffer(0, self.vertex_buffer.slice(..));
+        if tested > 0 {
+            render_pass.set_pipeline(&self.tested_pipeline);
+            render_pass.draw(0..tested, 0..1);
+        }
+        if overlay > 0 {
+            render_pass.set_pipeline(&self.overlay_pipeline);
+            render_pass.draw(tested..tested + overlay, 0..1);
+        }
+    }
+}
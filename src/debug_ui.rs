@@ -0,0 +1,290 @@
+//! Live editing panels, drawn with egui in their own pass on top of the finished frame.
+
+use std::time::Instant;
+
+use egui::{CtxRef, DragValue, Ui};
+use egui_wgpu_backend::{RenderPass, ScreenDescriptor};
+use egui_winit_platform::{Platform, PlatformDescriptor};
+use winit::event::{Event, WindowEvent};
+
+use crate::assets::AssetCache;
+use crate::camera::Camera;
+use crate::instance::InstanceGrid;
+use crate::light::Light;
+use crate::view_mode::ViewMode;
+
+/// The parts of the scene the panels edit.
+pub struct Scene<'a> {
+    pub camera: &'a mut Camera,
+    pub light: &'a mut Light,
+    pub light_orbit: &'a mut bool,
+    /// The clear colour, or `None` while a skybox is drawn instead.
+    pub clear_colour: Option<&'a mut wgpu::Color>,
+    pub instance_grid: &'a mut InstanceGrid,
+    pub view_mode: &'a mut ViewMode,
+    pub assets: &'a mut AssetCache,
+    /// Set to have the next frame lose the device.
+    pub lose_device: &'a mut bool,
+}
+
+pub struct DebugUi {
+    platform: Platform,
+    render_pass: RenderPass,
+    scale_factor: f64,
+    start: Instant,
+    visible: bool,
+    paint_jobs: Vec<egui::ClippedMesh>,
+}
+
+impl DebugUi {
+    pub fn new(
+        device: &wgpu::Device,
+        colour_format: wgpu::TextureFormat,
+        size: winit::dpi::PhysicalSize<u32>,
+        scale_factor: f64,
+    ) -> Self {
+        let platform = Platform::new(PlatformDescriptor {
+            physical_width: size.width,
+            physical_height: size.height,
+            scale_factor,
+            font_definitions: egui::FontDefinitions::default(),
+            style: egui::Style::default(),
+        });
+
+        Self {
+            platform,
+            // Drawn onto the resolved frame, so never multisampled
+            render_pass: RenderPass::new(device, colour_format, 1),
+            scale_factor,
+            start: Instant::now(),
+            visible: false,
+            paint_jobs: Vec::new(),
+        }
+    }
+
+    pub fn is_visible(&self) -> bool {
+        self.visible
+    }
+
+    pub fn toggle(&mut self) {
+        self.visible = !self.visible;
+    }
+
+    /// Passes an event on to egui. Returns true when a visible panel wants it for itself,
+    /// like a click on a panel or typing into a field, so the scene shouldn't also see it.
+    pub fn handle_event(&mut self, event: &Event<()>) -> bool {
+        if let Event::WindowEvent {
+            event: WindowEvent::ScaleFactorChanged { scale_factor, .. },
+            ..
+        } = event
+        {
+            self.scale_factor = *scale_factor;
+        }
+
+        // Hidden panels still need to follow the window size
+        self.platform.handle_event(event);
+        self.visible && self.platform.captures_event(event)
+    }
+
+    /// Lays out this frame's panels, applying any edits to `scene`. Returns whether the
+    /// instance grid was changed.
+    pub fn update(&mut self, scene: Scene) -> bool {
+        self.platform.update_time(self.start.elapsed().as_secs_f64());
+        self.platform.begin_frame();
+
+        let ctx = self.platform.context();
+        let grid_changed = self.visible && show_panels(&ctx, scene);
+
+        let (_output, shapes) = self.platform.end_frame();
+        self.paint_jobs = ctx.tessellate(shapes);
+        grid_changed
+    }
+
+    /// Draws the panels laid out by the last [`update`](Self::update) onto `view`, which
+    /// must already hold the finished frame.
+    pub fn render(
+        &mut self,
+        device: &wgpu::Device,
+        queue: &wgpu::Queue,
+        encoder: &mut wgpu::CommandEncoder,
+        view: &wgpu::TextureView,
+        screen_size: winit::dpi::PhysicalSize<u32>,
+    ) {
+        if !self.visible {
+            return;
+        }
+
+        let screen = ScreenDescriptor {
+            physical_width: screen_size.width,
+            physical_height: screen_size.height,
+            scale_factor: self.scale_factor as f32,
+        };
+        self.render_pass
+            .update_texture(device, queue, &self.platform.context().texture());
+        self.render_pass.update_user_textures(device, queue);
+        self.render_pass
+            .update_buffers(device, queue, &self.paint_jobs, &screen);
+        self.render_pass
+            .execute(encoder, view, &self.paint_jobs, &screen, None);
+    }
+}
+
+/// Three drag fields on one row. Returns whether any of them changed.
+fn drag_vector(ui: &mut Ui, label: &str, value: &mut [f32; 3], speed: f32) -> bool {
+    ui.horizontal(|ui| {
+        ui.label(label);
+        let mut changed = false;
+        for (component, prefix) in value.iter_mut().zip(["x: ", "y: ", "z: "].iter()) {
+            changed |= ui
+                .add(DragValue::new(component).speed(speed).prefix(*prefix))
+                .changed();
+        }
+        changed
+    })
+    .inner
+}
+
+fn camera_panel(ui: &mut Ui, camera: &mut Camera) {
+    let mut eye: [f32; 3] = camera.eye.into();
+    if drag_vector(ui, "Eye", &mut eye, 0.05) {
+        camera.eye = eye.into();
+    }
+    let mut target: [f32; 3] = camera.target.into();
+    if drag_vector(ui, "Target", &mut target, 0.05) {
+        camera.target = target.into();
+    }
+    ui.horizontal(|ui| {
+        ui.label("Field of view");
+        ui.add(
+            DragValue::new(&mut camera.fovy)
+                .speed(0.5)
+                .clamp_range(10.0..=120.0)
+                .suffix("°"),
+        );
+    });
+    ui.horizontal(|ui| {
+        ui.label("Clip planes");
+        ui.add(
+            DragValue::new(&mut camera.znear)
+                .speed(0.01)
+                .clamp_range(0.01..=camera.zfar - 0.01),
+        );
+        ui.add(
+            DragValue::new(&mut camera.zfar)
+                .speed(1.0)
+                .clamp_range(camera.znear + 0.01..=10_000.0),
+        );
+    });
+}
+
+fn light_panel(ui: &mut Ui, light: &mut Light, orbit: &mut bool) {
+    ui.horizontal(|ui| {
+        ui.label("Colour");
+        ui.color_edit_button_rgb(&mut light.colour);
+    });
+    drag_vector(ui, "Position", &mut light.position, 0.05);
+    ui.checkbox(orbit, "Orbit");
+}
+
+fn background_panel(ui: &mut Ui, clear_colour: Option<&mut wgpu::Color>) {
+    match clear_colour {
+        Some(colour) => {
+            let mut rgb = [colour.r as f32, colour.g as f32, colour.b as f32];
+            ui.horizontal(|ui| {
+                ui.label("Clear colour");
+                if ui.color_edit_button_rgb(&mut rgb).changed() {
+                    colour.r = rgb[0] as f64;
+                    colour.g = rgb[1] as f64;
+                    colour.b = rgb[2] as f64;
+                }
+            });
+        }
+        None => {
+            ui.label("Drawing a skybox");
+        }
+    }
+}
+
+fn instances_panel(ui: &mut Ui, grid: &mut InstanceGrid) -> bool {
+    let mut changed = false;
+    ui.horizontal(|ui| {
+        ui.label("Per row");
+        changed |= ui
+            .add(DragValue::new(&mut grid.per_row).clamp_range(1..=200))
+            .changed();
+    });
+    ui.horizontal(|ui| {
+        ui.label("Spacing");
+        changed |= ui
+            .add(
+                DragValue::new(&mut grid.spacing)
+                    .speed(0.05)
+                    .clamp_range(0.5..=20.0),
+            )
+            .changed();
+    });
+    ui.horizontal(|ui| {
+        ui.label("Tilt");
+        changed |= ui
+            .add(
+                DragValue::new(&mut grid.tilt)
+                    .speed(0.5)
+                    .clamp_range(-180.0..=180.0)
+                    .suffix("°"),
+            )
+            .changed();
+    });
+    ui.label(format!("{} instances", grid.per_row * grid.per_row));
+    changed
+}
+
+fn view_panel(ui: &mut Ui, view_mode: &mut ViewMode) {
+    for mode in ViewMode::ALL.iter() {
+        ui.radio_value(view_mode, *mode, mode.name());
+    }
+}
+
+fn assets_panel(ui: &mut Ui, assets: &mut AssetCache) {
+    let stats = assets.stats();
+    ui.label(format!(
+        "{} textures, {} in use",
+        stats.textures, stats.textures_in_use
+    ));
+    ui.label(format!(
+        "{} materials, {} in use",
+        stats.materials, stats.materials_in_use
+    ));
+    if ui.button("Evict unused").clicked() {
+        assets.evict_unused();
+    }
+}
+
+fn show_panels(ctx: &CtxRef, scene: Scene) -> bool {
+    let Scene {
+        camera,
+        light,
+        light_orbit,
+        clear_colour,
+        instance_grid,
+        view_mode,
+        assets,
+        lose_device,
+    } = scene;
+
+    egui::Window::new("Camera").show(ctx, |ui| camera_panel(ui, camera));
+    egui::Window::new("Light").show(ctx, |ui| light_panel(ui, light, light_orbit));
+    egui::Window::new("Background").show(ctx, |ui| background_panel(ui, clear_colour));
+    egui::Window::new("View").show(ctx, |ui| view_panel(ui, view_mode));
+    egui::Window::new("Assets").show(ctx, |ui| assets_panel(ui, assets));
+    egui::Window::new("Device").show(ctx, |ui| {
+        if ui.button("Simulate device loss").clicked() {
+            *lose_device = true;
+        }
+    });
+
+    let mut grid_changed = false;
+    egui::Window::new("Instances").show(ctx, |ui| {
+        grid_changed = instances_panel(ui, instance_grid);
+    });
+    grid_changed
+}
@@ -0,0 +1,74 @@
+//! wgpu 0.9 has no way to report a lost device from acquiring, submitting, presenting,
+//! writing to a queue or waiting on a device, and panics instead. Calls that may do any of
+//! these are run through [`catch`], which turns the panic back into an error.
+//!
+//! This relies on the panic unwinding, so building with `panic = "abort"` is refused rather
+//! than having the first lost device quietly end the process. Whatever wgpu was in the
+//! middle of when it panicked is only ever torn down afterwards, along with the device.
+
+#[cfg(panic = "abort")]
+compile_error!("recovering from a lost device needs panics to unwind, not abort");
+
+use std::panic::{self, AssertUnwindSafe};
+use std::sync::Arc;
+use std::thread;
+
+use crate::error::FrameError;
+
+/// Runs `call`, giving [`FrameError::DeviceLost`] if it panics. Meanwhile, a panic on this
+/// thread is logged as a warning rather than printed as a crash by the usual panic hook,
+/// which other threads still go through.
+pub fn catch<T>(call: impl FnOnce() -> T) -> Result<T, FrameError> {
+    let hook = Arc::new(panic::take_hook());
+    let thread = thread::current().id();
+    panic::set_hook(Box::new({
+        let hook = Arc::clone(&hook);
+        move |info| {
+            if thread::current().id() == thread {
+                log::warn!("wgpu panicked, treating the device as lost: {}", info);
+            } else {
+                hook(info);
+            }
+        }
+    }));
+
+    let result = panic::catch_unwind(AssertUnwindSafe(call));
+
+    // Dropping the hook set above leaves the only reference to the one it stood in for
+    drop(panic::take_hook());
+    if let Ok(hook) = Arc::try_unwrap(hook) {
+        panic::set_hook(hook);
+    }
+    result.map_err(|_| FrameError::DeviceLost)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    #[test]
+    fn passes_results_through_and_turns_panics_into_device_loss() {
+        static HOOK_CALLS: AtomicUsize = AtomicUsize::new(0);
+        panic::set_hook(Box::new(|_| {
+            HOOK_CALLS.fetch_add(1, Ordering::SeqCst);
+        }));
+
+        assert_eq!(catch(|| 1 + 1).unwrap(), 2);
+        assert!(matches!(
+            catch(|| panic!("Device lost")),
+            Err(FrameError::DeviceLost)
+        ));
+        // Nested, as a pick can be inside handling input
+        assert!(matches!(
+            catch(|| catch(|| panic!("Device lost"))),
+            Ok(Err(FrameError::DeviceLost))
+        ));
+        assert_eq!(HOOK_CALLS.load(Ordering::SeqCst), 0);
+
+        // The hook in place beforehand is put back
+        let _ = panic::catch_unwind(|| panic!("Not a lost device"));
+        assert_eq!(HOOK_CALLS.load(Ordering::SeqCst), 1);
+        drop(panic::take_hook());
+    }
+}
@@ -0,0 +1,83 @@
+use std::fmt;
+use std::path::PathBuf;
+
+/// Why the renderer could not be created.
+#[derive(Debug)]
+pub enum RendererError {
+    /// No adapter matched the requested backends and selection.
+    Adapter(anyhow::Error),
+    /// The adapter refused to create a device with the features and limits we need.
+    Device(wgpu::RequestDeviceError),
+    /// The chosen adapter can't present to the window.
+    IncompatibleSurface,
+    Model {
+        path: PathBuf,
+        source: anyhow::Error,
+    },
+    /// A shader failed to preprocess or validate.
+    Shader(anyhow::Error),
+    /// The GPU ran out of memory for the next frame.
+    OutOfMemory,
+    /// The device kept being lost straight after being recreated.
+    DeviceLost,
+    /// wgpu reported an error while the device was still working, which is a bug.
+    Wgpu(anyhow::Error),
+}
+
+pub type Result<T, E = RendererError> = std::result::Result<T, E>;
+
+impl fmt::Display for RendererError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Adapter(_) => write!(f, "Could not select an adapter"),
+            Self::Device(_) => write!(f, "Could not get a device from the adapter"),
+            Self::IncompatibleSurface => {
+                write!(f, "The adapter can't present to the window surface")
+            }
+            Self::Model { path, .. } => write!(f, "Could not load model {}", path.display()),
+            Self::Shader(_) => write!(f, "Could not build a shader"),
+            Self::OutOfMemory => write!(f, "Out of GPU memory"),
+            Self::DeviceLost => write!(f, "The device could not be recovered after being lost"),
+            Self::Wgpu(_) => write!(f, "wgpu reported an error"),
+        }
+    }
+}
+
+impl std::error::Error for RendererError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Self::Adapter(e) | Self::Shader(e) | Self::Wgpu(e) | Self::Model { source: e, .. } => {
+                Some(e.as_ref())
+            }
+            Self::Device(e) => Some(e),
+            Self::IncompatibleSurface | Self::OutOfMemory | Self::DeviceLost => None,
+        }
+    }
+}
+
+/// Why a frame could not be rendered, see [`State::recover`](crate::state::State::recover).
+#[derive(Debug)]
+pub enum FrameError {
+    SwapChain(wgpu::SwapChainError),
+    DeviceLost,
+    /// wgpu reported an error, but the device is still working.
+    Wgpu(String),
+}
+
+impl From<wgpu::SwapChainError> for FrameError {
+    fn from(error: wgpu::SwapChainError) -> Self {
+        Self::SwapChain(error)
+    }
+}
+
+impl fmt::Display for FrameError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::SwapChain(e) => write!(f, "{}", e),
+            Self::DeviceLost => write!(f, "The device was lost"),
+            Self::Wgpu(e) => write!(f, "{}", e),
+        }
+    }
+}
+
+impl std::error::Error for FrameError {}
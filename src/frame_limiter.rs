@@ -0,0 +1,91 @@
+use std::time::{Duration, Instant};
+
+/// Caps the frame rate on the CPU, by telling the event loop when the next frame is due
+/// instead of redrawing as fast as possible.
+pub struct FrameLimiter {
+    max_fps: Option<u32>,
+    next_frame: Instant,
+}
+
+impl FrameLimiter {
+    pub fn new(max_fps: Option<u32>) -> Self {
+        Self {
+            max_fps,
+            next_frame: Instant::now(),
+        }
+    }
+
+    pub fn max_fps(&self) -> Option<u32> {
+        self.max_fps
+    }
+
+    pub fn set_max_fps(&mut self, max_fps: Option<u32>) {
+        self.max_fps = max_fps;
+        self.next_frame = Instant::now();
+    }
+
+    fn frame_time(&self) -> Option<Duration> {
+        self.max_fps.map(|fps| Duration::from_secs_f64(1.0 / fps as f64))
+    }
+
+    /// When the next frame should start, or `None` when uncapped.
+    pub fn next_frame(&self) -> Option<Instant> {
+        self.max_fps.map(|_| self.next_frame)
+    }
+
+    pub fn frame_started(&mut self, now: Instant) {
+        if let Some(frame_time) = self.frame_time() {
+            // Schedule from the last deadline so the rate doesn't drift, unless we've fallen
+            // more than a frame behind
+            let next_frame = self.next_frame + frame_time;
+            self.next_frame = if next_frame < now {
+                now + frame_time
+            } else {
+                next_frame
+            };
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn uncapped() {
+        let mut limiter = FrameLimiter::new(None);
+        limiter.frame_started(Instant::now());
+        assert_eq!(limiter.next_frame(), None);
+    }
+
+    #[test]
+    fn schedules_from_the_last_deadline() {
+        let mut limiter = FrameLimiter::new(Some(10));
+        let start = limiter.next_frame().unwrap();
+        // Starting late, but less than a frame late, doesn't push the next deadline back
+        limiter.frame_started(start + Duration::from_millis(30));
+        assert_eq!(limiter.next_frame(), Some(start + Duration::from_millis(100)));
+        limiter.frame_started(start + Duration::from_millis(150));
+        assert_eq!(limiter.next_frame(), Some(start + Duration::from_millis(200)));
+    }
+
+    #[test]
+    fn catches_up_when_a_frame_behind() {
+        let mut limiter = FrameLimiter::new(Some(10));
+        let start = limiter.next_frame().unwrap();
+        let late = start + Duration::from_millis(500);
+        limiter.frame_started(late);
+        assert_eq!(limiter.next_frame(), Some(late + Duration::from_millis(100)));
+    }
+
+    #[test]
+    fn changing_the_cap_restarts_the_schedule() {
+        let mut limiter = FrameLimiter::new(Some(10));
+        let before = Instant::now();
+        limiter.set_max_fps(Some(20));
+        assert_eq!(limiter.max_fps(), Some(20));
+        assert!(limiter.next_frame().unwrap() >= before);
+        limiter.set_max_fps(None);
+        assert_eq!(limiter.next_frame(), None);
+    }
+}
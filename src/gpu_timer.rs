@@ -0,0 +1,309 @@
+use std::collections::VecDeque;
+use std::future::Future;
+use std::pin::Pin;
+use std::time::Duration;
+
+use futures::FutureExt;
+
+/// Timestamps available to each frame, two per scope.
+const QUERIES_PER_FRAME: u32 = 32;
+/// Frames that can be waiting on their results at once. A frame whose slot is still
+/// waiting goes untimed, rather than stalling until the GPU catches up.
+const FRAMES_IN_FLIGHT: usize = 3;
+/// Samples kept for the rolling statistics.
+const HISTORY: usize = 120;
+
+/// Something a timestamp can be written into, between passes or inside one.
+pub trait WriteTimestamp {
+    fn write_timestamp(&mut self, query_set: &wgpu::QuerySet, query_index: u32);
+}
+
+impl WriteTimestamp for wgpu::CommandEncoder {
+    fn write_timestamp(&mut self, query_set: &wgpu::QuerySet, query_index: u32) {
+        wgpu::CommandEncoder::write_timestamp(self, query_set, query_index)
+    }
+}
+
+impl<'a> WriteTimestamp for wgpu::RenderPass<'a> {
+    fn write_timestamp(&mut self, query_set: &wgpu::QuerySet, query_index: u32) {
+        wgpu::RenderPass::write_timestamp(self, query_set, query_index)
+    }
+}
+
+/// The last `HISTORY` timings of a scope.
+#[derive(Default)]
+pub struct RollingStats {
+    samples: VecDeque<Duration>,
+}
+
+/// The spread of a scope's recent timings.
+#[derive(Clone, Copy, Debug)]
+pub struct TimingSummary {
+    pub min: Duration,
+    pub average: Duration,
+    pub max: Duration,
+}
+
+impl RollingStats {
+    fn push(&mut self, sample: Duration) {
+        if self.samples.len() == HISTORY {
+            self.samples.pop_front();
+        }
+        self.samples.push_back(sample);
+    }
+
+    pub fn average(&self) -> Option<Duration> {
+        if self.samples.is_empty() {
+            return None;
+        }
+        Some(self.samples.iter().sum::<Duration>() / self.samples.len() as u32)
+    }
+
+    pub fn min(&self) -> Option<Duration> {
+        self.samples.iter().min().copied()
+    }
+
+    pub fn max(&self) -> Option<Duration> {
+        self.samples.iter().max().copied()
+    }
+
+    /// Minimum, average and maximum, once there are any samples.
+    pub fn summary(&self) -> Option<TimingSummary> {
+        Some(TimingSummary {
+            min: self.min()?,
+            average: self.average()?,
+            max: self.max()?,
+        })
+    }
+}
+
+struct Scope {
+    label: &'static str,
+    start: u32,
+    end: Option<u32>,
+}
+
+type MapFuture = Pin<Box<dyn Future<Output = Result<(), wgpu::BufferAsyncError>> + Send>>;
+
+struct Slot {
+    buffer: wgpu::Buffer,
+    scopes: Vec<Scope>,
+    mapping: Option<MapFuture>,
+}
+
+struct Queries {
+    query_set: wgpu::QuerySet,
+    /// Nanoseconds per timestamp tick.
+    period: f32,
+    slots: Vec<Slot>,
+    current: usize,
+    /// Whether the current frame's slot was free, so the frame is being timed.
+    recording: bool,
+    next_query: u32,
+    open: Vec<usize>,
+}
+
+/// Times passes on the GPU with timestamp queries, when the device supports them.
+/// Without `TIMESTAMP_QUERY` every method does nothing and no stats are collected.
+pub struct GpuTimer {
+    queries: Option<Queries>,
+    scopes: Vec<(&'static str, RollingStats)>,
+    frame: RollingStats,
+}
+
+impl GpuTimer {
+    /// Features to request when the adapter has them.
+    pub const FEATURES: wgpu::Features = wgpu::Features::TIMESTAMP_QUERY;
+
+    pub fn new(device: &wgpu::Device, queue: &wgpu::Queue) -> Self {
+        let queries = if device.features().contains(Self::FEATURES) {
+            let query_set = device.create_query_set(&wgpu::QuerySetDescriptor {
+                count: QUERIES_PER_FRAME * FRAMES_IN_FLIGHT as u32,
+                ty: wgpu::QueryType::Timestamp,
+            });
+            let slots = (0..FRAMES_IN_FLIGHT)
+                .map(|_| Slot {
+                    buffer: device.create_buffer(&wgpu::BufferDescriptor {
+                        label: Some("Timestamp readback buffer"),
+                        size: (QUERIES_PER_FRAME * wgpu::QUERY_SIZE) as wgpu::BufferAddress,
+                        usage: wgpu::BufferUsage::COPY_DST | wgpu::BufferUsage::MAP_READ,
+                        mapped_at_creation: false,
+                    }),
+                    scopes: Vec::new(),
+                    mapping: None,
+                })
+                .collect();
+
+            Some(Queries {
+                query_set,
+                period: queue.get_timestamp_period(),
+                slots,
+                current: 0,
+                recording: false,
+                next_query: 0,
+                open: Vec::new(),
+            })
+        } else {
+            log::info!("Timestamp queries are not supported, GPU timings are unavailable");
+            None
+        };
+
+        Self {
+            queries,
+            scopes: Vec::new(),
+            frame: RollingStats::default(),
+        }
+    }
+
+    /// Reads back the results of earlier frames that have finished, without waiting for
+    /// any that haven't, then starts timing a new frame.
+    pub fn begin_frame(&mut self, device: &wgpu::Device) {
+        let queries = match &mut self.queries {
+            Some(queries) => queries,
+            None => return,
+        };
+
+        device.poll(wgpu::Maintain::Poll);
+        let period = queries.period as f64;
+        for slot in queries.slots.iter_mut() {
+            let result = match slot.mapping.as_mut().and_then(|mapping| mapping.now_or_never()) {
+                Some(result) => result,
+                None => continue,
+            };
+            slot.mapping = None;
+            if result.is_err() {
+                continue;
+            }
+
+            {
+                let data = slot.buffer.slice(..).get_mapped_range();
+                let ticks: &[u64] = bytemuck::cast_slice(&data);
+                let duration = |start: u32, end: u32| {
+                    let elapsed = ticks[end as usize].saturating_sub(ticks[start as usize]);
+                    Duration::from_nanos((elapsed as f64 * period) as u64)
+                };
+
+                let mut first = None;
+                let mut last = None;
+                for scope in slot.scopes.iter() {
+                    let end = match scope.end {
+                        Some(end) => end,
+                        None => continue,
+                    };
+                    let sample = duration(scope.start, end);
+                    match self.scopes.iter_mut().find(|(label, _)| *label == scope.label) {
+                        Some((_, stats)) => stats.push(sample),
+                        None => {
+                            let mut stats = RollingStats::default();
+                            stats.push(sample);
+                            self.scopes.push((scope.label, stats));
+                        }
+                    }
+                    first = Some(first.map_or(scope.start, |first: u32| first.min(scope.start)));
+                    last = Some(last.map_or(end, |last: u32| last.max(end)));
+                }
+                if let (Some(first), Some(last)) = (first, last) {
+                    self.frame.push(duration(first, last));
+                }
+            }
+            slot.buffer.unmap();
+        }
+
+        let slot = &mut queries.slots[queries.current];
+        queries.recording = slot.mapping.is_none();
+        queries.next_query = 0;
+        queries.open.clear();
+        if queries.recording {
+            slot.scopes.clear();
+        }
+    }
+
+    fn next_query(queries: &mut Queries) -> Option<u32> {
+        if !queries.recording || queries.next_query == QUERIES_PER_FRAME {
+            return None;
+        }
+        let query = queries.next_query;
+        queries.next_query += 1;
+        Some(query)
+    }
+
+    /// Starts timing `label`. Scopes can nest, each is ended by the matching
+    /// [`end`](Self::end).
+    pub fn start<W: WriteTimestamp>(&mut self, writer: &mut W, label: &'static str) {
+        let queries = match &mut self.queries {
+            Some(queries) => queries,
+            None => return,
+        };
+        let query = match Self::next_query(queries) {
+            Some(query) => query,
+            None => return,
+        };
+
+        let base = queries.current as u32 * QUERIES_PER_FRAME;
+        writer.write_timestamp(&queries.query_set, base + query);
+        let slot = &mut queries.slots[queries.current];
+        queries.open.push(slot.scopes.len());
+        slot.scopes.push(Scope {
+            label,
+            start: query,
+            end: None,
+        });
+    }
+
+    pub fn end<W: WriteTimestamp>(&mut self, writer: &mut W) {
+        let queries = match &mut self.queries {
+            Some(queries) => queries,
+            None => return,
+        };
+        let scope = match queries.open.pop() {
+            Some(scope) => scope,
+            None => return,
+        };
+        let query = match Self::next_query(queries) {
+            Some(query) => query,
+            None => return,
+        };
+
+        let base = queries.current as u32 * QUERIES_PER_FRAME;
+        writer.write_timestamp(&queries.query_set, base + query);
+        queries.slots[queries.current].scopes[scope].end = Some(query);
+    }
+
+    /// Copies this frame's timestamps into its readback buffer. Call once all scopes have
+    /// ended, on the last encoder of the frame.
+    pub fn resolve(&mut self, encoder: &mut wgpu::CommandEncoder) {
+        if let Some(queries) = &mut self.queries {
+            if queries.recording && queries.next_query > 0 {
+                let base = queries.current as u32 * QUERIES_PER_FRAME;
+                encoder.resolve_query_set(
+                    &queries.query_set,
+                    base..base + queries.next_query,
+                    &queries.slots[queries.current].buffer,
+                    0,
+                );
+            }
+        }
+    }
+
+    /// Starts reading back this frame's timestamps. Call after submitting the encoder
+    /// [`resolve`](Self::resolve) was called with.
+    pub fn end_frame(&mut self) {
+        if let Some(queries) = &mut self.queries {
+            if queries.recording && queries.next_query > 0 {
+                let slot = &mut queries.slots[queries.current];
+                slot.mapping = Some(Box::pin(slot.buffer.slice(..).map_async(wgpu::MapMode::Read)));
+            }
+            queries.current = (queries.current + 1) % FRAMES_IN_FLIGHT;
+        }
+    }
+
+    /// Rolling stats for each scope, in the order they were first seen.
+    pub fn scopes(&self) -> impl Iterator<Item = (&'static str, &RollingStats)> {
+        self.scopes.iter().map(|(label, stats)| (*label, stats))
+    }
+
+    /// Rolling stats for the whole frame, from the first timestamp to the last.
+    pub fn frame(&self) -> &RollingStats {
+        &self.frame
+    }
+}
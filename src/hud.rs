@@ -0,0 +1,376 @@
+use std::time::{Duration, Instant};
+
+use anyhow::Result;
+use wgpu::util::DeviceExt;
+
+use crate::font;
+use crate::gpu_timer::TimingSummary;
+use crate::preprocessor::Defines;
+use crate::shader::{self, ShaderLibrary};
+use crate::shader_interface;
+
+/// What the scene pass drew in a frame.
+#[derive(Clone, Debug, Default)]
+pub struct FrameStats {
+    pub draw_calls: u32,
+    pub instances: u32,
+    pub triangles: u64,
+    /// Recent GPU times of the whole frame and of each timed pass, when the device can
+    /// time them.
+    pub gpu_time: Option<TimingSummary>,
+    pub gpu_passes: Vec<(&'static str, TimingSummary)>,
+    /// What's loading in the background and how far along it is, from 0 to 1.
+    pub loading: Option<(String, f32)>,
+}
+
+impl FrameStats {
+    /// Counts `draw_calls` draws of `instances` instances of a `triangles` triangle mesh.
+    pub fn record(&mut self, draw_calls: u32, instances: u32, triangles: u64) {
+        self.draw_calls += draw_calls;
+        self.instances += instances;
+        self.triangles += triangles * instances as u64;
+    }
+}
+
+/// Frame timings, averaged over half a second so they stay readable.
+struct Timings {
+    window_start: Instant,
+    frames: u32,
+    cpu_time: Duration,
+    fps: f32,
+    cpu_ms: f32,
+}
+
+impl Timings {
+    const WINDOW: Duration = Duration::from_millis(500);
+
+    fn new() -> Self {
+        Self {
+            window_start: Instant::now(),
+            frames: 0,
+            cpu_time: Duration::default(),
+            fps: 0.0,
+            cpu_ms: 0.0,
+        }
+    }
+
+    fn record(&mut self, cpu_time: Duration) {
+        self.frames += 1;
+        self.cpu_time += cpu_time;
+
+        let elapsed = self.window_start.elapsed();
+        if elapsed >= Self::WINDOW {
+            self.fps = self.frames as f32 / elapsed.as_secs_f32();
+            self.cpu_ms = self.cpu_time.as_secs_f32() * 1000.0 / self.frames as f32;
+            self.window_start = Instant::now();
+            self.frames = 0;
+            self.cpu_time = Duration::default();
+        }
+    }
+}
+
+#[repr(C)]
+#[derive(Copy, Clone, Debug, bytemuck::Pod, bytemuck::Zeroable)]
+struct GlyphInstance {
+    rect: [f32; 4],
+    uv_rect: [f32; 4],
+    colour: [f32; 4],
+}
+
+const _: [(); std::mem::size_of::<GlyphInstance>()] =
+    [(); shader_interface::stride(shader_interface::HUD_GLYPH)];
+
+impl GlyphInstance {
+    const ATTRIBUTES: [wgpu::VertexAttribute; shader_interface::HUD_GLYPH.len()] =
+        shader::vertex_attributes(shader_interface::HUD_GLYPH);
+
+    fn desc<'a>() -> wgpu::VertexBufferLayout<'a> {
+        wgpu::VertexBufferLayout {
+            array_stride: std::mem::size_of::<GlyphInstance>() as wgpu::BufferAddress,
+            step_mode: wgpu::InputStepMode::Instance,
+            attributes: &Self::ATTRIBUTES,
+        }
+    }
+}
+
+/// Performance overlay in the top left corner, drawn in its own pass over the finished
+/// frame.
+pub struct Hud {
+    visible: bool,
+    timings: Timings,
+    screen_buffer: wgpu::Buffer,
+    bind_group: wgpu::BindGroup,
+    pipeline: wgpu::RenderPipeline,
+    glyphs: Vec<GlyphInstance>,
+    glyph_buffer: wgpu::Buffer,
+    glyph_capacity: usize,
+}
+
+impl Hud {
+    /// Size of a font pixel on screen.
+    const SCALE: f32 = 2.0;
+    const MARGIN: f32 = 8.0;
+    const TEXT_COLOUR: [f32; 4] = [1.0, 1.0, 1.0, 1.0];
+    const PANEL_COLOUR: [f32; 4] = [0.0, 0.0, 0.0, 0.6];
+
+    pub fn new(
+        device: &wgpu::Device,
+        queue: &wgpu::Queue,
+        colour_format: wgpu::TextureFormat,
+    ) -> Result<Self> {
+        let atlas_size = wgpu::Extent3d {
+            width: font::ATLAS_WIDTH,
+            height: font::ATLAS_HEIGHT,
+            depth_or_array_layers: 1,
+        };
+        let atlas = device.create_texture(&wgpu::TextureDescriptor {
+            label: Some("HUD font atlas"),
+            size: atlas_size,
+            mip_level_count: 1,
+            sample_count: 1,
+            dimension: wgpu::TextureDimension::D2,
+            format: wgpu::TextureFormat::R8Unorm,
+            usage: wgpu::TextureUsage::SAMPLED | wgpu::TextureUsage::COPY_DST,
+        });
+        queue.write_texture(
+            wgpu::ImageCopyTexture {
+                texture: &atlas,
+                mip_level: 0,
+                origin: wgpu::Origin3d::ZERO,
+            },
+            &font::atlas(),
+            wgpu::ImageDataLayout {
+                offset: 0,
+                bytes_per_row: std::num::NonZeroU32::new(font::ATLAS_WIDTH),
+                rows_per_image: std::num::NonZeroU32::new(font::ATLAS_HEIGHT),
+            },
+            atlas_size,
+        );
+        let atlas_view = atlas.create_view(&wgpu::TextureViewDescriptor::default());
+        // Nearest, so the font stays crisp when scaled up
+        let sampler = device.create_sampler(&wgpu::SamplerDescriptor::default());
+
+        let screen_buffer = device.create_buffer_init(&wgpu::util::BufferInitDescriptor {
+            label: Some("HUD screen buffer"),
+            contents: bytemuck::cast_slice(&[0.0f32; 2]),
+            usage: wgpu::BufferUsage::UNIFORM | wgpu::BufferUsage::COPY_DST,
+        });
+
+        let bind_group_layout = shader::create_bind_group_layout(
+            device,
+            "HUD bind group layout",
+            shader_interface::HUD_GROUP,
+        );
+        let bind_group = device.create_bind_group(&wgpu::BindGroupDescriptor {
+            label: Some("HUD bind group"),
+            layout: &bind_group_layout,
+            entries: &[
+                wgpu::BindGroupEntry {
+                    binding: 0,
+                    resource: screen_buffer.as_entire_binding(),
+                },
+                wgpu::BindGroupEntry {
+                    binding: 1,
+                    resource: wgpu::BindingResource::TextureView(&atlas_view),
+                },
+                wgpu::BindGroupEntry {
+                    binding: 2,
+                    resource: wgpu::BindingResource::Sampler(&sampler),
+                },
+            ],
+        });
+
+        let layout = device.create_pipeline_layout(&wgpu::PipelineLayoutDescriptor {
+            label: Some("HUD pipeline layout"),
+            bind_group_layouts: &[&bind_group_layout],
+            push_constant_ranges: &[],
+        });
+
+        let shader = device.create_shader_module(
+            &ShaderLibrary::embedded()
+                .load("hud.wgsl", &Defines::new())?,
+        );
+
+        let pipeline = device.create_render_pipeline(&wgpu::RenderPipelineDescriptor {
+            label: Some("HUD Pipeline"),
+            layout: Some(&layout),
+            vertex: wgpu::VertexState {
+                module: &shader,
+                entry_point: "main",
+                buffers: &[GlyphInstance::desc()],
+            },
+            fragment: Some(wgpu::FragmentState {
+                module: &shader,
+                entry_point: "main",
+                targets: &[wgpu::ColorTargetState {
+                    format: colour_format,
+                    blend: Some(wgpu::BlendState::ALPHA_BLENDING),
+                    write_mask: wgpu::ColorWrite::ALL,
+                }],
+            }),
+            primitive: wgpu::PrimitiveState {
+                topology: wgpu::PrimitiveTopology::TriangleStrip,
+                strip_index_format: None,
+                front_face: wgpu::FrontFace::Ccw,
+                cull_mode: None,
+                polygon_mode: wgpu::PolygonMode::Fill,
+                clamp_depth: false,
+                conservative: false,
+            },
+            depth_stencil: None,
+            multisample: wgpu::MultisampleState::default(),
+        });
+
+        let glyph_capacity = 256;
+        let glyph_buffer = Self::create_glyph_buffer(device, glyph_capacity);
+
+        Ok(Self {
+            visible: false,
+            timings: Timings::new(),
+            screen_buffer,
+            bind_group,
+            pipeline,
+            glyphs: Vec::new(),
+            glyph_buffer,
+            glyph_capacity,
+        })
+    }
+
+    fn create_glyph_buffer(device: &wgpu::Device, capacity: usize) -> wgpu::Buffer {
+        device.create_buffer(&wgpu::BufferDescriptor {
+            label: Some("HUD glyph buffer"),
+            size: (capacity * std::mem::size_of::<GlyphInstance>()) as wgpu::BufferAddress,
+            usage: wgpu::BufferUsage::VERTEX | wgpu::BufferUsage::COPY_DST,
+            mapped_at_creation: false,
+        })
+    }
+
+    pub fn is_visible(&self) -> bool {
+        self.visible
+    }
+
+    pub fn toggle(&mut self) {
+        self.visible = !self.visible;
+    }
+
+    /// Records how long the CPU spent on a frame. Called every frame, even while hidden,
+    /// so the numbers are current when the HUD is shown.
+    pub fn record_frame(&mut self, cpu_time: Duration) {
+        self.timings.record(cpu_time);
+    }
+
+    fn lines(&self, stats: &FrameStats) -> Vec<String> {
+        let mut lines = vec![
+            format!("FPS        {:.1}", self.timings.fps),
+            format!("CPU        {:.2} ms", self.timings.cpu_ms),
+            match &stats.gpu_time {
+                Some(gpu_time) => format!("GPU        {}", format_timing(gpu_time)),
+                None => "GPU        n/a".to_string(),
+            },
+        ];
+        lines.extend(
+            stats
+                .gpu_passes
+                .iter()
+                .map(|(label, time)| format!("  {:<8} {}", label, format_timing(time))),
+        );
+        lines.extend(vec![
+            format!("Draw calls {}", stats.draw_calls),
+            format!("Instances  {}", stats.instances),
+            format!("Triangles  {}", stats.triangles),
+        ]);
+        if let Some((name, progress)) = &stats.loading {
+            lines.push(format!("Loading    {:.0}% {}", progress * 100.0, name));
+        }
+        lines
+    }
+
+    fn layout(&mut self, lines: &[String]) {
+        let cell_width = font::CELL_WIDTH as f32 * Self::SCALE;
+        let cell_height = font::CELL_HEIGHT as f32 * Self::SCALE;
+        let columns = lines.iter().map(|line| line.chars().count()).max().unwrap_or(0);
+
+        self.glyphs.clear();
+        self.glyphs.push(GlyphInstance {
+            rect: [
+                Self::MARGIN,
+                Self::MARGIN,
+                columns as f32 * cell_width + Self::MARGIN * 2.0,
+                lines.len() as f32 * cell_height + Self::MARGIN * 2.0,
+            ],
+            uv_rect: font::solid_uv_rect(),
+            colour: Self::PANEL_COLOUR,
+        });
+
+        for (row, line) in lines.iter().enumerate() {
+            for (column, c) in line.chars().enumerate().filter(|(_, c)| *c != ' ') {
+                self.glyphs.push(GlyphInstance {
+                    rect: [
+                        Self::MARGIN * 2.0 + column as f32 * cell_width,
+                        Self::MARGIN * 2.0 + row as f32 * cell_height,
+                        cell_width,
+                        cell_height,
+                    ],
+                    uv_rect: font::glyph_uv_rect(c),
+                    colour: Self::TEXT_COLOUR,
+                });
+            }
+        }
+    }
+
+    /// Draws the overlay onto `view`, which must already hold the finished frame.
+    pub fn render(
+        &mut self,
+        device: &wgpu::Device,
+        queue: &wgpu::Queue,
+        encoder: &mut wgpu::CommandEncoder,
+        view: &wgpu::TextureView,
+        screen_size: winit::dpi::PhysicalSize<u32>,
+        stats: &FrameStats,
+    ) {
+        if !self.visible {
+            return;
+        }
+
+        let lines = self.lines(stats);
+        self.layout(&lines);
+        if self.glyphs.len() > self.glyph_capacity {
+            self.glyph_capacity = self.glyphs.len().next_power_of_two();
+            self.glyph_buffer = Self::create_glyph_buffer(device, self.glyph_capacity);
+        }
+        queue.write_buffer(&self.glyph_buffer, 0, bytemuck::cast_slice(&self.glyphs));
+        queue.write_buffer(
+            &self.screen_buffer,
+            0,
+            bytemuck::cast_slice(&[screen_size.width as f32, screen_size.height as f32]),
+        );
+
+        let mut render_pass = encoder.begin_render_pass(&wgpu::RenderPassDescriptor {
+            label: Some("HUD render pass"),
+            color_attachments: &[wgpu::RenderPassColorAttachment {
+                view,
+                resolve_target: None,
+                ops: wgpu::Operations {
+                    load: wgpu::LoadOp::Load,
+                    store: true,
+                },
+            }],
+            depth_stencil_attachment: None,
+        });
+        render_pass.set_pipeline(&self.pipeline);
+        render_pass.set_bind_group(0, &self.bind_group, &[]);
+        render_pass.set_vertex_buffer(0, self.glyph_buffer.slice(..));
+        render_pass.draw(0..4, 0..self.glyphs.len() as u32);
+    }
+}
+
+/// The average in milliseconds, followed by the range of the samples it's taken over.
+fn format_timing(timing: &TimingSummary) -> String {
+    let ms = |duration: Duration| duration.as_secs_f32() * 1000.0;
+    format!(
+        "{:.2} ms ({:.2}-{:.2})",
+        ms(timing.average),
+        ms(timing.min),
+        ms(timing.max)
+    )
+}
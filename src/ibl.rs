@@ -0,0 +1,351 @@
+use anyhow::Result;
+
+use crate::preprocessor::Defines;
+use crate::shader::{self, ShaderLibrary};
+use crate::shader_interface;
+use crate::texture::{self, Texture};
+
+fn embedded_shader(name: &str) -> Result<wgpu::ShaderModuleDescriptor<'_>> {
+    ShaderLibrary::embedded().load(name, &Defines::new())
+}
+
+/// Diffuse irradiance and prefiltered specular cube maps, plus the BRDF lookup texture,
+/// used for the ambient term in the main shader.
+pub struct EnvironmentMaps {
+    /// Irradiance, prefiltered and BRDF lookup, only held so they live as long as the
+    /// bind group that samples them.
+    _textures: [Texture; 3],
+    pub bind_group: wgpu::BindGroup,
+}
+
+impl EnvironmentMaps {
+    /// Size of the copy of the environment the maps are filtered from, which unlike the
+    /// skybox has a full mip chain.
+    const SOURCE_SIZE: u32 = 512;
+    const IRRADIANCE_SIZE: u32 = 32;
+    const PREFILTERED_SIZE: u32 = 128;
+    // Needs to match MAX_PREFILTERED_LOD + 1 in shader.wgsl
+    const PREFILTERED_MIP_LEVELS: u32 = 5;
+    const BRDF_LUT_SIZE: u32 = 256;
+    const BRDF_LUT_FORMAT: wgpu::TextureFormat = wgpu::TextureFormat::Rg16Float;
+
+    pub fn create_bind_group_layout(device: &wgpu::Device) -> wgpu::BindGroupLayout {
+        shader::create_bind_group_layout(
+            device,
+            "Environment bind group layout",
+            shader_interface::ENVIRONMENT_GROUP,
+        )
+    }
+
+    /// Generates the maps on the GPU from an HDR environment cube map.
+    pub fn generate(
+        device: &wgpu::Device,
+        queue: &wgpu::Queue,
+        layout: &wgpu::BindGroupLayout,
+        environment: &Texture,
+    ) -> Result<Self> {
+        let source_layout = shader::create_bind_group_layout(
+            device,
+            "Environment source bind group layout",
+            shader_interface::CUBE_FACE_GROUP,
+        );
+        let source_pipeline_layout =
+            device.create_pipeline_layout(&wgpu::PipelineLayoutDescriptor {
+                label: Some("Environment source pipeline layout"),
+                bind_group_layouts: &[&source_layout],
+                push_constant_ranges: &[],
+            });
+
+        let downsample_pipeline = texture::cube_face_pipeline(
+            device,
+            &source_pipeline_layout,
+            Texture::HDR_CUBE_FORMAT,
+            embedded_shader("downsample.wgsl")?,
+        );
+        let irradiance_pipeline = texture::cube_face_pipeline(
+            device,
+            &source_pipeline_layout,
+            Texture::HDR_CUBE_FORMAT,
+            embedded_shader("irradiance.wgsl")?,
+        );
+        let prefilter_pipeline = texture::cube_face_pipeline(
+            device,
+            &source_pipeline_layout,
+            Texture::HDR_CUBE_FORMAT,
+            embedded_shader("prefilter.wgsl")?,
+        );
+
+        let usage = wgpu::TextureUsage::SAMPLED | wgpu::TextureUsage::RENDER_ATTACHMENT;
+        let source_mip_levels = 32 - Self::SOURCE_SIZE.leading_zeros();
+        let source = Texture::create_cube(
+            device,
+            Self::SOURCE_SIZE,
+            source_mip_levels,
+            Texture::HDR_CUBE_FORMAT,
+            usage,
+            Some("Environment source map"),
+        );
+        let irradiance = Texture::create_cube(
+            device,
+            Self::IRRADIANCE_SIZE,
+            1,
+            Texture::HDR_CUBE_FORMAT,
+            usage,
+            Some("Irradiance map"),
+        );
+        let prefiltered = Texture::create_cube(
+            device,
+            Self::PREFILTERED_SIZE,
+            Self::PREFILTERED_MIP_LEVELS,
+            Texture::HDR_CUBE_FORMAT,
+            usage,
+            Some("Prefiltered environment map"),
+        );
+
+        let mut encoder = device.create_command_encoder(&wgpu::CommandEncoderDescriptor {
+            label: Some("Environment map encoder"),
+        });
+
+        let draw_face = |encoder: &mut wgpu::CommandEncoder,
+                         pipeline: &wgpu::RenderPipeline,
+                         from: &wgpu::TextureView,
+                         target: &Texture,
+                         face: u32,
+                         mip_level: u32,
+                         roughness: f32| {
+            let face_buffer = texture::face_uniform_buffer(device, face, roughness);
+            let bind_group = device.create_bind_group(&wgpu::BindGroupDescriptor {
+                label: None,
+                layout: &source_layout,
+                entries: &[
+                    wgpu::BindGroupEntry {
+                        binding: 0,
+                        resource: wgpu::BindingResource::TextureView(from),
+                    },
+                    wgpu::BindGroupEntry {
+                        binding: 1,
+                        resource: face_buffer.as_entire_binding(),
+                    },
+                    wgpu::BindGroupEntry {
+                        binding: 2,
+                        resource: wgpu::BindingResource::Sampler(&source.sampler),
+                    },
+                ],
+            });
+
+            let view = target.cube_face_view(face, mip_level);
+            let mut pass = encoder.begin_render_pass(&wgpu::RenderPassDescriptor {
+                label: Some("Environment face pass"),
+                color_attachments: &[wgpu::RenderPassColorAttachment {
+                    view: &view,
+                    resolve_target: None,
+                    ops: wgpu::Operations {
+                        load: wgpu::LoadOp::Clear(wgpu::Color::BLACK),
+                        store: true,
+                    },
+                }],
+                depth_stencil_attachment: None,
+            });
+            pass.set_pipeline(pipeline);
+            pass.set_bind_group(0, &bind_group, &[]);
+            pass.draw(0..3, 0..1);
+        };
+
+        // Level 0 is copied from the environment, and each level after from the one above
+        for mip_level in 0..source_mip_levels {
+            let above;
+            let from = if mip_level == 0 {
+                &environment.view
+            } else {
+                above = source.cube_mip_view(mip_level - 1);
+                &above
+            };
+            for face in 0..6 {
+                draw_face(&mut encoder, &downsample_pipeline, from, &source, face, mip_level, 0.0);
+            }
+        }
+
+        for face in 0..6 {
+            draw_face(
+                &mut encoder,
+                &irradiance_pipeline,
+                &source.view,
+                &irradiance,
+                face,
+                0,
+                0.0,
+            );
+
+            for mip_level in 0..Self::PREFILTERED_MIP_LEVELS {
+                let roughness = mip_level as f32 / (Self::PREFILTERED_MIP_LEVELS - 1) as f32;
+                draw_face(
+                    &mut encoder,
+                    &prefilter_pipeline,
+                    &source.view,
+                    &prefiltered,
+                    face,
+                    mip_level,
+                    roughness,
+                );
+            }
+        }
+
+        let brdf_lut = Self::render_brdf_lut(device, &mut encoder)?;
+        queue.submit(std::iter::once(encoder.finish()));
+
+        Ok(Self::from_textures(device, layout, irradiance, prefiltered, brdf_lut))
+    }
+
+    /// Builds maps for a flat, uniformly coloured environment, used when there is no skybox.
+    pub fn from_colour(
+        device: &wgpu::Device,
+        queue: &wgpu::Queue,
+        layout: &wgpu::BindGroupLayout,
+        colour: wgpu::Color,
+    ) -> Result<Self> {
+        let usage = wgpu::TextureUsage::SAMPLED | wgpu::TextureUsage::RENDER_ATTACHMENT;
+        let irradiance = Texture::create_cube(
+            device,
+            1,
+            1,
+            Texture::HDR_CUBE_FORMAT,
+            usage,
+            Some("Irradiance map"),
+        );
+        let prefiltered = Texture::create_cube(
+            device,
+            1,
+            1,
+            Texture::HDR_CUBE_FORMAT,
+            usage,
+            Some("Prefiltered environment map"),
+        );
+
+        let mut encoder = device.create_command_encoder(&wgpu::CommandEncoderDescriptor {
+            label: Some("Environment map encoder"),
+        });
+        // A uniform environment convolves to itself, so clearing each face is enough
+        for target in [&irradiance, &prefiltered].iter() {
+            for face in 0..6 {
+                let view = target.cube_face_view(face, 0);
+                encoder.begin_render_pass(&wgpu::RenderPassDescriptor {
+                    label: Some("Environment clear pass"),
+                    color_attachments: &[wgpu::RenderPassColorAttachment {
+                        view: &view,
+                        resolve_target: None,
+                        ops: wgpu::Operations {
+                            load: wgpu::LoadOp::Clear(colour),
+                            store: true,
+                        },
+                    }],
+                    depth_stencil_attachment: None,
+                });
+            }
+        }
+        let brdf_lut = Self::render_brdf_lut(device, &mut encoder)?;
+        queue.submit(std::iter::once(encoder.finish()));
+
+        Ok(Self::from_textures(device, layout, irradiance, prefiltered, brdf_lut))
+    }
+
+    fn render_brdf_lut(
+        device: &wgpu::Device,
+        encoder: &mut wgpu::CommandEncoder,
+    ) -> Result<Texture> {
+        let texture = device.create_texture(&wgpu::TextureDescriptor {
+            label: Some("BRDF lookup texture"),
+            size: wgpu::Extent3d {
+                width: Self::BRDF_LUT_SIZE,
+                height: Self::BRDF_LUT_SIZE,
+                depth_or_array_layers: 1,
+            },
+            mip_level_count: 1,
+            sample_count: 1,
+            dimension: wgpu::TextureDimension::D2,
+            format: Self::BRDF_LUT_FORMAT,
+            usage: wgpu::TextureUsage::SAMPLED | wgpu::TextureUsage::RENDER_ATTACHMENT,
+        });
+        let view = texture.create_view(&wgpu::TextureViewDescriptor::default());
+        let sampler = device.create_sampler(&wgpu::SamplerDescriptor {
+            label: Some("BRDF lookup sampler"),
+            address_mode_u: wgpu::AddressMode::ClampToEdge,
+            address_mode_v: wgpu::AddressMode::ClampToEdge,
+            address_mode_w: wgpu::AddressMode::ClampToEdge,
+            mag_filter: wgpu::FilterMode::Linear,
+            min_filter: wgpu::FilterMode::Linear,
+            mipmap_filter: wgpu::FilterMode::Nearest,
+            ..Default::default()
+        });
+
+        let layout = device.create_pipeline_layout(&wgpu::PipelineLayoutDescriptor {
+            label: Some("BRDF lookup pipeline layout"),
+            bind_group_layouts: &[],
+            push_constant_ranges: &[],
+        });
+        let pipeline = texture::cube_face_pipeline(
+            device,
+            &layout,
+            Self::BRDF_LUT_FORMAT,
+            embedded_shader("brdf_lut.wgsl")?,
+        );
+
+        {
+            let mut pass = encoder.begin_render_pass(&wgpu::RenderPassDescriptor {
+                label: Some("BRDF lookup pass"),
+                color_attachments: &[wgpu::RenderPassColorAttachment {
+                    view: &view,
+                    resolve_target: None,
+                    ops: wgpu::Operations {
+                        load: wgpu::LoadOp::Clear(wgpu::Color::BLACK),
+                        store: true,
+                    },
+                }],
+                depth_stencil_attachment: None,
+            });
+            pass.set_pipeline(&pipeline);
+            pass.draw(0..3, 0..1);
+        }
+
+        Ok(Texture {
+            texture,
+            view,
+            sampler,
+        })
+    }
+
+    fn from_textures(
+        device: &wgpu::Device,
+        layout: &wgpu::BindGroupLayout,
+        irradiance: Texture,
+        prefiltered: Texture,
+        brdf_lut: Texture,
+    ) -> Self {
+        let bind_group = device.create_bind_group(&wgpu::BindGroupDescriptor {
+            label: Some("Environment bind group"),
+            layout,
+            entries: &[
+                wgpu::BindGroupEntry {
+                    binding: 0,
+                    resource: wgpu::BindingResource::TextureView(&irradiance.view),
+                },
+                wgpu::BindGroupEntry {
+                    binding: 1,
+                    resource: wgpu::BindingResource::TextureView(&prefiltered.view),
+                },
+                wgpu::BindGroupEntry {
+                    binding: 2,
+                    resource: wgpu::BindingResource::TextureView(&brdf_lut.view),
+                },
+                wgpu::BindGroupEntry {
+                    binding: 3,
+                    resource: wgpu::BindingResource::Sampler(&prefiltered.sampler),
+                },
+            ],
+        });
+
+        Self {
+            _textures: [irradiance, prefiltered, brdf_lut],
+            bind_group,
+        }
+    }
+}
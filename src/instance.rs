@@ -1,3 +1,5 @@
+use cgmath::{InnerSpace, Rotation3, Zero};
+use crate::{shader, shader_interface};
 
 #[repr(C)]
 #[derive(Copy, Clone, Debug, bytemuck::Pod, bytemuck::Zeroable)]
@@ -6,49 +8,18 @@ pub struct InstanceRaw {
 	normal: [[f32; 3]; 3],
 }
 
+const _: [(); std::mem::size_of::<InstanceRaw>()] =
+	[(); shader_interface::stride(shader_interface::INSTANCE_RAW)];
+
 impl InstanceRaw {
+	const ATTRIBUTES: [wgpu::VertexAttribute; shader_interface::INSTANCE_RAW.len()] =
+		shader::vertex_attributes(shader_interface::INSTANCE_RAW);
+
 	pub fn desc<'a>() -> wgpu::VertexBufferLayout<'a> {
-		use std::mem;
 		wgpu::VertexBufferLayout {
-    		array_stride: mem::size_of::<InstanceRaw>() as wgpu::BufferAddress,
-	    	step_mode: wgpu::InputStepMode::Instance,
-    		attributes: &[
-    			wgpu::VertexAttribute {
-        			format: wgpu::VertexFormat::Float32x4,
-        			offset: 0,
-        			shader_location: 5,
-    			},
-    			wgpu::VertexAttribute {
-                    offset: mem::size_of::<[f32; 4]>() as wgpu::BufferAddress,
-                    shader_location: 6,
-                    format: wgpu::VertexFormat::Float32x4,
-                },
-                wgpu::VertexAttribute {
-                    offset: mem::size_of::<[f32; 8]>() as wgpu::BufferAddress,
-                    shader_location: 7,
-                    format: wgpu::VertexFormat::Float32x4,
-                },
-                wgpu::VertexAttribute {
-                    offset: mem::size_of::<[f32; 12]>() as wgpu::BufferAddress,
-                    shader_location: 8,
-                    format: wgpu::VertexFormat::Float32x4,
-                },
-                wgpu::VertexAttribute {
-                    offset: mem::size_of::<[f32; 16]>() as wgpu::BufferAddress,
-                    shader_location: 9,
-                    format: wgpu::VertexFormat::Float32x3,
-                },
-                wgpu::VertexAttribute {
-                    offset: mem::size_of::<[f32; 19]>() as wgpu::BufferAddress,
-                    shader_location: 10,
-                    format: wgpu::VertexFormat::Float32x3,
-                },
-                wgpu::VertexAttribute {
-                    offset: mem::size_of::<[f32; 22]>() as wgpu::BufferAddress,
-                    shader_location: 11,
-                    format: wgpu::VertexFormat::Float32x3,
-                },
-    		],
+			array_stride: std::mem::size_of::<InstanceRaw>() as wgpu::BufferAddress,
+			step_mode: wgpu::InputStepMode::Instance,
+			attributes: &Self::ATTRIBUTES,
 		}
 	}
 }
@@ -56,13 +27,64 @@ impl InstanceRaw {
 pub struct Instance {
 	pub position: cgmath::Vector3<f32>,
 	pub rotation: cgmath::Quaternion<f32>,
+	/// Drawn with an outline, like when selected.
+	pub highlighted: bool,
 }
 
 impl Instance {
+	pub fn transform(&self) -> cgmath::Matrix4<f32> {
+		cgmath::Matrix4::from_translation(self.position) * cgmath::Matrix4::from(self.rotation)
+	}
+
 	pub fn to_raw(&self) -> InstanceRaw {
 		InstanceRaw {
-		    model: (cgmath::Matrix4::from_translation(self.position) * cgmath::Matrix4::from(self.rotation)).into(),
+		    model: self.transform().into(),
 		    normal: cgmath::Matrix3::from(self.rotation).into(),
 		}
 	}
-}
\ No newline at end of file
+}
+/// Lays the instances out as a square grid, centred on the origin, each tilted away
+/// from the centre.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct InstanceGrid {
+	pub per_row: u32,
+	pub spacing: f32,
+	/// Degrees each instance is rotated about the direction from the origin to it.
+	pub tilt: f32,
+}
+
+impl Default for InstanceGrid {
+	fn default() -> Self {
+		Self {
+			per_row: 100,
+			spacing: 3.0,
+			tilt: 45.0,
+		}
+	}
+}
+
+impl InstanceGrid {
+	pub fn instances(&self) -> Vec<Instance> {
+		let InstanceGrid { per_row, spacing, tilt } = *self;
+		let displacement = cgmath::Vector3::new(per_row as f32 * 0.5, 0.0, per_row as f32 * 0.5);
+
+		(0..per_row)
+			.flat_map(|z| {
+				(0..per_row).map(move |x| {
+					let x = spacing * (x as f32 - per_row as f32 / 2.0);
+					let z = spacing * (z as f32 - per_row as f32 / 2.0);
+
+					let position = cgmath::Vector3 { x, y: 0.0, z } - displacement;
+
+					let rotation = if position.is_zero() {
+						cgmath::Quaternion::from_axis_angle(cgmath::Vector3::unit_z(), cgmath::Deg(0.0))
+					} else {
+						cgmath::Quaternion::from_axis_angle(position.normalize(), cgmath::Deg(tilt))
+					};
+
+					Instance { position, rotation, highlighted: false }
+				})
+			})
+			.collect()
+	}
+}
@@ -0,0 +1,128 @@
+//! Background loading. The slow CPU side of a load, like parsing and decoding, runs on a
+//! worker thread while the render thread keeps drawing, and the result is picked up from
+//! its handle once it's ready to upload.
+
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::mpsc::{self, Receiver, TryRecvError};
+use std::sync::Arc;
+use std::thread;
+
+use anyhow::{anyhow, Result};
+
+/// Steps of a load done so far, out of how many it expects.
+#[derive(Debug, Default)]
+pub struct Progress {
+    done: AtomicUsize,
+    total: AtomicUsize,
+}
+
+impl Progress {
+    /// Expects `steps` more steps.
+    pub fn add_steps(&self, steps: usize) {
+        self.total.fetch_add(steps, Ordering::Relaxed);
+    }
+
+    pub fn step_done(&self) {
+        self.done.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// From 0 to 1, or 0 before any steps are known.
+    pub fn fraction(&self) -> f32 {
+        let total = self.total.load(Ordering::Relaxed);
+        if total == 0 {
+            return 0.0;
+        }
+        (self.done.load(Ordering::Relaxed) as f32 / total as f32).min(1.0)
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum LoadState {
+    Loading,
+    /// The result is waiting to be taken with [`LoadHandle::poll`].
+    Ready,
+    /// The result has been taken.
+    Finished,
+}
+
+pub struct LoadHandle<T> {
+    name: String,
+    progress: Arc<Progress>,
+    receiver: Receiver<Result<T>>,
+    /// A result received while checking the state, kept for `poll`.
+    result: Option<Result<T>>,
+    state: LoadState,
+}
+
+impl<T: Send + 'static> LoadHandle<T> {
+    /// Runs `load` on a new worker thread, which reports how far it got through `Progress`.
+    pub fn spawn<F>(name: &str, load: F) -> Self
+    where
+        F: FnOnce(&Arc<Progress>) -> Result<T> + Send + 'static,
+    {
+        let progress = Arc::new(Progress::default());
+        let (sender, receiver) = mpsc::channel();
+
+        let worker_progress = Arc::clone(&progress);
+        let spawned = thread::Builder::new()
+            .name(format!("load {}", name))
+            .spawn(move || {
+                // The handle may have been dropped, in which case nobody wants the result
+                let _ = sender.send(load(&worker_progress));
+            });
+        let result = match spawned {
+            Ok(_) => None,
+            Err(e) => Some(Err(anyhow!("Could not start a loader thread: {}", e))),
+        };
+
+        Self {
+            name: name.to_string(),
+            progress,
+            receiver,
+            state: if result.is_some() {
+                LoadState::Ready
+            } else {
+                LoadState::Loading
+            },
+            result,
+        }
+    }
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    pub fn progress(&self) -> f32 {
+        match self.state {
+            LoadState::Loading => self.progress.fraction(),
+            LoadState::Ready | LoadState::Finished => 1.0,
+        }
+    }
+
+    pub fn state(&mut self) -> LoadState {
+        if self.state == LoadState::Loading {
+            match self.receiver.try_recv() {
+                Ok(result) => {
+                    self.result = Some(result);
+                    self.state = LoadState::Ready;
+                }
+                Err(TryRecvError::Empty) => {}
+                Err(TryRecvError::Disconnected) => {
+                    self.result = Some(Err(anyhow!("Loader thread for {} panicked", self.name)));
+                    self.state = LoadState::Ready;
+                }
+            }
+        }
+        self.state
+    }
+
+    /// Takes the result once the load is done, or `None` while it's still loading and
+    /// after the result has been taken.
+    pub fn poll(&mut self) -> Option<Result<T>> {
+        if self.state() != LoadState::Ready {
+            return None;
+        }
+        self.state = LoadState::Finished;
+        self.result.take()
+    }
+}
@@ -1,69 +1,206 @@
 use futures::executor::block_on;
+use std::time::Instant;
 use winit::{
     dpi::PhysicalSize,
     event::*,
     event_loop::{ControlFlow, EventLoop},
-    window::WindowBuilder,
+    window::{Fullscreen, WindowBuilder},
 };
 
+#[macro_use]
+mod profiler;
+
+mod adapter;
+mod assets;
+mod bounds;
+mod cli;
+#[cfg(feature = "debug-ui")]
+mod debug_ui;
+mod debug_draw;
+mod device_loss;
+mod error;
+mod font;
+mod frame_limiter;
+mod gpu_timer;
+mod hash;
+mod hud;
 mod state;
 mod vertex;
 mod texture;
 mod camera;
 mod uniform;
 mod instance;
+mod mesh;
+mod mesh_cache;
 mod model;
+mod outline;
+mod picking;
 mod light;
+mod loader;
+mod preprocessor;
+mod raycast;
+mod shader;
+mod shader_interface;
+mod shader_validation;
+mod ibl;
+mod skybox;
+mod view_mode;
 
+use cli::Args;
+use frame_limiter::FrameLimiter;
 use state::State;
 
 fn main() {
-    env_logger::init();
+    // wgpu's warning is the only sign that it fell back to Fifo presentation, so show it
+    // even when RUST_LOG isn't set
+    env_logger::Builder::from_env(
+        env_logger::Env::default().default_filter_or("error,wgpu_core::device=warn"),
+    )
+    .init();
+
+    let args = match Args::parse() {
+        Ok(Some(args)) => args,
+        Ok(None) => {
+            print!("{}", cli::USAGE);
+            return;
+        }
+        Err(e) => {
+            eprintln!("error: {}\n\n{}", e, cli::USAGE);
+            std::process::exit(2);
+        }
+    };
+
+    if args.list_adapters {
+        adapter::list(args.options.backends);
+        return;
+    }
+
+    if args.trace.is_some() {
+        profiler::enable();
+    }
 
     let evt_loop = EventLoop::new();
 
-    let window_size = PhysicalSize::new(1920, 1080);
+    let window_size = PhysicalSize::new(args.width, args.height);
     let window = WindowBuilder::new()
         .with_title("WGPU Rendering")
         .with_inner_size(window_size)
+        .with_fullscreen(args.fullscreen.then(|| Fullscreen::Borderless(None)))
         .build(&evt_loop)
         .expect("Failed to create window!");
 
-    let mut render_state = block_on(State::new(&window));
-    evt_loop.run(move |event, _, control_flow| match event {
-        Event::WindowEvent {
-            ref event,
-            window_id,
-        } if window_id == window.id() && !render_state.input(event) => match event {
-            WindowEvent::CloseRequested => *control_flow = ControlFlow::Exit,
-            WindowEvent::Resized(new_size) => render_state.resize(*new_size),
-            WindowEvent::ScaleFactorChanged { new_inner_size, .. } => {
-                render_state.resize(**new_inner_size)
-            }
-            WindowEvent::KeyboardInput { input, .. } => match input {
-                KeyboardInput {
-                    state: ElementState::Pressed,
-                    virtual_keycode: Some(VirtualKeyCode::Escape),
-                    ..
-                } => *control_flow = ControlFlow::Exit,
+    let mut render_state = match block_on(State::new(&window, &args.options)) {
+        Ok(state) => state,
+        Err(e) => {
+            eprintln!("error: {:#}", anyhow::Error::new(e));
+            std::process::exit(1);
+        }
+    };
+    // Only frames of the loaded model count towards --frames
+    let mut frames_rendered = 0u64;
+    let mut picked = false;
+    let mut exit_code = 0;
+    let mut frame_limiter = FrameLimiter::new(args.max_fps);
+    evt_loop.run(move |event, _, control_flow| {
+        // The debug UI sees events first, so typing into a panel doesn't also move the camera
+        let ui_captured = render_state.ui_input(&event);
+
+        match event {
+            Event::WindowEvent {
+                ref event,
+                window_id,
+            } if window_id == window.id() && !ui_captured && !render_state.input(event) => match event {
+                WindowEvent::CloseRequested => *control_flow = ControlFlow::Exit,
+                WindowEvent::Resized(new_size) => render_state.resize(*new_size),
+                WindowEvent::ScaleFactorChanged { new_inner_size, .. } => {
+                    render_state.resize(**new_inner_size)
+                }
+                WindowEvent::KeyboardInput { input, .. } => match input {
+                    KeyboardInput {
+                        state: ElementState::Pressed,
+                        virtual_keycode: Some(VirtualKeyCode::Escape),
+                        ..
+                    } => *control_flow = ControlFlow::Exit,
+                    KeyboardInput {
+                        state: ElementState::Pressed,
+                        virtual_keycode: Some(VirtualKeyCode::L),
+                        ..
+                    } => {
+                        let max_fps = match frame_limiter.max_fps() {
+                            Some(_) => None,
+                            None => Some(args.max_fps.unwrap_or(cli::DEFAULT_MAX_FPS)),
+                        };
+                        log::info!("Frame rate cap: {:?}", max_fps);
+                        frame_limiter.set_max_fps(max_fps);
+                    }
+                    _ => {}
+                },
                 _ => {}
             },
-            _ => {}
-        },
-        Event::RedrawRequested(_) => {
-            render_state.update();
-            match render_state.render() {
-                Ok(_) => {}
-                //On swapchain lost, recreate
-                Err(wgpu::SwapChainError::Lost) => render_state.resize(render_state.size),
-                // On OOM Exit
-                Err(wgpu::SwapChainError::OutOfMemory) => *control_flow = ControlFlow::Exit,
-                Err(e) => eprintln!("{:?}", e),
+            Event::RedrawRequested(_) => {
+                frame_limiter.frame_started(Instant::now());
+                if let Err(e) = render_state.update() {
+                    eprintln!("error: {:#}", anyhow::Error::new(e));
+                    exit_code = 1;
+                    *control_flow = ControlFlow::Exit;
+                    return;
+                }
+                match render_state.render() {
+                    Ok(_) if render_state.is_loading() => {}
+                    Ok(_) => {
+                        frames_rendered += 1;
+                        if args.lose_device == Some(frames_rendered) {
+                            render_state.simulate_device_loss();
+                        }
+                        if let (false, Some((x, y))) = (picked, args.pick) {
+                            picked = true;
+                            match render_state.pick(x, y) {
+                                Some(pick) => println!(
+                                    "Pixel {},{}: instance {}, mesh {}, at {:?}",
+                                    x,
+                                    y,
+                                    pick.instance,
+                                    pick.mesh,
+                                    render_state.instances()[pick.instance as usize].position
+                                ),
+                                None => println!("Pixel {},{}: nothing", x, y),
+                            }
+                        }
+                        if args.frames.is_some_and(|frames| frames_rendered >= frames) {
+                            *control_flow = ControlFlow::Exit;
+                        }
+                    }
+                    Err(e) => {
+                        if let Err(e) = block_on(render_state.recover(&window, e)) {
+                            eprintln!("error: {:#}", anyhow::Error::new(e));
+                            exit_code = 1;
+                            *control_flow = ControlFlow::Exit;
+                        }
+                    }
+                }
             }
+            Event::MainEventsCleared if *control_flow != ControlFlow::Exit => {
+                match frame_limiter.next_frame() {
+                    // Sleep until the frame is due rather than spinning
+                    Some(next_frame) if Instant::now() < next_frame => {
+                        *control_flow = ControlFlow::WaitUntil(next_frame)
+                    }
+                    _ => {
+                        *control_flow = ControlFlow::Poll;
+                        window.request_redraw();
+                    }
+                }
+            }
+            Event::LoopDestroyed => {
+                if let Some(path) = &args.trace {
+                    if let Err(e) = profiler::write_chrome_trace(path) {
+                        eprintln!("error: {:#}", e);
+                    }
+                }
+                // winit exits with 0 once the loop is destroyed
+                std::process::exit(exit_code);
+            }
+            _ => {}
         }
-        Event::MainEventsCleared => {
-            window.request_redraw();
-        }
-        _ => {}
     });
 }
@@ -0,0 +1,318 @@
+//! The CPU side of a model's meshes: reading an OBJ and its MTL files into finished
+//! vertices. Nothing here touches the GPU, so the build script uses it too, to build
+//! mesh caches ahead of time.
+
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::fs::{self, File};
+use std::io::BufReader;
+use std::path::{Path, PathBuf};
+
+use anyhow::{Context, Result};
+use cgmath::{InnerSpace, Vector2, Vector3};
+
+use crate::bounds::Aabb;
+use crate::loader::Progress;
+use crate::mesh_cache::MeshArray;
+
+#[repr(C)]
+#[derive(Debug, Clone, Copy, bytemuck::Pod, bytemuck::Zeroable)]
+pub struct ModelVertex {
+    pub position: [f32; 3],
+    pub tex_coords: [f32; 2],
+    pub normal: [f32; 3],
+    pub tangent: [f32; 3],
+    pub bitangent: [f32; 3],
+}
+
+pub struct MeshData {
+    pub name: String,
+    pub vertices: MeshArray<ModelVertex>,
+    pub indices: MeshArray<u32>,
+    pub material: usize,
+    pub bounds: Aabb,
+}
+
+/// A material as read from an MTL file.
+pub struct MtlMaterial {
+    /// Canonical path of the MTL file, which with the material's name keys it in the asset
+    /// cache. Empty for materials that aren't from a file.
+    pub library: PathBuf,
+    pub mtl: tobj::Material,
+}
+
+/// Reads the MTL file an OBJ names with `mtllib lib`, relative to the OBJ's `folder`.
+pub fn load_mtl_library(folder: &Path, lib: &str) -> Result<Vec<MtlMaterial>> {
+    let path = folder.join(lib);
+    let text = fs::read_to_string(&path).with_context(|| format!("Could not read {:?}", path))?;
+    let (mut materials, _) = tobj::load_mtl_buf(&mut text.as_bytes())
+        .with_context(|| format!("Could not load {:?}", path))?;
+    default_missing_colours(&text, &mut materials);
+    let library = path.canonicalize().unwrap_or(path);
+    Ok(materials
+        .into_iter()
+        .map(|mtl| MtlMaterial {
+            library: library.clone(),
+            mtl,
+        })
+        .collect())
+}
+
+/// tobj reads the `Ka`, `Kd` and `Ks` colours a material leaves out as black, which would
+/// blank out its maps. Makes them white instead, using the MTL source to tell them apart
+/// from colours that really are black.
+fn default_missing_colours(mtl_source: &str, materials: &mut [tobj::Material]) {
+    let mut given = HashMap::new();
+    let mut current = String::new();
+    for line in mtl_source.lines().map(str::trim) {
+        let colour = match line.split_whitespace().next() {
+            Some("newmtl") => {
+                // Named as tobj names it, the rest of the line
+                current = line["newmtl".len()..].trim().to_string();
+                given.insert(current.clone(), [false; 3]);
+                continue;
+            }
+            Some("Ka") => 0,
+            Some("Kd") => 1,
+            Some("Ks") => 2,
+            _ => continue,
+        };
+        if let Some(given) = given.get_mut(&current) {
+            given[colour] = true;
+        }
+    }
+
+    for material in materials {
+        let given = given.get(&material.name).copied().unwrap_or([true; 3]);
+        let mut colours = [&mut material.ambient, &mut material.diffuse, &mut material.specular];
+        for (colour, given) in colours.iter_mut().zip(given.iter()) {
+            if !given {
+                **colour = [1.0; 3];
+            }
+        }
+    }
+}
+
+/// Parses the OBJ at `path` and generates tangents for its meshes. Also gives the
+/// materials of the MTL files it names, in the order tobj indexes them, and the names.
+/// Meshes without texture coordinates get `[0, 0]`, and ones without normals are given
+/// flat normals.
+pub fn parse_obj(
+    path: &Path,
+    progress: &Progress,
+) -> Result<(Vec<MeshData>, Vec<MtlMaterial>, Vec<String>)> {
+    let folder = path.parent().context("Directory has no parent")?;
+    // tobj merges the materials of every MTL file, so note which file each came from
+    let libraries = RefCell::new(Vec::new());
+    let (obj_models, obj_materials) = {
+        profile_scope!("parse obj");
+        let file = File::open(path).with_context(|| format!("Could not open {:?}", path))?;
+        let options = tobj::LoadOptions {
+            triangulate: true,
+            single_index: true,
+            ..Default::default()
+        };
+        tobj::load_obj_buf(&mut BufReader::new(file), &options, |lib| {
+            let lib = lib.to_string_lossy();
+            let materials = load_mtl_library(folder, &lib).map_err(|e| {
+                log::error!("{:#}", e);
+                tobj::LoadError::OpenFileFailed
+            })?;
+            let names = materials
+                .iter()
+                .enumerate()
+                .map(|(i, material)| (material.mtl.name.clone(), i))
+                .collect();
+            let tobj_materials = materials.iter().map(|material| material.mtl.clone()).collect();
+            libraries.borrow_mut().push((lib.into_owned(), materials));
+            Ok((tobj_materials, names))
+        })?
+    };
+    obj_materials?;
+    let (mtl_libs, obj_materials): (Vec<_>, Vec<_>) = libraries.into_inner().into_iter().unzip();
+    let obj_materials = obj_materials.into_iter().flatten().collect::<Vec<_>>();
+    progress.add_steps(obj_models.len());
+
+    let mut meshes = Vec::new();
+
+    for model in obj_models {
+        let vertex_count = model.mesh.positions.len() / 3;
+        let has_normals = model.mesh.normals.len() >= vertex_count * 3;
+        let (mut vertices, indices) = {
+            profile_scope!("build vertices", "{}", model.name);
+            let mut vertices = Vec::with_capacity(vertex_count);
+            for i in 0..vertex_count {
+                vertices.push(ModelVertex {
+                    position: [
+                        model.mesh.positions[i * 3],
+                        model.mesh.positions[i * 3 + 1],
+                        model.mesh.positions[i * 3 + 2],
+                    ],
+                    tex_coords: match model.mesh.texcoords.get(i * 2..i * 2 + 2) {
+                        Some(uv) => [uv[0], uv[1]],
+                        None => [0.0; 2],
+                    },
+                    normal: if has_normals {
+                        [
+                            model.mesh.normals[i * 3],
+                            model.mesh.normals[i * 3 + 1],
+                            model.mesh.normals[i * 3 + 2],
+                        ]
+                    } else {
+                        [0.0; 3]
+                    },
+                    tangent: [0.0; 3],
+                    bitangent: [0.0; 3],
+                });
+            }
+            if has_normals {
+                (vertices, model.mesh.indices)
+            } else {
+                generate_flat_normals(&vertices, &model.mesh.indices)
+            }
+        };
+
+        generate_tangents(&mut vertices, &indices);
+
+        meshes.push(MeshData {
+            name: model.name,
+            bounds: Aabb::from_points(vertices.iter().map(|vertex| vertex.position.into())),
+            vertices: vertices.into(),
+            indices: indices.into(),
+            // Models without materials get a default one, which is the first
+            material: model
+                .mesh
+                .material_id
+                .unwrap_or(0)
+                .min(obj_materials.len().max(1) - 1),
+        });
+        progress.step_done();
+    }
+
+    Ok((meshes, obj_materials, mtl_libs))
+}
+
+/// Gives each triangle its own copy of its vertices, with the triangle's normal, so it's
+/// lit flat rather than smoothed into its neighbours.
+fn generate_flat_normals(
+    vertices: &[ModelVertex],
+    indices: &[u32],
+) -> (Vec<ModelVertex>, Vec<u32>) {
+    profile_scope!("generate flat normals");
+    let mut flat = Vec::with_capacity(indices.len());
+    for chunk in indices.chunks_exact(3) {
+        let triangle = [
+            vertices[chunk[0] as usize],
+            vertices[chunk[1] as usize],
+            vertices[chunk[2] as usize],
+        ];
+        let pos0: Vector3<f32> = triangle[0].position.into();
+        let pos1: Vector3<f32> = triangle[1].position.into();
+        let pos2: Vector3<f32> = triangle[2].position.into();
+        let normal = (pos1 - pos0).cross(pos2 - pos0);
+        // Degenerate triangles have no area to face any way, so just pick one
+        let normal = if normal.magnitude2() > 0.0 {
+            normal.normalize()
+        } else {
+            Vector3::unit_z()
+        };
+        for mut vertex in triangle.iter().copied() {
+            vertex.normal = normal.into();
+            flat.push(vertex);
+        }
+    }
+    let indices = (0..flat.len() as u32).collect();
+    (flat, indices)
+}
+
+pub fn generate_tangents(vertices: &mut [ModelVertex], indices: &[u32]) {
+    profile_scope!("generate tangents");
+    for chunk in indices.chunks(3) {
+        let v0 = vertices[chunk[0] as usize];
+        let v1 = vertices[chunk[1] as usize];
+        let v2 = vertices[chunk[2] as usize];
+
+        let pos0: Vector3<f32> = v0.position.into();
+        let pos1: Vector3<f32> = v1.position.into();
+        let pos2: Vector3<f32> = v2.position.into();
+
+        let uv0: Vector2<f32> = v0.tex_coords.into();
+        let uv1: Vector2<f32> = v1.tex_coords.into();
+        let uv2: Vector2<f32> = v2.tex_coords.into();
+
+        let delta_pos1 = pos1 - pos0;
+        let delta_pos2 = pos2 - pos0;
+
+        let delta_uv1 = uv1 - uv0;
+        let delta_uv2 = uv2 - uv0;
+        let determinant = delta_uv1.x * delta_uv2.y - delta_uv1.y * delta_uv2.x;
+        let (tangent, bitangent) = if determinant != 0.0 {
+            let r = 1.0 / determinant;
+            (
+                (delta_pos1 * delta_uv2.y - delta_pos2 * delta_uv1.y) * r,
+                (delta_pos2 * delta_uv1.x - delta_pos1 * delta_uv2.x) * r,
+            )
+        } else {
+            // Without texture coordinates to follow, any axes across the normal will do
+            let normal: Vector3<f32> = v0.normal.into();
+            let across = if normal.x.abs() < 0.9 {
+                Vector3::unit_x()
+            } else {
+                Vector3::unit_y()
+            };
+            let tangent = normal.cross(across).normalize();
+            (tangent, normal.cross(tangent))
+        };
+
+        vertices[chunk[0] as usize].tangent = tangent.into();
+        vertices[chunk[1] as usize].tangent = tangent.into();
+        vertices[chunk[2] as usize].tangent = tangent.into();
+
+        vertices[chunk[0] as usize].bitangent = bitangent.into();
+        vertices[chunk[1] as usize].bitangent = bitangent.into();
+        vertices[chunk[2] as usize].bitangent = bitangent.into();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn missing_colours_default_to_white() {
+        let source = "newmtl  lit  \nKd 0 0 0\n\nnewmtl bare\nNs 10\n";
+        let (mut materials, _) = tobj::load_mtl_buf(&mut source.as_bytes()).unwrap();
+        default_missing_colours(source, &mut materials);
+        let colours = |material: &tobj::Material| {
+            [material.ambient, material.diffuse, material.specular]
+        };
+        assert_eq!(materials[0].name, "lit");
+        assert!(colours(&materials[0]) == [[1.0; 3], [0.0; 3], [1.0; 3]]);
+        assert_eq!(materials[1].name, "bare");
+        assert!(colours(&materials[1]) == [[1.0; 3]; 3]);
+    }
+
+    #[test]
+    fn positions_only() {
+        let dir = std::env::temp_dir().join(format!("mesh_positions_only_{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("quad.obj");
+        fs::write(&path, "v 0 0 0\nv 1 0 0\nv 1 1 0\nv 0 1 0\nf 1 2 3 4\n").unwrap();
+        let parsed = parse_obj(&path, &Progress::default());
+        let _ = fs::remove_dir_all(&dir);
+
+        let (meshes, materials, _) = parsed.unwrap();
+        assert!(materials.is_empty());
+        assert_eq!(meshes.len(), 1);
+        // Two triangles, each with vertices of its own
+        assert_eq!(meshes[0].vertices.len(), 6);
+        assert_eq!(&meshes[0].indices[..], &[0, 1, 2, 3, 4, 5]);
+        for vertex in meshes[0].vertices.iter() {
+            assert_eq!(vertex.tex_coords, [0.0; 2]);
+            assert_eq!(vertex.normal, [0.0, 0.0, 1.0]);
+            let tangent = Vector3::from(vertex.tangent);
+            assert!((tangent.magnitude() - 1.0).abs() < 1e-6);
+            assert!(tangent.dot(Vector3::from(vertex.normal)).abs() < 1e-6);
+        }
+    }
+}
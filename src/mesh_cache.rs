@@ -0,0 +1,456 @@
+//! Binary cache of a model's meshes, written next to its OBJ the first time it's loaded.
+//! It holds the finished vertices, tangents included, so later loads skip parsing and
+//! tangent generation and use the arrays straight out of the file.
+//!
+//! The file is native endian 32 bit words, so every array in it is aligned for a
+//! `bytemuck` cast. That makes a cache specific to hosts of the endianness that wrote it,
+//! anywhere else its version doesn't match and it's rebuilt. After the header (magic,
+//! version, size of a `ModelVertex` and a checksum of the rest) come the length,
+//! modification time and hash of the OBJ it was built from, the MTL libraries the OBJ
+//! names, then for each mesh its name, material name, bounds, vertex and index counts,
+//! vertices and indices. Strings are a byte length followed by UTF-8, padded to a whole
+//! word.
+
+use std::fs::{self, File};
+use std::io::Read;
+use std::ops::{Deref, Range};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::UNIX_EPOCH;
+
+use anyhow::{bail, Context, Result};
+use cgmath::Point3;
+
+use crate::bounds::Aabb;
+use crate::hash;
+use crate::mesh::{self, MeshData, ModelVertex, MtlMaterial};
+
+const MAGIC: u32 = u32::from_ne_bytes(*b"MESH");
+/// Bump whenever the layout changes, so older caches are rebuilt rather than misread.
+const VERSION: u32 = 2;
+const HEADER_WORDS: usize = 5;
+const VERTEX_WORDS: usize = std::mem::size_of::<ModelVertex>() / 4;
+
+/// A mesh's vertices or indices, either built in memory or borrowed from a cache file.
+pub enum MeshArray<T> {
+    Owned(Vec<T>),
+    Cached {
+        file: Arc<Vec<u32>>,
+        words: Range<usize>,
+    },
+}
+
+impl<T: bytemuck::Pod> Deref for MeshArray<T> {
+    type Target = [T];
+
+    fn deref(&self) -> &[T] {
+        match self {
+            MeshArray::Owned(elements) => elements,
+            MeshArray::Cached { file, words } => bytemuck::cast_slice(&file[words.clone()]),
+        }
+    }
+}
+
+impl<T> From<Vec<T>> for MeshArray<T> {
+    fn from(elements: Vec<T>) -> Self {
+        MeshArray::Owned(elements)
+    }
+}
+
+/// Where the cache of the OBJ at `source` lives.
+pub fn cache_path(source: &Path) -> PathBuf {
+    let mut path = source.as_os_str().to_owned();
+    path.push(".meshcache");
+    PathBuf::from(path)
+}
+
+struct CachedMesh {
+    name: String,
+    material: String,
+    bounds: Aabb,
+    vertices: MeshArray<ModelVertex>,
+    indices: MeshArray<u32>,
+}
+
+/// Meshes read from a cache. They name their materials rather than index them, as the
+/// MTL files are still read each time and may have changed.
+pub struct CachedModel {
+    mtl_libs: Vec<String>,
+    meshes: Vec<CachedMesh>,
+}
+
+impl CachedModel {
+    /// The materials of every MTL library the OBJ named, in order, as tobj merges them.
+    pub fn load_materials(&self, folder: &Path) -> Result<Vec<MtlMaterial>> {
+        let mut materials = Vec::new();
+        for lib in self.mtl_libs.iter() {
+            materials.append(&mut mesh::load_mtl_library(folder, lib)?);
+        }
+        Ok(materials)
+    }
+
+    /// The meshes, with their materials looked up by name in `materials`. A material
+    /// that's gone falls back to the first, as a mesh without one does.
+    pub fn into_meshes(self, materials: &[MtlMaterial]) -> Vec<MeshData> {
+        self.meshes
+            .into_iter()
+            .map(|mesh| MeshData {
+                material: materials
+                    .iter()
+                    .position(|material| material.mtl.name == mesh.material)
+                    .unwrap_or(0),
+                name: mesh.name,
+                bounds: mesh.bounds,
+                vertices: mesh.vertices,
+                indices: mesh.indices,
+            })
+            .collect()
+    }
+}
+
+/// Reads the cache of the OBJ at `source`. Gives `None` when there's no cache, or it was
+/// built from a different OBJ or by a different version, and an error when it's corrupt.
+/// The OBJ is only hashed when its length matches but its modification time doesn't.
+pub fn read(source: &Path) -> Result<Option<CachedModel>> {
+    profile_scope!("mesh_cache::read", "{}", source.display());
+    let path = cache_path(source);
+    let len = match fs::metadata(&path) {
+        Ok(metadata) => metadata.len() as usize,
+        Err(_) => return Ok(None),
+    };
+    if len % 4 != 0 || len < HEADER_WORDS * 4 {
+        bail!("{:?} is truncated", path);
+    }
+
+    // Read as words rather than bytes, so the arrays in it are aligned
+    let mut words = vec![0u32; len / 4];
+    File::open(&path)
+        .and_then(|mut file| file.read_exact(bytemuck::cast_slice_mut(&mut words)))
+        .with_context(|| format!("Could not read {:?}", path))?;
+
+    if words[0] != MAGIC || words[1] != VERSION || words[2] != VERTEX_WORDS as u32 * 4 {
+        return Ok(None);
+    }
+    let checksum = u64::from(words[3]) | u64::from(words[4]) << 32;
+    if hash::fnv1a(bytemuck::cast_slice(&words[HEADER_WORDS..])) != checksum {
+        bail!("{:?} does not match its checksum", path);
+    }
+
+    let source_metadata =
+        fs::metadata(source).with_context(|| format!("Could not read {:?}", source))?;
+    let words = Arc::new(words);
+    let mut reader = Reader {
+        words: &words,
+        position: HEADER_WORDS,
+    };
+    let source_len = reader.u64()?;
+    let source_modified = reader.u64()?;
+    let source_hash = reader.u64()?;
+    if source_len != source_metadata.len() {
+        return Ok(None);
+    }
+    if source_modified == 0 || source_modified != modified_nanos(&source_metadata) {
+        let source_bytes =
+            fs::read(source).with_context(|| format!("Could not read {:?}", source))?;
+        if source_hash != hash::fnv1a(&source_bytes) {
+            return Ok(None);
+        }
+    }
+
+    let mtl_libs = (0..reader.word()?)
+        .map(|_| reader.string())
+        .collect::<Result<Vec<_>>>()?;
+
+    let mut meshes = Vec::new();
+    for _ in 0..reader.word()? {
+        let name = reader.string()?;
+        let material = reader.string()?;
+        let bounds = Aabb {
+            min: reader.point()?,
+            max: reader.point()?,
+        };
+        let vertex_count = reader.word()? as usize;
+        let index_count = reader.word()? as usize;
+        meshes.push(CachedMesh {
+            name,
+            material,
+            bounds,
+            vertices: MeshArray::Cached {
+                file: Arc::clone(&words),
+                words: reader.array(vertex_count * VERTEX_WORDS)?,
+            },
+            indices: MeshArray::Cached {
+                file: Arc::clone(&words),
+                words: reader.array(index_count)?,
+            },
+        });
+    }
+
+    Ok(Some(CachedModel { mtl_libs, meshes }))
+}
+
+/// Writes the cache of the OBJ at `source`, which was parsed into `meshes` indexing
+/// `materials`, from the MTL files it names as `mtl_libs`.
+pub fn write(
+    source: &Path,
+    mtl_libs: &[String],
+    meshes: &[MeshData],
+    materials: &[MtlMaterial],
+) -> Result<()> {
+    profile_scope!("mesh_cache::write", "{}", source.display());
+    let source_bytes = fs::read(source).with_context(|| format!("Could not read {:?}", source))?;
+    let source_modified = fs::metadata(source).map_or(0, |metadata| modified_nanos(&metadata));
+
+    let mut body = Vec::new();
+    push_u64(&mut body, source_bytes.len() as u64);
+    push_u64(&mut body, source_modified);
+    push_u64(&mut body, hash::fnv1a(&source_bytes));
+    body.push(mtl_libs.len() as u32);
+    for lib in mtl_libs.iter() {
+        push_string(&mut body, lib);
+    }
+
+    body.push(meshes.len() as u32);
+    for mesh in meshes {
+        push_string(&mut body, &mesh.name);
+        let material = materials.get(mesh.material).map_or("", |material| &material.mtl.name);
+        push_string(&mut body, material);
+        for point in [mesh.bounds.min, mesh.bounds.max].iter() {
+            body.extend([point.x, point.y, point.z].iter().map(|f| f.to_bits()));
+        }
+        body.push(mesh.vertices.len() as u32);
+        body.push(mesh.indices.len() as u32);
+        body.extend_from_slice(bytemuck::cast_slice(&mesh.vertices));
+        body.extend_from_slice(&mesh.indices);
+    }
+
+    let checksum = hash::fnv1a(bytemuck::cast_slice(&body));
+    let mut words = vec![MAGIC, VERSION, VERTEX_WORDS as u32 * 4];
+    push_u64(&mut words, checksum);
+    words.append(&mut body);
+
+    // Written aside then moved into place, so a reader never sees half a file
+    let path = cache_path(source);
+    let partial = path.with_extension("meshcache.partial");
+    fs::write(&partial, bytemuck::cast_slice(&words))
+        .and_then(|_| fs::rename(&partial, &path))
+        .with_context(|| format!("Could not write {:?}", path))?;
+    log::info!("Wrote mesh cache {:?}", path);
+    Ok(())
+}
+
+/// A file's modification time in nanoseconds since the epoch, or 0 when it isn't known.
+fn modified_nanos(metadata: &fs::Metadata) -> u64 {
+    metadata
+        .modified()
+        .ok()
+        .and_then(|modified| modified.duration_since(UNIX_EPOCH).ok())
+        .map_or(0, |since| since.as_nanos() as u64)
+}
+
+fn push_u64(words: &mut Vec<u32>, value: u64) {
+    words.push(value as u32);
+    words.push((value >> 32) as u32);
+}
+
+fn push_string(words: &mut Vec<u32>, string: &str) {
+    words.push(string.len() as u32);
+    let mut bytes = string.as_bytes().to_vec();
+    bytes.resize(bytes.len().div_ceil(4) * 4, 0);
+    words.extend(
+        bytes
+            .chunks(4)
+            .map(|chunk| u32::from_ne_bytes([chunk[0], chunk[1], chunk[2], chunk[3]])),
+    );
+}
+
+struct Reader<'a> {
+    words: &'a [u32],
+    position: usize,
+}
+
+impl Reader<'_> {
+    /// The range of the next `len` words, which are skipped over.
+    fn array(&mut self, len: usize) -> Result<Range<usize>> {
+        let range = self.position..self.position + len;
+        if range.end > self.words.len() {
+            bail!("Mesh cache is truncated");
+        }
+        self.position = range.end;
+        Ok(range)
+    }
+
+    fn word(&mut self) -> Result<u32> {
+        let range = self.array(1)?;
+        Ok(self.words[range.start])
+    }
+
+    fn u64(&mut self) -> Result<u64> {
+        Ok(u64::from(self.word()?) | u64::from(self.word()?) << 32)
+    }
+
+    fn point(&mut self) -> Result<Point3<f32>> {
+        let range = self.array(3)?;
+        let xyz = &self.words[range];
+        Ok(Point3::new(
+            f32::from_bits(xyz[0]),
+            f32::from_bits(xyz[1]),
+            f32::from_bits(xyz[2]),
+        ))
+    }
+
+    fn string(&mut self) -> Result<String> {
+        let len = self.word()? as usize;
+        let range = self.array(len.div_ceil(4))?;
+        let bytes: &[u8] = bytemuck::cast_slice(&self.words[range]);
+        String::from_utf8(bytes[..len].to_vec()).context("Mesh cache has a string that isn't UTF-8")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// An OBJ file in its own temporary directory, removed on drop.
+    struct TempObj(PathBuf);
+
+    impl TempObj {
+        fn new(name: &str) -> Self {
+            let dir = std::env::temp_dir()
+                .join(format!("mesh_cache_{}_{}", name, std::process::id()));
+            fs::create_dir_all(&dir).unwrap();
+            let path = dir.join("model.obj");
+            fs::write(&path, "o triangle\n").unwrap();
+            TempObj(path)
+        }
+    }
+
+    impl Drop for TempObj {
+        fn drop(&mut self) {
+            let _ = fs::remove_dir_all(self.0.parent().unwrap());
+        }
+    }
+
+    fn vertex(i: u32) -> ModelVertex {
+        let f = i as f32;
+        ModelVertex {
+            position: [f, f + 0.5, -f],
+            tex_coords: [f / 4.0, 1.0 - f / 4.0],
+            normal: [0.0, 0.0, 1.0],
+            tangent: [1.0, 0.0, 0.0],
+            bitangent: [0.0, 1.0, 0.0],
+        }
+    }
+
+    fn material(name: &str) -> MtlMaterial {
+        MtlMaterial {
+            library: PathBuf::new(),
+            mtl: tobj::Material {
+                name: name.to_string(),
+                ..Default::default()
+            },
+        }
+    }
+
+    fn write_triangles(obj: &Path) {
+        let vertices = (0..3).map(vertex).collect::<Vec<_>>();
+        let meshes = ["first", "second"]
+            .iter()
+            .enumerate()
+            .map(|(i, name)| MeshData {
+                name: name.to_string(),
+                bounds: Aabb::from_points(vertices.iter().map(|vertex| vertex.position.into())),
+                vertices: vertices.clone().into(),
+                indices: vec![0, 1, 2].into(),
+                material: i,
+            })
+            .collect::<Vec<_>>();
+        let materials = [material("red"), material("green")];
+        write(obj, &[], &meshes, &materials).unwrap();
+    }
+
+    #[test]
+    fn round_trip() {
+        let obj = TempObj::new("round_trip");
+        write_triangles(&obj.0);
+
+        let cached = read(&obj.0).unwrap().unwrap();
+        assert!(cached.load_materials(obj.0.parent().unwrap()).unwrap().is_empty());
+        // Materials are matched by name, wherever they are now
+        let meshes = cached.into_meshes(&[material("green"), material("blue"), material("red")]);
+        assert_eq!(meshes.len(), 2);
+        assert_eq!(meshes[0].name, "first");
+        assert_eq!(meshes[0].material, 2);
+        assert_eq!(meshes[1].name, "second");
+        assert_eq!(meshes[1].material, 0);
+        for mesh in meshes.iter() {
+            assert!(matches!(mesh.vertices, MeshArray::Cached { .. }));
+            assert_eq!(mesh.bounds.min, Point3::new(0.0, 0.5, -2.0));
+            assert_eq!(mesh.bounds.max, Point3::new(2.0, 2.5, 0.0));
+            assert_eq!(&mesh.indices[..], &[0, 1, 2]);
+            for (i, cached) in mesh.vertices.iter().enumerate() {
+                assert_eq!(bytemuck::bytes_of(cached), bytemuck::bytes_of(&vertex(i as u32)));
+            }
+        }
+    }
+
+    #[test]
+    fn missing_or_stale() {
+        let obj = TempObj::new("stale");
+        assert!(read(&obj.0).unwrap().is_none());
+        write_triangles(&obj.0);
+        fs::write(&obj.0, "o changed\n").unwrap();
+        assert!(read(&obj.0).unwrap().is_none());
+    }
+
+    #[test]
+    fn hashes_when_only_the_time_changed() {
+        let obj = TempObj::new("touched");
+        write_triangles(&obj.0);
+        let touch = |contents: &str| {
+            fs::write(&obj.0, contents).unwrap();
+            let later = std::time::SystemTime::now() + std::time::Duration::from_secs(60);
+            File::options().write(true).open(&obj.0).unwrap().set_modified(later).unwrap();
+        };
+
+        // Same contents, so still fresh
+        touch("o triangle\n");
+        assert!(read(&obj.0).unwrap().is_some());
+        // Same length, different contents
+        touch("o triangel\n");
+        assert!(read(&obj.0).unwrap().is_none());
+    }
+
+    #[test]
+    fn corrupt() {
+        let obj = TempObj::new("corrupt");
+        write_triangles(&obj.0);
+        let path = cache_path(&obj.0);
+        let mut bytes = fs::read(&path).unwrap();
+        let last = bytes.len() - 1;
+        bytes[last] ^= 1;
+        fs::write(&path, &bytes).unwrap();
+        assert!(read(&obj.0).is_err());
+    }
+
+    #[test]
+    fn truncated() {
+        let obj = TempObj::new("truncated");
+        write_triangles(&obj.0);
+        let path = cache_path(&obj.0);
+        let bytes = fs::read(&path).unwrap();
+        // Cut mid word, and at a word but with the checksum fixed up to match
+        fs::write(&path, &bytes[..bytes.len() - 2]).unwrap();
+        assert!(read(&obj.0).is_err());
+
+        let mut words = bytes[..bytes.len() - 4]
+            .chunks_exact(4)
+            .map(|word| u32::from_ne_bytes([word[0], word[1], word[2], word[3]]))
+            .collect::<Vec<_>>();
+        let checksum = hash::fnv1a(bytemuck::cast_slice(&words[HEADER_WORDS..]));
+        words[3] = checksum as u32;
+        words[4] = (checksum >> 32) as u32;
+        fs::write(&path, bytemuck::cast_slice(&words)).unwrap();
+        assert!(read(&obj.0).is_err());
+    }
+}
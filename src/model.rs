@@ -1,22 +1,31 @@
-use std::{ops::Range, path::Path};
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::{ops::Range, rc::Rc, thread};
 
 use anyhow::*;
-use cgmath::{Vector2, Vector3};
+use cgmath::Vector3;
 
+use crate::assets::{AssetCache, DecodedImage};
+use crate::bounds::Aabb;
+use crate::loader::Progress;
+use crate::mesh::{self, MeshData, MtlMaterial};
+use crate::mesh_cache;
 use crate::vertex::Vertex;
 use crate::texture::Texture;
+use crate::{shader, shader_interface};
 
 use wgpu::util::DeviceExt;
 
 
-#[repr(C)]
-#[derive(Debug, Clone, Copy, bytemuck::Pod, bytemuck::Zeroable)]
-pub struct ModelVertex {
-	position: [f32; 3],
-	tex_coords: [f32; 2],
-	normal: [f32; 3],
-    tangent: [f32; 3],
-    bitangent: [f32; 3],
+pub use crate::mesh::ModelVertex;
+
+const _: [(); std::mem::size_of::<ModelVertex>()] =
+    [(); shader_interface::stride(shader_interface::MODEL_VERTEX)];
+
+impl ModelVertex {
+    const ATTRIBUTES: [wgpu::VertexAttribute; shader_interface::MODEL_VERTEX.len()] =
+        shader::vertex_attributes(shader_interface::MODEL_VERTEX);
 }
 
 impl Vertex for ModelVertex {
@@ -24,44 +33,350 @@ impl Vertex for ModelVertex {
         wgpu::VertexBufferLayout {
             array_stride: std::mem::size_of::<ModelVertex>() as wgpu::BufferAddress,
             step_mode: wgpu::InputStepMode::Vertex,
-            attributes: &[
-                wgpu::VertexAttribute {
-                    format: wgpu::VertexFormat::Float32x3,
-                    offset: 0,
-                    shader_location: 0,
+            attributes: &Self::ATTRIBUTES,
+        }
+    }
+}
+
+/// Specular exponent for materials that don't give one.
+const DEFAULT_SHININESS: f32 = 32.0;
+/// Normal map texel pointing straight out of the surface.
+const FLAT_NORMAL: [u8; 4] = [128, 128, 255, 255];
+/// Alpha below which masked materials are cut out, when they don't give their own.
+const DEFAULT_ALPHA_CUTOFF: f32 = 0.5;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum AlphaMode {
+	Opaque,
+	/// Fragments with a diffuse alpha below the cutoff are discarded.
+	Mask(f32),
+	/// Blended over whatever is behind, drawn after everything opaque.
+	Blend,
+}
+
+impl AlphaMode {
+	/// MTL has no alpha mode of its own. Materials with an alpha map (`map_d`) are masked,
+	/// as foliage usually is, using the diffuse texture's alpha, and ones with a dissolve
+	/// below 1 are blended. The non-standard `alpha_mode opaque|mask|blend` and
+	/// `alpha_cutoff` statements override this.
+	fn from_mtl(mat: &tobj::Material) -> Self {
+		let cutoff = mat.unknown_param.get("alpha_cutoff")
+			.and_then(|cutoff| cutoff.parse().ok())
+			.unwrap_or(DEFAULT_ALPHA_CUTOFF);
+		match mat.unknown_param.get("alpha_mode").map(String::as_str) {
+			Some("opaque") => return AlphaMode::Opaque,
+			Some("mask") => return AlphaMode::Mask(cutoff),
+			Some("blend") => return AlphaMode::Blend,
+			Some(other) => log::warn!("Unknown alpha_mode {:?} in material {}", other, mat.name),
+			None => {}
+		}
+
+		if mat.dissolve < 1.0 {
+			AlphaMode::Blend
+		} else if !mat.dissolve_texture.is_empty() {
+			AlphaMode::Mask(cutoff)
+		} else {
+			AlphaMode::Opaque
+		}
+	}
+}
+
+/// The scalar properties of an MTL material, as laid out in the shader's uniform.
+#[repr(C)]
+#[derive(Debug, Clone, Copy, bytemuck::Pod, bytemuck::Zeroable)]
+pub struct MaterialUniform {
+	pub ambient: [f32; 4],
+	pub diffuse: [f32; 4],
+	pub specular: [f32; 4],
+	pub shininess: f32,
+	pub dissolve: f32,
+	/// 0 unless masked, so nothing is discarded.
+	pub alpha_cutoff: f32,
+	_padding: f32,
+}
+
+impl MaterialUniform {
+	fn from_mtl(mat: &tobj::Material, alpha_mode: AlphaMode) -> Self {
+		let [ar, ag, ab] = mat.ambient;
+		let [dr, dg, db] = mat.diffuse;
+		let [sr, sg, sb] = mat.specular;
+		Self {
+			ambient: [ar, ag, ab, 0.0],
+			diffuse: [dr, dg, db, 0.0],
+			specular: [sr, sg, sb, 0.0],
+			// An exponent of 0 would light the whole surface as a highlight
+			shininess: if mat.shininess > 0.0 { mat.shininess } else { DEFAULT_SHININESS },
+			dissolve: mat.dissolve,
+			alpha_cutoff: match alpha_mode {
+				AlphaMode::Mask(cutoff) => cutoff,
+				_ => 0.0,
+			},
+			_padding: 0.0,
+		}
+	}
+}
+
+pub struct Material {
+	pub name: String,
+	pub diffuse_texture: Rc<Texture>,
+	pub normal_texture: Rc<Texture>,
+	/// White when the material has no specular map.
+	pub specular_texture: Rc<Texture>,
+	pub alpha_mode: AlphaMode,
+	pub params: MaterialUniform,
+	pub params_buffer: wgpu::Buffer,
+	pub bind_group: wgpu::BindGroup,
+}
+
+impl Material {
+    /// Uploads `data`, sharing its textures through `assets`, with its maps from `images`
+    /// where they're still there. Missing maps are filled in with plain textures that leave
+    /// the material's colours as they are.
+    pub fn from_data(device: &wgpu::Device, queue: &wgpu::Queue, layout: &wgpu::BindGroupLayout, assets: &mut AssetCache, data: &MaterialData, images: &HashMap<PathBuf, DecodedImage>) -> Result<Self> {
+        profile_scope!("Material::from_data", "{}", data.mtl.name);
+        let mat = &data.mtl;
+        let alpha_mode = AlphaMode::from_mtl(mat);
+        let params = MaterialUniform::from_mtl(mat, alpha_mode);
+        let diffuse_texture = match &data.diffuse {
+            Some(path) => assets.texture(device, queue, path, images.get(path), false)?,
+            None => assets.colour_texture(device, queue, [255; 4], false)?,
+        };
+        let normal_texture = match &data.normal {
+            Some(path) => assets.texture(device, queue, path, images.get(path), true)?,
+            None => assets.colour_texture(device, queue, FLAT_NORMAL, true)?,
+        };
+        let specular_texture = match &data.specular {
+            Some(path) => assets.texture(device, queue, path, images.get(path), false)?,
+            None => assets.colour_texture(device, queue, [255; 4], false)?,
+        };
+
+        let params_buffer = device.create_buffer_init(&wgpu::util::BufferInitDescriptor {
+            label: Some(&format!("{} material buffer", mat.name)),
+            contents: bytemuck::bytes_of(&params),
+            usage: wgpu::BufferUsage::UNIFORM,
+        });
+
+        let bind_group = device.create_bind_group(&wgpu::BindGroupDescriptor{
+            label: None,
+            layout,
+            entries: &[
+                wgpu::BindGroupEntry {
+                    binding: 0,
+                    resource: wgpu::BindingResource::TextureView(&diffuse_texture.view),
+                },
+                wgpu::BindGroupEntry {
+                    binding: 1,
+                    resource: wgpu::BindingResource::Sampler(&diffuse_texture.sampler),
+                },
+                wgpu::BindGroupEntry {
+                    binding: 2,
+                    resource: wgpu::BindingResource::TextureView(&normal_texture.view),
                 },
-                wgpu::VertexAttribute {
-                    format: wgpu::VertexFormat::Float32x2,
-                    offset: std::mem::size_of::<[f32; 3]>() as wgpu::BufferAddress,
-                    shader_location: 1,
+                wgpu::BindGroupEntry {
+                    binding: 3,
+                    resource: wgpu::BindingResource::Sampler(&normal_texture.sampler),
                 },
-                wgpu::VertexAttribute {
-                    format: wgpu::VertexFormat::Float32x3,
-                    offset: std::mem::size_of::<[f32; 5]>() as wgpu::BufferAddress,
-                    shader_location: 2,
+                wgpu::BindGroupEntry {
+                    binding: 4,
+                    resource: wgpu::BindingResource::TextureView(&specular_texture.view),
                 },
-                wgpu::VertexAttribute {
-                    format: wgpu::VertexFormat::Float32x3,
-                    offset: std::mem::size_of::<[f32; 8]>() as wgpu::BufferAddress,
-                    shader_location: 3,
+                wgpu::BindGroupEntry {
+                    binding: 5,
+                    resource: wgpu::BindingResource::Sampler(&specular_texture.sampler),
+                },
+                wgpu::BindGroupEntry {
+                    binding: 6,
+                    resource: params_buffer.as_entire_binding(),
                 },
-                wgpu::VertexAttribute {
-                    format: wgpu::VertexFormat::Float32x3,
-                    offset: std::mem::size_of::<[f32; 11]>() as wgpu::BufferAddress,
-                    shader_location: 4,
-                }
             ],
-        }
+        });
+
+        Ok(Material {
+            name: mat.name.clone(),
+            diffuse_texture,
+            normal_texture,
+            specular_texture,
+            alpha_mode,
+            params,
+            params_buffer,
+            bind_group,
+        })
     }
 }
 
-pub struct Material {
+/// A material ready to upload, its maps decoded into [`ModelData::images`].
+pub struct MaterialData {
+	pub library: PathBuf,
+	pub mtl: tobj::Material,
+	/// Canonical paths of the maps.
+	pub diffuse: Option<PathBuf>,
+	pub normal: Option<PathBuf>,
+	pub specular: Option<PathBuf>,
+}
+
+impl MaterialData {
+	/// Plain grey, for models without materials.
+	fn default_grey() -> Self {
+		Self {
+			library: PathBuf::new(),
+			mtl: tobj::Material {
+				name: String::from("Default"),
+				ambient: [1.0; 3],
+				diffuse: [0.6; 3],
+				specular: [0.2; 3],
+				..Default::default()
+			},
+			diffuse: None,
+			normal: None,
+			specular: None,
+		}
+	}
+}
+
+/// Everything needed to build a [`Model`], prepared off the render thread.
+pub struct ModelData {
 	pub name: String,
-	pub diffuse_texture: Texture,
-    pub normal_texture: Texture,
-    pub bind_group: wgpu::BindGroup,
+	pub meshes: Vec<MeshData>,
+	pub materials: Vec<MaterialData>,
+	/// The materials' maps by canonical path, only needed until the model is uploaded.
+	pub images: HashMap<PathBuf, DecodedImage>,
 }
 
+impl ModelData {
+	/// Reads the OBJ at `path`, from its mesh cache when that's fresh, and its MTL files,
+	/// and decodes the maps its materials use. Slow, so meant for a worker thread, see
+	/// [`LoadHandle`](crate::loader::LoadHandle).
+	pub fn load(path: &Path, progress: &Arc<Progress>) -> Result<Self> {
+		profile_scope!("ModelData::load", "{}", path.display());
+		progress.add_steps(1);
+
+		let containing_folder = path.parent().context("Directory has no parent")?;
+
+		let cached = mesh_cache::read(path).unwrap_or_else(|e| {
+			log::warn!("Ignoring the mesh cache of {:?}: {:#}", path, e);
+			None
+		});
+		let (meshes, obj_materials) = match cached {
+			Some(cached) => {
+				let obj_materials = cached.load_materials(containing_folder)?;
+				(cached.into_meshes(&obj_materials), obj_materials)
+			}
+			None => {
+				let (meshes, obj_materials, mtl_libs) = mesh::parse_obj(path, progress)?;
+				if let Err(e) = mesh_cache::write(path, &mtl_libs, &meshes, &obj_materials) {
+					log::warn!("Could not cache the meshes of {:?}: {:#}", path, e);
+				}
+				(meshes, obj_materials)
+			}
+		};
+		progress.step_done();
+
+		// Each image is decoded once on its own thread, however many materials use it
+		let mut image_paths = Vec::new();
+		for MtlMaterial { mtl: mat, .. } in obj_materials.iter() {
+			for file in [&mat.diffuse_texture, &mat.normal_texture, &mat.specular_texture].iter() {
+				let image_path = containing_folder.join(file);
+				if !file.is_empty() && !image_paths.contains(&image_path) {
+					image_paths.push(image_path);
+				}
+			}
+		}
+		progress.add_steps(image_paths.len());
+		let decoders = image_paths
+			.iter()
+			.map(|image_path| {
+				let image_path = image_path.clone();
+				let progress = Arc::clone(progress);
+				thread::spawn(move || {
+					let image = DecodedImage::open(&image_path);
+					progress.step_done();
+					image
+				})
+			})
+			.collect::<Vec<_>>();
+		let mut images = HashMap::new();
+		for (image_path, decoder) in image_paths.into_iter().zip(decoders) {
+			let image = decoder
+				.join()
+				.map_err(|_| anyhow!("Decoding {:?} panicked", image_path))??;
+			images.insert(image_path, image);
+		}
+
+		let image = |file: &str| {
+			if file.is_empty() {
+				None
+			} else {
+				images.get(&containing_folder.join(file)).map(|image| image.path.clone())
+			}
+		};
+		let mut materials = obj_materials
+			.into_iter()
+			.map(|MtlMaterial { library, mtl }| MaterialData {
+				diffuse: image(&mtl.diffuse_texture),
+				normal: image(&mtl.normal_texture),
+				specular: image(&mtl.specular_texture),
+				library,
+				mtl,
+			})
+			.collect::<Vec<_>>();
+		if materials.is_empty() {
+			materials.push(MaterialData::default_grey());
+		}
+
+		Ok(Self {
+			name: path.display().to_string(),
+			meshes,
+			materials,
+			images: images.into_values().map(|image| (image.path.clone(), image)).collect(),
+		})
+	}
+
+	/// A unit cube in a plain material, to draw while the real model loads.
+	pub fn placeholder() -> Self {
+		// Each face as its normal and two axes spanning it
+		let faces: [([f32; 3], [f32; 3], [f32; 3]); 6] = [
+			([1.0, 0.0, 0.0], [0.0, 0.0, -1.0], [0.0, 1.0, 0.0]),
+			([-1.0, 0.0, 0.0], [0.0, 0.0, 1.0], [0.0, 1.0, 0.0]),
+			([0.0, 1.0, 0.0], [1.0, 0.0, 0.0], [0.0, 0.0, -1.0]),
+			([0.0, -1.0, 0.0], [1.0, 0.0, 0.0], [0.0, 0.0, 1.0]),
+			([0.0, 0.0, 1.0], [1.0, 0.0, 0.0], [0.0, 1.0, 0.0]),
+			([0.0, 0.0, -1.0], [-1.0, 0.0, 0.0], [0.0, 1.0, 0.0]),
+		];
+
+		let mut vertices = Vec::with_capacity(24);
+		let mut indices = Vec::with_capacity(36);
+		for (normal, u, v) in faces.iter() {
+			let normal = Vector3::from(*normal);
+			let (u, v) = (Vector3::from(*u), Vector3::from(*v));
+			let first = vertices.len() as u32;
+			for (s, t) in [(0.0, 0.0), (1.0, 0.0), (1.0, 1.0), (0.0, 1.0)].iter() {
+				let position = (normal + u * (s * 2.0 - 1.0) + v * (t * 2.0 - 1.0)) * 0.5;
+				vertices.push(ModelVertex {
+					position: position.into(),
+					tex_coords: [*s, 1.0 - t],
+					normal: normal.into(),
+					tangent: [0.0; 3],
+					bitangent: [0.0; 3],
+				});
+			}
+			indices.extend_from_slice(&[first, first + 1, first + 2, first, first + 2, first + 3]);
+		}
+		mesh::generate_tangents(&mut vertices, &indices);
+
+		Self {
+			name: String::from("Placeholder"),
+			meshes: vec![MeshData {
+				name: String::from("Placeholder"),
+				bounds: Aabb::from_points(vertices.iter().map(|vertex| vertex.position.into())),
+				vertices: vertices.into(),
+				indices: indices.into(),
+				material: 0,
+			}],
+			materials: vec![MaterialData::default_grey()],
+			images: HashMap::new(),
+		}
+	}
+}
 
 pub struct Mesh {
 	pub name: String,
@@ -69,146 +384,84 @@ pub struct Mesh {
 	pub index_buffer: wgpu::Buffer,
 	pub num_elements: u32,
 	pub material: usize,
+	pub bounds: Aabb,
+	/// Kept on the CPU for ray casting.
+	pub positions: Vec<[f32; 3]>,
+	pub indices: Vec<u32>,
 }
 
 pub struct Model {
 	pub meshes: Vec<Mesh>,
-	pub materials: Vec<Material>,
+	/// Shared with any other model using the same materials.
+	pub materials: Vec<Rc<Material>>,
+	pub bounds: Aabb,
 }
 
 impl Model {
-	pub fn load<P: AsRef<Path>>(device: &wgpu::Device, queue: &wgpu::Queue, layout: &wgpu::BindGroupLayout, path: P) -> Result<Self> {
-		let (obj_models, obj_materials) = tobj::load_obj(path.as_ref(), &tobj::LoadOptions {
-		    triangulate: true,
-		    single_index: true,
-		    ..Default::default()
-        })?;
-        
-        let obj_materials = obj_materials?;
-
-        let containing_folder = path.as_ref().parent().context("Directory has no parent")?;
+    /// Uploads a model prepared by [`ModelData::load`]. This part has to run on the render thread.
+    /// `data` is left as it was, to upload again should the device be lost, though its images
+    /// can be dropped after, as the textures they became stay cached in `assets`.
+    pub fn from_data(device: &wgpu::Device, queue: &wgpu::Queue, layout: &wgpu::BindGroupLayout, assets: &mut AssetCache, data: &ModelData) -> Result<Self> {
+        profile_scope!("Model::from_data", "{}", data.name);
 
         let mut materials = Vec::new();
 
-        for mat in obj_materials {
-            let diffuse_path = mat.diffuse_texture;
-            let diffuse_texture = Texture::load(device, queue, containing_folder.join(diffuse_path), false)?;
-
-            let normal_path = mat.normal_texture;
-            let normal_texture = Texture::load(device, queue, containing_folder.join(normal_path), true)?;
-
-            let bind_group = device.create_bind_group(&wgpu::BindGroupDescriptor{
-                label: None,
-                layout,
-                entries: &[
-                    wgpu::BindGroupEntry {
-                        binding: 0,
-                        resource: wgpu::BindingResource::TextureView(&diffuse_texture.view),
-                    },
-                    wgpu::BindGroupEntry {
-                        binding: 1,
-                        resource: wgpu::BindingResource::Sampler(&diffuse_texture.sampler),
-                    },
-                    wgpu::BindGroupEntry {
-                        binding: 2,
-                        resource: wgpu::BindingResource::TextureView(&normal_texture.view),
-                    },
-                    wgpu::BindGroupEntry {
-                        binding: 3,
-                        resource: wgpu::BindingResource::Sampler(&normal_texture.sampler),
-                    },
-                ],
-            });
-
-            materials.push(Material {
-                name: mat.name,
-                diffuse_texture,
-                normal_texture,
-                bind_group,
-            })
-        };
+        for mat in data.materials.iter() {
+            let material = assets.material(&mat.library, &mat.mtl.name, |assets| {
+                Material::from_data(device, queue, layout, assets, mat, &data.images)
+            })?;
+            materials.push(material);
+        }
 
         let mut meshes = Vec::new();
 
-        for model in obj_models {
-            let mut vertices = Vec::with_capacity(model.mesh.positions.len() / 3);
-            for i in 0..model.mesh.positions.len() / 3 {
-                vertices.push(ModelVertex {
-                    position: [
-                        model.mesh.positions[i * 3],
-                        model.mesh.positions[i * 3 + 1],
-                        model.mesh.positions[i * 3 + 2],
-                    ],
-                    tex_coords: [model.mesh.texcoords[i * 2], model.mesh.texcoords[i * 2 + 1]],
-                    normal: [
-                        model.mesh.normals[i * 3],
-                        model.mesh.normals[i * 3 + 1],
-                        model.mesh.normals[i * 3 + 2],
-                    ],
-                    tangent: [0.0; 3],
-                    bitangent: [0.0; 3],
+        for mesh in data.meshes.iter() {
+            let (vertex_buffer, index_buffer) = {
+                profile_scope!("upload mesh", "{}", mesh.name);
+                let vertex_buffer = device.create_buffer_init(&wgpu::util::BufferInitDescriptor {
+                    label: Some(&format!("{} Vertex Buffer", data.name)),
+                    contents: bytemuck::cast_slice(&mesh.vertices),
+                    usage: wgpu::BufferUsage::VERTEX,
                 });
-            }
-
-            let indices = &model.mesh.indices;
-
-            for chunk in indices.chunks(3) {
-                let v0 = vertices[chunk[0] as usize];
-                let v1 = vertices[chunk[1] as usize];
-                let v2 = vertices[chunk[2] as usize];
-
-                let pos0: Vector3<f32> = v0.position.into();
-                let pos1: Vector3<f32> = v1.position.into();
-                let pos2: Vector3<f32> = v2.position.into();
-
-                let uv0: Vector2<f32> = v0.tex_coords.into();
-                let uv1: Vector2<f32> = v1.tex_coords.into();
-                let uv2: Vector2<f32> = v2.tex_coords.into();
-
-                let delta_pos1 = pos1 - pos0;
-                let delta_pos2 = pos2 - pos0;
-
-                let delta_uv1 = uv1 - uv0;
-                let delta_uv2 = uv2 - uv0;
-                let r = 1.0 / (delta_uv1.x * delta_uv2.y - delta_uv1.y * delta_uv2.x);
-                let tangent = (delta_pos1 * delta_uv2.y - delta_pos2 * delta_uv1.y) * r;
-                let bitangent = (delta_pos2 * delta_uv1.x - delta_pos1 * delta_uv2.x) * r;
 
-                vertices[chunk[0] as usize].tangent = tangent.into();
-                vertices[chunk[1] as usize].tangent = tangent.into();
-                vertices[chunk[2] as usize].tangent = tangent.into();
-
-                vertices[chunk[0] as usize].bitangent = bitangent.into();
-                vertices[chunk[1] as usize].bitangent = bitangent.into();
-                vertices[chunk[2] as usize].bitangent = bitangent.into();
-            }
-
-            let vertex_buffer = device.create_buffer_init(&wgpu::util::BufferInitDescriptor {
-                label: Some(&format!("{:?} Vertex Buffer", path.as_ref())),
-                contents: bytemuck::cast_slice(&vertices),
-                usage: wgpu::BufferUsage::VERTEX,
-            });
-
-            let index_buffer = device.create_buffer_init(&wgpu::util::BufferInitDescriptor {
-                label: Some(&format!("{:?} Index Buffer", path.as_ref())),
-                contents: bytemuck::cast_slice(&model.mesh.indices),
-                usage: wgpu::BufferUsage::INDEX,
-            });
+                let index_buffer = device.create_buffer_init(&wgpu::util::BufferInitDescriptor {
+                    label: Some(&format!("{} Index Buffer", data.name)),
+                    contents: bytemuck::cast_slice(&mesh.indices),
+                    usage: wgpu::BufferUsage::INDEX,
+                });
+                (vertex_buffer, index_buffer)
+            };
 
             meshes.push(Mesh {
-                name: model.name,
+                name: mesh.name.clone(),
                 vertex_buffer,
                 index_buffer,
-                num_elements: model.mesh.indices.len() as u32,
-                material: model.mesh.material_id.unwrap_or(0),
+                num_elements: mesh.indices.len() as u32,
+                material: mesh.material,
+                bounds: mesh.bounds,
+                positions: mesh.vertices.iter().map(|vertex| vertex.position).collect(),
+                indices: mesh.indices.to_vec(),
             });
         }
 
+        let bounds = meshes.iter().fold(Aabb::empty(), |bounds, mesh| bounds.union(&mesh.bounds));
+
         Ok(Self{
             meshes,
             materials,
+            bounds,
         })
-	}
+    }
+
+    pub fn is_blended(&self, mesh: &Mesh) -> bool {
+        self.materials
+            .get(mesh.material)
+            .is_some_and(|material| material.alpha_mode == AlphaMode::Blend)
+    }
+
+    pub fn has_blended_meshes(&self) -> bool {
+        self.meshes.iter().any(|mesh| self.is_blended(mesh))
+    }
 }
 
 pub trait DrawModel<'a, 'b>
@@ -219,6 +472,8 @@ where 'b: 'a,
 
     fn draw_model(&mut self, model: &'b Model, uniforms: &'b wgpu::BindGroup, light: &'b wgpu::BindGroup);
     fn draw_model_instanced(&mut self, model: &'b Model, instances: Range<u32>, uniforms: &'b wgpu::BindGroup, light: &'b wgpu::BindGroup);
+    /// Draws only the meshes with blended materials when `blended`, and only the others otherwise.
+    fn draw_model_layer_instanced(&mut self, model: &'b Model, blended: bool, instances: Range<u32>, uniforms: &'b wgpu::BindGroup, light: &'b wgpu::BindGroup);
 }
 
 impl<'a, 'b> DrawModel<'a, 'b> for wgpu::RenderPass<'a>
@@ -232,8 +487,8 @@ where 'b: 'a
         self.set_vertex_buffer(0, mesh.vertex_buffer.slice(..));
         self.set_index_buffer(mesh.index_buffer.slice(..), wgpu::IndexFormat::Uint32);
         self.set_bind_group(0, &material.bind_group, &[]);
-        self.set_bind_group(1, &uniforms, &[]);
-        self.set_bind_group(2, &light, &[]);
+        self.set_bind_group(1, uniforms, &[]);
+        self.set_bind_group(2, light, &[]);
         self.draw_indexed(0..mesh.num_elements, 0, instances);
     }
 
@@ -247,6 +502,13 @@ where 'b: 'a
             self.draw_mesh_instanced(mesh, material, instances.clone(), uniforms, light);
         }
     }
+
+    fn draw_model_layer_instanced(&mut self, model: &'b Model, blended: bool, instances: Range<u32>, uniforms: &'b wgpu::BindGroup, light: &'b wgpu::BindGroup) {
+        for mesh in model.meshes.iter().filter(|mesh| model.is_blended(mesh) == blended) {
+            let material = &model.materials[mesh.material];
+            self.draw_mesh_instanced(mesh, material, instances.clone(), uniforms, light);
+        }
+    }
 }
 
 pub trait DrawLight<'a, 'b>
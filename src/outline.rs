@@ -0,0 +1,194 @@
+//! Outlines around highlighted instances. Each one is first drawn into the stencil buffer
+//! only, then drawn again slightly larger and in a flat colour wherever the stencil isn't
+//! set, so just a rim around it shows.
+
+use std::ops::Range;
+
+use anyhow::Result;
+use wgpu::util::DeviceExt;
+
+use crate::instance::InstanceRaw;
+use crate::model::{Model, ModelVertex};
+use crate::preprocessor::Defines;
+use crate::shader::{self, ShaderLibrary};
+use crate::shader_interface;
+use crate::texture::Texture;
+use crate::vertex::Vertex;
+
+const STENCIL_MARK: u32 = 1;
+
+pub struct Outline {
+    mask_pipeline: wgpu::RenderPipeline,
+    outline_pipeline: wgpu::RenderPipeline,
+    mask_bind_group: wgpu::BindGroup,
+    outline_bind_group: wgpu::BindGroup,
+}
+
+impl Outline {
+    pub const COLOUR: [f32; 4] = [1.0, 0.6, 0.1, 1.0];
+    /// How much larger than the instance its outline is drawn.
+    pub const SCALE: f32 = 1.06;
+
+    pub fn new(
+        device: &wgpu::Device,
+        uniform_bind_group_layout: &wgpu::BindGroupLayout,
+        texture_bind_group_layout: &wgpu::BindGroupLayout,
+        colour_format: wgpu::TextureFormat,
+        sample_count: u32,
+    ) -> Result<Self> {
+        let bind_group_layout = shader::create_bind_group_layout(
+            device,
+            "Outline bind group layout",
+            shader_interface::OUTLINE_GROUP,
+        );
+        let create_bind_group = |label, colour: [f32; 4], scale: f32| {
+            // Padded out to the 32 byte size of the uniform struct
+            let mut params = [0.0f32; 8];
+            params[..4].copy_from_slice(&colour);
+            params[4] = scale;
+            let buffer = device.create_buffer_init(&wgpu::util::BufferInitDescriptor {
+                label: Some(label),
+                contents: bytemuck::cast_slice(&params),
+                usage: wgpu::BufferUsage::UNIFORM,
+            });
+            device.create_bind_group(&wgpu::BindGroupDescriptor {
+                label: Some(label),
+                layout: &bind_group_layout,
+                entries: &[wgpu::BindGroupEntry {
+                    binding: 0,
+                    resource: buffer.as_entire_binding(),
+                }],
+            })
+        };
+        let mask_bind_group = create_bind_group("Outline mask", [0.0; 4], 1.0);
+        let outline_bind_group = create_bind_group("Outline", Self::COLOUR, Self::SCALE);
+
+        let layout = device.create_pipeline_layout(&wgpu::PipelineLayoutDescriptor {
+            label: Some("Outline pipeline layout"),
+            bind_group_layouts: &[
+                uniform_bind_group_layout,
+                &bind_group_layout,
+                texture_bind_group_layout,
+            ],
+            push_constant_ranges: &[],
+        });
+
+        let shader = device.create_shader_module(
+            &ShaderLibrary::embedded()
+                .load("outline.wgsl", &Defines::new())?,
+        );
+
+        let create_pipeline = |label, write_mask, depth_compare, stencil| {
+            device.create_render_pipeline(&wgpu::RenderPipelineDescriptor {
+                label: Some(label),
+                layout: Some(&layout),
+                vertex: wgpu::VertexState {
+                    module: &shader,
+                    entry_point: "main",
+                    buffers: &[ModelVertex::desc(), InstanceRaw::desc()],
+                },
+                fragment: Some(wgpu::FragmentState {
+                    module: &shader,
+                    entry_point: "main",
+                    targets: &[wgpu::ColorTargetState {
+                        format: colour_format,
+                        blend: Some(wgpu::BlendState::ALPHA_BLENDING),
+                        write_mask,
+                    }],
+                }),
+                primitive: wgpu::PrimitiveState {
+                    topology: wgpu::PrimitiveTopology::TriangleList,
+                    strip_index_format: None,
+                    front_face: wgpu::FrontFace::Ccw,
+                    cull_mode: None,
+                    polygon_mode: wgpu::PolygonMode::Fill,
+                    clamp_depth: false,
+                    conservative: false,
+                },
+                depth_stencil: Some(wgpu::DepthStencilState {
+                    format: Texture::DEPTH_FORMAT,
+                    depth_write_enabled: false,
+                    depth_compare,
+                    stencil,
+                    bias: wgpu::DepthBiasState::default(),
+                }),
+                multisample: wgpu::MultisampleState {
+                    count: sample_count,
+                    mask: !0,
+                    alpha_to_coverage_enabled: false,
+                },
+            })
+        };
+        let stencil = |compare, pass_op| {
+            let face = wgpu::StencilFaceState {
+                compare,
+                fail_op: wgpu::StencilOperation::Keep,
+                depth_fail_op: wgpu::StencilOperation::Keep,
+                pass_op,
+            };
+            wgpu::StencilState {
+                front: face,
+                back: face,
+                read_mask: !0,
+                write_mask: !0,
+            }
+        };
+
+        // Marks the visible parts of the instance, which are already in the depth buffer
+        let mask_pipeline = create_pipeline(
+            "Outline mask pipeline",
+            wgpu::ColorWrite::empty(),
+            wgpu::CompareFunction::LessEqual,
+            stencil(wgpu::CompareFunction::Always, wgpu::StencilOperation::Replace),
+        );
+        let outline_pipeline = create_pipeline(
+            "Outline pipeline",
+            wgpu::ColorWrite::ALL,
+            wgpu::CompareFunction::LessEqual,
+            stencil(wgpu::CompareFunction::NotEqual, wgpu::StencilOperation::Keep),
+        );
+
+        Ok(Self {
+            mask_pipeline,
+            outline_pipeline,
+            mask_bind_group,
+            outline_bind_group,
+        })
+    }
+
+    /// Outlines each range of instances of `model`. Draw after the scene, into a pass
+    /// that cleared the stencil buffer.
+    pub fn draw<'a>(
+        &'a self,
+        render_pass: &mut wgpu::RenderPass<'a>,
+        model: &'a Model,
+        instance_buffer: &'a wgpu::Buffer,
+        uniform_bind_group: &'a wgpu::BindGroup,
+        instances: &[Range<u32>],
+    ) {
+        if instances.is_empty() {
+            return;
+        }
+
+        render_pass.set_stencil_reference(STENCIL_MARK);
+        render_pass.set_bind_group(0, uniform_bind_group, &[]);
+        render_pass.set_vertex_buffer(1, instance_buffer.slice(..));
+        for (pipeline, bind_group) in [
+            (&self.mask_pipeline, &self.mask_bind_group),
+            (&self.outline_pipeline, &self.outline_bind_group),
+        ]
+        .iter()
+        {
+            render_pass.set_pipeline(pipeline);
+            render_pass.set_bind_group(1, bind_group, &[]);
+            for mesh in model.meshes.iter() {
+                render_pass.set_bind_group(2, &model.materials[mesh.material].bind_group, &[]);
+                render_pass.set_vertex_buffer(0, mesh.vertex_buffer.slice(..));
+                render_pass.set_index_buffer(mesh.index_buffer.slice(..), wgpu::IndexFormat::Uint32);
+                for range in instances.iter() {
+                    render_pass.draw_indexed(0..mesh.num_elements, 0, range.clone());
+                }
+            }
+        }
+    }
+}
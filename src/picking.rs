@@ -0,0 +1,283 @@
+//! Object picking. On request the instances are drawn into an `Rg32Uint` ID target, and the
+//! pixel asked about is read back to find which instance and mesh cover it.
+
+use anyhow::Result;
+use futures::executor::block_on;
+use wgpu::util::DeviceExt;
+
+use crate::model::Model;
+use crate::preprocessor::Defines;
+use crate::shader::{self, ShaderLibrary};
+use crate::shader_interface;
+use crate::texture::Texture;
+use crate::{instance::InstanceRaw, model::ModelVertex, vertex::Vertex};
+
+/// The instance index plus one in red, so 0 means nothing, and the mesh index in green.
+const ID_FORMAT: wgpu::TextureFormat = wgpu::TextureFormat::Rg32Uint;
+const ID_BYTES: u32 = 8;
+
+/// What was under the picked pixel.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Pick {
+    pub instance: u32,
+    pub mesh: u32,
+}
+
+impl Pick {
+    fn from_id([instance, mesh]: [u32; 2]) -> Option<Self> {
+        Some(Self {
+            instance: instance.checked_sub(1)?,
+            mesh,
+        })
+    }
+}
+
+struct Targets {
+    size: winit::dpi::PhysicalSize<u32>,
+    id_view: wgpu::TextureView,
+    id_texture: wgpu::Texture,
+    depth_view: wgpu::TextureView,
+}
+
+pub struct Picker {
+    pipeline: wgpu::RenderPipeline,
+    mesh_bind_group_layout: wgpu::BindGroupLayout,
+    /// One bind group per mesh index, holding the index for the shader.
+    mesh_bind_groups: Vec<wgpu::BindGroup>,
+    targets: Option<Targets>,
+    readback_buffer: wgpu::Buffer,
+}
+
+impl Picker {
+    pub fn new(
+        device: &wgpu::Device,
+        uniform_bind_group_layout: &wgpu::BindGroupLayout,
+        texture_bind_group_layout: &wgpu::BindGroupLayout,
+    ) -> Result<Self> {
+        let mesh_bind_group_layout = shader::create_bind_group_layout(
+            device,
+            "Picking mesh bind group layout",
+            shader_interface::PICK_GROUP,
+        );
+
+        let layout = device.create_pipeline_layout(&wgpu::PipelineLayoutDescriptor {
+            label: Some("Picking pipeline layout"),
+            bind_group_layouts: &[
+                uniform_bind_group_layout,
+                &mesh_bind_group_layout,
+                texture_bind_group_layout,
+            ],
+            push_constant_ranges: &[],
+        });
+
+        let shader = device.create_shader_module(
+            &ShaderLibrary::embedded()
+                .load("picking.wgsl", &Defines::new())?,
+        );
+
+        let pipeline = device.create_render_pipeline(&wgpu::RenderPipelineDescriptor {
+            label: Some("Picking Pipeline"),
+            layout: Some(&layout),
+            vertex: wgpu::VertexState {
+                module: &shader,
+                entry_point: "main",
+                buffers: &[ModelVertex::desc(), InstanceRaw::desc()],
+            },
+            fragment: Some(wgpu::FragmentState {
+                module: &shader,
+                entry_point: "main",
+                targets: &[wgpu::ColorTargetState {
+                    format: ID_FORMAT,
+                    blend: None,
+                    write_mask: wgpu::ColorWrite::ALL,
+                }],
+            }),
+            primitive: wgpu::PrimitiveState {
+                topology: wgpu::PrimitiveTopology::TriangleList,
+                strip_index_format: None,
+                front_face: wgpu::FrontFace::Ccw,
+                cull_mode: None,
+                polygon_mode: wgpu::PolygonMode::Fill,
+                clamp_depth: false,
+                conservative: false,
+            },
+            depth_stencil: Some(wgpu::DepthStencilState {
+                format: Texture::DEPTH_FORMAT,
+                depth_write_enabled: true,
+                depth_compare: wgpu::CompareFunction::LessEqual,
+                stencil: wgpu::StencilState::default(),
+                bias: wgpu::DepthBiasState::default(),
+            }),
+            multisample: wgpu::MultisampleState::default(),
+        });
+
+        // A single pixel is read back, but rows are copied in multiples of 256 bytes
+        let readback_buffer = device.create_buffer(&wgpu::BufferDescriptor {
+            label: Some("Picking readback buffer"),
+            size: wgpu::COPY_BYTES_PER_ROW_ALIGNMENT as wgpu::BufferAddress,
+            usage: wgpu::BufferUsage::COPY_DST | wgpu::BufferUsage::MAP_READ,
+            mapped_at_creation: false,
+        });
+
+        Ok(Self {
+            pipeline,
+            mesh_bind_group_layout,
+            mesh_bind_groups: Vec::new(),
+            targets: None,
+            readback_buffer,
+        })
+    }
+
+    fn create_targets(device: &wgpu::Device, size: winit::dpi::PhysicalSize<u32>) -> Targets {
+        let extent = wgpu::Extent3d {
+            width: size.width,
+            height: size.height,
+            depth_or_array_layers: 1,
+        };
+        let id_texture = device.create_texture(&wgpu::TextureDescriptor {
+            label: Some("Picking ID texture"),
+            size: extent,
+            mip_level_count: 1,
+            sample_count: 1,
+            dimension: wgpu::TextureDimension::D2,
+            format: ID_FORMAT,
+            usage: wgpu::TextureUsage::RENDER_ATTACHMENT | wgpu::TextureUsage::COPY_SRC,
+        });
+        let depth_texture = device.create_texture(&wgpu::TextureDescriptor {
+            label: Some("Picking depth texture"),
+            size: extent,
+            mip_level_count: 1,
+            sample_count: 1,
+            dimension: wgpu::TextureDimension::D2,
+            format: Texture::DEPTH_FORMAT,
+            usage: wgpu::TextureUsage::RENDER_ATTACHMENT,
+        });
+
+        Targets {
+            size,
+            id_view: id_texture.create_view(&wgpu::TextureViewDescriptor::default()),
+            id_texture,
+            depth_view: depth_texture.create_view(&wgpu::TextureViewDescriptor::default()),
+        }
+    }
+
+    fn mesh_bind_groups(&mut self, device: &wgpu::Device, meshes: usize) {
+        for index in self.mesh_bind_groups.len() as u32..meshes as u32 {
+            let buffer = device.create_buffer_init(&wgpu::util::BufferInitDescriptor {
+                label: Some("Picking mesh index buffer"),
+                contents: bytemuck::cast_slice(&[index, 0, 0, 0]),
+                usage: wgpu::BufferUsage::UNIFORM,
+            });
+            self.mesh_bind_groups
+                .push(device.create_bind_group(&wgpu::BindGroupDescriptor {
+                    label: Some("Picking mesh bind group"),
+                    layout: &self.mesh_bind_group_layout,
+                    entries: &[wgpu::BindGroupEntry {
+                        binding: 0,
+                        resource: buffer.as_entire_binding(),
+                    }],
+                }));
+        }
+    }
+
+    /// Finds what covers pixel `(x, y)` of a `size` frame, drawing `instances` of `model` as
+    /// the camera in `uniform_bind_group` sees them. Waits for the GPU to finish, so it's
+    /// for occasional use like a click rather than every frame.
+    #[allow(clippy::too_many_arguments)]
+    pub fn pick(
+        &mut self,
+        device: &wgpu::Device,
+        queue: &wgpu::Queue,
+        uniform_bind_group: &wgpu::BindGroup,
+        model: &Model,
+        instance_buffer: &wgpu::Buffer,
+        instances: u32,
+        size: winit::dpi::PhysicalSize<u32>,
+        (x, y): (u32, u32),
+    ) -> Option<Pick> {
+        profile_scope!("Picker::pick");
+        if x >= size.width || y >= size.height {
+            return None;
+        }
+
+        if self.targets.as_ref().map(|targets| targets.size) != Some(size) {
+            self.targets = Some(Self::create_targets(device, size));
+        }
+        self.mesh_bind_groups(device, model.meshes.len());
+        let targets = self.targets.as_ref().unwrap();
+
+        let mut encoder = device.create_command_encoder(&wgpu::CommandEncoderDescriptor {
+            label: Some("Picking Encoder"),
+        });
+        {
+            let mut render_pass = encoder.begin_render_pass(&wgpu::RenderPassDescriptor {
+                label: Some("Picking render pass"),
+                color_attachments: &[wgpu::RenderPassColorAttachment {
+                    view: &targets.id_view,
+                    resolve_target: None,
+                    ops: wgpu::Operations {
+                        load: wgpu::LoadOp::Clear(wgpu::Color::TRANSPARENT),
+                        store: true,
+                    },
+                }],
+                depth_stencil_attachment: Some(wgpu::RenderPassDepthStencilAttachment {
+                    view: &targets.depth_view,
+                    depth_ops: Some(wgpu::Operations {
+                        load: wgpu::LoadOp::Clear(1.0),
+                        store: false,
+                    }),
+                    stencil_ops: None,
+                }),
+            });
+
+            render_pass.set_pipeline(&self.pipeline);
+            render_pass.set_bind_group(0, uniform_bind_group, &[]);
+            render_pass.set_vertex_buffer(1, instance_buffer.slice(..));
+            for (mesh, bind_group) in model.meshes.iter().zip(self.mesh_bind_groups.iter()) {
+                render_pass.set_bind_group(1, bind_group, &[]);
+                render_pass.set_bind_group(2, &model.materials[mesh.material].bind_group, &[]);
+                render_pass.set_vertex_buffer(0, mesh.vertex_buffer.slice(..));
+                render_pass.set_index_buffer(mesh.index_buffer.slice(..), wgpu::IndexFormat::Uint32);
+                render_pass.draw_indexed(0..mesh.num_elements, 0, 0..instances);
+            }
+        }
+
+        encoder.copy_texture_to_buffer(
+            wgpu::ImageCopyTexture {
+                texture: &targets.id_texture,
+                mip_level: 0,
+                origin: wgpu::Origin3d { x, y, z: 0 },
+            },
+            wgpu::ImageCopyBuffer {
+                buffer: &self.readback_buffer,
+                layout: wgpu::ImageDataLayout {
+                    offset: 0,
+                    bytes_per_row: std::num::NonZeroU32::new(wgpu::COPY_BYTES_PER_ROW_ALIGNMENT),
+                    rows_per_image: None,
+                },
+            },
+            wgpu::Extent3d {
+                width: 1,
+                height: 1,
+                depth_or_array_layers: 1,
+            },
+        );
+        queue.submit(std::iter::once(encoder.finish()));
+
+        let slice = self.readback_buffer.slice(..);
+        let mapping = slice.map_async(wgpu::MapMode::Read);
+        device.poll(wgpu::Maintain::Wait);
+        if let Err(e) = block_on(mapping) {
+            log::error!("Could not read back the picking target: {}", e);
+            return None;
+        }
+        let id = {
+            let data = slice.get_mapped_range();
+            let id: &[u32] = bytemuck::cast_slice(&data[..ID_BYTES as usize]);
+            [id[0], id[1]]
+        };
+        self.readback_buffer.unmap();
+
+        Pick::from_id(id)
+    }
+}
@@ -0,0 +1,285 @@
+//! A small preprocessor for WGSL, supporting `#include "file"`, `#define NAME [value]`,
+//! `#undef NAME` and `#ifdef`/`#ifndef`/`#else`/`#endif` blocks.
+//!
+//! Defined values are substituted wherever their name appears as a whole identifier,
+//! except in `//` comments, which are passed through as written.
+//! Every file is only ever included once, so shared snippets need no include guards.
+//! This module is also compiled into `build.rs`, so it must only depend on `std` and `anyhow`.
+
+use std::collections::{BTreeMap, HashSet};
+
+use anyhow::*;
+
+#[derive(Debug, Clone, Default, PartialEq, Eq, Hash)]
+pub struct Defines(BTreeMap<String, String>);
+
+impl Defines {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Defines `name` as a flag, with no value.
+    pub fn with(mut self, name: &str) -> Self {
+        self.set(name, "");
+        self
+    }
+
+    pub fn set(&mut self, name: &str, value: &str) {
+        self.0.insert(name.to_string(), value.to_string());
+    }
+
+    pub fn contains(&self, name: &str) -> bool {
+        self.0.contains_key(name)
+    }
+}
+
+struct Conditional {
+    active: bool,
+    parent_active: bool,
+    seen_else: bool,
+}
+
+struct Preprocessor {
+    defines: Defines,
+    included: HashSet<String>,
+    output: String,
+}
+
+const MAX_INCLUDE_DEPTH: usize = 16;
+
+/// Expands `name`, reading it and anything it includes through `load`.
+pub fn preprocess<F>(name: &str, defines: &Defines, mut load: F) -> Result<String>
+where
+    F: FnMut(&str) -> Result<String>,
+{
+    let mut preprocessor = Preprocessor {
+        defines: defines.clone(),
+        included: HashSet::new(),
+        output: String::new(),
+    };
+    preprocessor.include(name, &mut load, 0)?;
+    Ok(preprocessor.output)
+}
+
+/// The names tested by `#ifdef`/`#ifndef` in `source`, i.e. the feature toggles it offers.
+#[allow(dead_code)] // Only used by build.rs
+pub fn conditionals(source: &str) -> Vec<String> {
+    let mut names = source
+        .lines()
+        .filter_map(|line| {
+            let mut parts = line.trim().splitn(2, char::is_whitespace);
+            match parts.next() {
+                Some("#ifdef") | Some("#ifndef") => parts.next().map(|n| n.trim().to_string()),
+                _ => None,
+            }
+        })
+        .collect::<Vec<_>>();
+    names.sort();
+    names.dedup();
+    names
+}
+
+impl Preprocessor {
+    fn include<F>(&mut self, name: &str, load: &mut F, depth: usize) -> Result<()>
+    where
+        F: FnMut(&str) -> Result<String>,
+    {
+        if depth > MAX_INCLUDE_DEPTH {
+            bail!("Includes nested too deeply at {}", name);
+        }
+        if !self.included.insert(name.to_string()) {
+            return Ok(());
+        }
+
+        let source = load(name)?;
+        let mut conditionals: Vec<Conditional> = Vec::new();
+
+        for (number, line) in source.lines().enumerate() {
+            let location = || format!("{}:{}", name, number + 1);
+            let active = conditionals.last().map_or(true, |c| c.active);
+            let trimmed = line.trim();
+
+            if !trimmed.starts_with('#') {
+                if active {
+                    self.output.push_str(&self.substitute(line));
+                    self.output.push('\n');
+                }
+                continue;
+            }
+
+            let mut parts = trimmed.splitn(2, char::is_whitespace);
+            let directive = parts.next().unwrap_or_default();
+            let argument = parts.next().map(str::trim).unwrap_or_default();
+
+            match directive {
+                "#ifdef" | "#ifndef" => {
+                    if argument.is_empty() {
+                        bail!("{}: {} needs a name", location(), directive);
+                    }
+                    let defined = self.defines.contains(argument);
+                    conditionals.push(Conditional {
+                        active: active && (defined == (directive == "#ifdef")),
+                        parent_active: active,
+                        seen_else: false,
+                    });
+                }
+                "#else" => {
+                    let conditional = conditionals
+                        .last_mut()
+                        .with_context(|| format!("{}: #else without #ifdef", location()))?;
+                    if conditional.seen_else {
+                        bail!("{}: duplicate #else", location());
+                    }
+                    conditional.seen_else = true;
+                    conditional.active = conditional.parent_active && !conditional.active;
+                }
+                "#endif" => {
+                    conditionals
+                        .pop()
+                        .with_context(|| format!("{}: #endif without #ifdef", location()))?;
+                }
+                _ if !active => {}
+                "#define" => {
+                    let mut parts = argument.splitn(2, char::is_whitespace);
+                    let define = parts.next().unwrap_or_default();
+                    if define.is_empty() {
+                        bail!("{}: #define needs a name", location());
+                    }
+                    let value = parts.next().map(str::trim).unwrap_or_default();
+                    self.defines.set(define, value);
+                }
+                "#undef" => {
+                    self.defines.0.remove(argument);
+                }
+                "#include" => {
+                    let path = argument
+                        .strip_prefix('"')
+                        .and_then(|a| a.strip_suffix('"'))
+                        .with_context(|| format!("{}: expected #include \"file\"", location()))?;
+                    self.include(path, load, depth + 1)
+                        .with_context(|| format!("Included from {}", location()))?;
+                }
+                _ => bail!("{}: unknown directive {}", location(), directive),
+            }
+        }
+
+        if !conditionals.is_empty() {
+            bail!("{}: missing #endif", name);
+        }
+
+        Ok(())
+    }
+
+    fn substitute(&self, line: &str) -> String {
+        let (code, comment) = line.split_at(line.find("//").unwrap_or(line.len()));
+        let mut result = String::with_capacity(line.len());
+        let mut identifier = String::new();
+
+        let flush = |identifier: &mut String, result: &mut String| {
+            match self.defines.0.get(identifier.as_str()) {
+                Some(value) if !value.is_empty() => result.push_str(value),
+                _ => result.push_str(identifier),
+            }
+            identifier.clear();
+        };
+
+        // Numbers, including suffixes like the u in 1u, pass straight through
+        let mut in_number = false;
+        for c in code.chars() {
+            let word = c == '_' || c.is_ascii_alphanumeric();
+            if word && (in_number || (identifier.is_empty() && c.is_ascii_digit())) {
+                in_number = true;
+                result.push(c);
+            } else if word {
+                identifier.push(c);
+            } else {
+                in_number = false;
+                flush(&mut identifier, &mut result);
+                result.push(c);
+            }
+        }
+        flush(&mut identifier, &mut result);
+        result.push_str(comment);
+
+        result
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Preprocesses `main` from a set of named sources.
+    fn run(files: &[(&str, &str)], defines: &Defines) -> Result<String> {
+        preprocess("main", defines, |name| {
+            files
+                .iter()
+                .find(|(file, _)| *file == name)
+                .map(|(_, source)| source.to_string())
+                .with_context(|| format!("No file {}", name))
+        })
+    }
+
+    #[test]
+    fn includes_each_file_once() {
+        let files = [
+            ("main", "#include \"a\"\n#include \"b\"\nmain\n"),
+            ("a", "#include \"b\"\na\n"),
+            ("b", "b\n"),
+        ];
+        assert_eq!(run(&files, &Defines::new()).unwrap(), "b\na\nmain\n");
+    }
+
+    #[test]
+    fn missing_include_fails() {
+        let files = [("main", "#include \"gone\"\n")];
+        assert!(run(&files, &Defines::new()).is_err());
+    }
+
+    #[test]
+    fn conditionals_follow_defines() {
+        let source = "#ifdef A\na\n#ifndef B\nnot b\n#else\nb\n#endif\n#else\nnot a\n#endif\n";
+        let files = [("main", source)];
+        assert_eq!(run(&files, &Defines::new()).unwrap(), "not a\n");
+        assert_eq!(run(&files, &Defines::new().with("A")).unwrap(), "a\nnot b\n");
+        assert_eq!(run(&files, &Defines::new().with("A").with("B")).unwrap(), "a\nb\n");
+    }
+
+    #[test]
+    fn unbalanced_conditionals_fail() {
+        let sources = ["#ifdef A\n", "#endif\n", "#else\n", "#ifdef A\n#else\n#else\n#endif\n"];
+        for source in sources.iter() {
+            assert!(run(&[("main", source)], &Defines::new()).is_err(), "{:?}", source);
+        }
+    }
+
+    #[test]
+    fn substitutes_whole_identifiers() {
+        let source = "#define SIZE 4\nlet a = SIZE + SIZE_2 + 1SIZE;\n#undef SIZE\nSIZE\n";
+        assert_eq!(
+            run(&[("main", source)], &Defines::new()).unwrap(),
+            "let a = 4 + SIZE_2 + 1SIZE;\nSIZE\n"
+        );
+    }
+
+    #[test]
+    fn flags_are_not_substituted() {
+        let files = [("main", "FLAG\n")];
+        assert_eq!(run(&files, &Defines::new().with("FLAG")).unwrap(), "FLAG\n");
+    }
+
+    #[test]
+    fn comments_are_not_substituted() {
+        let source = "#define SIZE 4\nSIZE // SIZE\n// #define SIZE 8\nSIZE\n";
+        assert_eq!(
+            run(&[("main", source)], &Defines::new()).unwrap(),
+            "4 // SIZE\n// #define SIZE 8\n4\n"
+        );
+    }
+
+    #[test]
+    fn finds_conditionals() {
+        let source = "#ifdef B\n#endif\n  #ifndef A\n#ifdef B\n#endif\n#endif\n";
+        assert_eq!(conditionals(source), ["A", "B"]);
+    }
+}
@@ -0,0 +1,162 @@
+//! Lightweight CPU profiling. While enabled, spans opened with [`profile_scope!`] are
+//! recorded from every thread and can be written out as Chrome trace event JSON, which
+//! chrome://tracing and Perfetto both open.
+
+use std::borrow::Cow;
+use std::fmt::Write as _;
+use std::path::Path;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::Mutex;
+use std::time::Instant;
+
+use anyhow::*;
+use once_cell::sync::Lazy;
+
+/// Stop recording past this many spans, rather than growing without bound.
+const MAX_EVENTS: usize = 1_000_000;
+
+static ENABLED: AtomicBool = AtomicBool::new(false);
+static DROPPING: AtomicBool = AtomicBool::new(false);
+static EPOCH: Lazy<Instant> = Lazy::new(Instant::now);
+static EVENTS: Lazy<Mutex<Vec<Event>>> = Lazy::new(|| Mutex::new(Vec::new()));
+static THREADS: Lazy<Mutex<Vec<(u64, String)>>> = Lazy::new(|| Mutex::new(Vec::new()));
+static NEXT_THREAD_ID: AtomicU64 = AtomicU64::new(1);
+
+thread_local! {
+    static THREAD_ID: u64 = {
+        let id = NEXT_THREAD_ID.fetch_add(1, Ordering::Relaxed);
+        let name = std::thread::current()
+            .name()
+            .map_or_else(|| format!("thread {}", id), str::to_owned);
+        THREADS.lock().unwrap().push((id, name));
+        id
+    };
+}
+
+struct Event {
+    name: &'static str,
+    detail: Option<String>,
+    thread: u64,
+    start_us: f64,
+    duration_us: f64,
+}
+
+/// Times a block until the end of the enclosing scope. Takes a static name, optionally
+/// followed by `format!` arguments for a detail string, which is only formatted while
+/// profiling is enabled.
+macro_rules! profile_scope {
+    ($name:expr) => {
+        let _profile_span = $crate::profiler::Span::new($name, || None);
+    };
+    ($name:expr, $($detail:tt)+) => {
+        let _profile_span = $crate::profiler::Span::new($name, || Some(format!($($detail)+)));
+    };
+}
+
+pub fn enable() {
+    Lazy::force(&EPOCH);
+    ENABLED.store(true, Ordering::SeqCst);
+}
+
+pub fn is_enabled() -> bool {
+    ENABLED.load(Ordering::Relaxed)
+}
+
+/// An open span, recorded when dropped.
+pub struct Span {
+    name: &'static str,
+    detail: Option<String>,
+    start: Option<Instant>,
+}
+
+impl Span {
+    pub fn new<F: FnOnce() -> Option<String>>(name: &'static str, detail: F) -> Self {
+        let enabled = is_enabled();
+        Self {
+            name,
+            detail: if enabled { detail() } else { None },
+            start: if enabled { Some(Instant::now()) } else { None },
+        }
+    }
+}
+
+impl Drop for Span {
+    fn drop(&mut self) {
+        let start = match self.start {
+            Some(start) => start,
+            None => return,
+        };
+        let end = Instant::now();
+        let event = Event {
+            name: self.name,
+            detail: self.detail.take(),
+            thread: THREAD_ID.with(|id| *id),
+            start_us: start.duration_since(*EPOCH).as_secs_f64() * 1e6,
+            duration_us: end.duration_since(start).as_secs_f64() * 1e6,
+        };
+
+        let mut events = EVENTS.lock().unwrap();
+        if events.len() < MAX_EVENTS {
+            events.push(event);
+        } else if !DROPPING.swap(true, Ordering::Relaxed) {
+            log::warn!("Recorded {} profiling spans, dropping the rest", MAX_EVENTS);
+        }
+    }
+}
+
+fn escape(s: &str) -> Cow<'_, str> {
+    if !s.chars().any(|c| c == '"' || c == '\\' || c.is_control()) {
+        return Cow::Borrowed(s);
+    }
+
+    let mut escaped = String::with_capacity(s.len() + 2);
+    for c in s.chars() {
+        match c {
+            '"' => escaped.push_str("\\\""),
+            '\\' => escaped.push_str("\\\\"),
+            '\n' => escaped.push_str("\\n"),
+            c if c.is_control() => {
+                let _ = write!(escaped, "\\u{:04x}", c as u32);
+            }
+            c => escaped.push(c),
+        }
+    }
+    Cow::Owned(escaped)
+}
+
+/// Writes every span recorded so far to `path` as Chrome trace event JSON.
+pub fn write_chrome_trace<P: AsRef<Path>>(path: P) -> Result<()> {
+    let path = path.as_ref();
+    let events = EVENTS.lock().unwrap();
+    let threads = THREADS.lock().unwrap();
+
+    let mut json = String::from("{\"displayTimeUnit\":\"ms\",\"traceEvents\":[\n");
+    let mut entries = Vec::with_capacity(events.len() + threads.len());
+    for (id, name) in threads.iter() {
+        entries.push(format!(
+            "{{\"name\":\"thread_name\",\"ph\":\"M\",\"pid\":1,\"tid\":{},\"args\":{{\"name\":\"{}\"}}}}",
+            id,
+            escape(name)
+        ));
+    }
+    for event in events.iter() {
+        let mut entry = format!(
+            "{{\"name\":\"{}\",\"cat\":\"cpu\",\"ph\":\"X\",\"pid\":1,\"tid\":{},\"ts\":{:.3},\"dur\":{:.3}",
+            escape(event.name),
+            event.thread,
+            event.start_us,
+            event.duration_us
+        );
+        if let Some(detail) = &event.detail {
+            let _ = write!(entry, ",\"args\":{{\"detail\":\"{}\"}}", escape(detail));
+        }
+        entry.push('}');
+        entries.push(entry);
+    }
+    json.push_str(&entries.join(",\n"));
+    json.push_str("\n]}\n");
+
+    std::fs::write(path, json).with_context(|| format!("Could not write trace to {:?}", path))?;
+    log::info!("Wrote {} profiling spans to {:?}", events.len(), path);
+    Ok(())
+}
@@ -0,0 +1,372 @@
+//! Ray casting against the instanced model on the CPU, for when a GPU readback isn't
+//! wanted. Instances are found through a BVH over their world bounds, then tested
+//! triangle by triangle in model space.
+
+use cgmath::{InnerSpace, Matrix, Matrix4, Point3, SquareMatrix, Transform, Vector3};
+
+use crate::bounds::Aabb;
+use crate::instance::Instance;
+use crate::model::Model;
+
+/// Instances per BVH leaf.
+const MAX_LEAF_SIZE: usize = 4;
+
+#[derive(Clone, Copy, Debug)]
+pub struct Ray {
+    pub origin: Point3<f32>,
+    pub direction: Vector3<f32>,
+}
+
+impl Ray {
+    pub fn at(&self, distance: f32) -> Point3<f32> {
+        self.origin + self.direction * distance
+    }
+
+    fn transformed(&self, transform: &Matrix4<f32>) -> Ray {
+        Ray {
+            origin: transform.transform_point(self.origin),
+            direction: transform.transform_vector(self.direction),
+        }
+    }
+
+    /// Distance along the ray to where it enters `aabb`, or 0 when it starts inside.
+    pub fn intersect_aabb(&self, aabb: &Aabb) -> Option<f32> {
+        let mut near = 0.0f32;
+        let mut far = f32::INFINITY;
+        for axis in 0..3 {
+            let inverse = 1.0 / self.direction[axis];
+            let mut t0 = (aabb.min[axis] - self.origin[axis]) * inverse;
+            let mut t1 = (aabb.max[axis] - self.origin[axis]) * inverse;
+            if inverse < 0.0 {
+                std::mem::swap(&mut t0, &mut t1);
+            }
+            near = near.max(t0);
+            far = far.min(t1);
+            if near > far {
+                return None;
+            }
+        }
+        Some(near)
+    }
+
+    /// Möller–Trumbore. Returns the distance along the ray, in units of its direction,
+    /// and the geometric normal facing back along the ray. Both sides of a triangle are hit.
+    pub fn intersect_triangle(&self, triangle: [Point3<f32>; 3]) -> Option<(f32, Vector3<f32>)> {
+        let edge1 = triangle[1] - triangle[0];
+        let edge2 = triangle[2] - triangle[0];
+        let p_vec = self.direction.cross(edge2);
+        let determinant = edge1.dot(p_vec);
+        if determinant.abs() < f32::EPSILON {
+            return None;
+        }
+
+        let inverse = 1.0 / determinant;
+        let s_vec = self.origin - triangle[0];
+        let u = s_vec.dot(p_vec) * inverse;
+        if !(0.0..=1.0).contains(&u) {
+            return None;
+        }
+        let q_vec = s_vec.cross(edge1);
+        let v = self.direction.dot(q_vec) * inverse;
+        if v < 0.0 || u + v > 1.0 {
+            return None;
+        }
+
+        let distance = edge2.dot(q_vec) * inverse;
+        if distance <= 0.0 {
+            return None;
+        }
+        let normal = edge1.cross(edge2).normalize();
+        let normal = if normal.dot(self.direction) > 0.0 {
+            -normal
+        } else {
+            normal
+        };
+        Some((distance, normal))
+    }
+}
+
+/// The closest surface a ray hit.
+#[derive(Clone, Copy, Debug)]
+pub struct Hit {
+    pub point: Point3<f32>,
+    pub normal: Vector3<f32>,
+    pub instance: u32,
+    pub mesh: u32,
+    pub distance: f32,
+}
+
+enum NodeKind {
+    Leaf { first: usize, count: usize },
+    Interior { left: usize, right: usize },
+}
+
+struct Node {
+    bounds: Aabb,
+    kind: NodeKind,
+}
+
+/// Bounding volume hierarchy over the world bounds of each instance.
+pub struct InstanceBvh {
+    nodes: Vec<Node>,
+    /// Instance indices, ordered so each leaf covers a contiguous run.
+    instances: Vec<u32>,
+}
+
+impl InstanceBvh {
+    pub fn build(model_bounds: &Aabb, instances: &[Instance]) -> Self {
+        profile_scope!("InstanceBvh::build", "{} instances", instances.len());
+        let bounds = instances
+            .iter()
+            .map(|instance| model_bounds.transformed(&instance.transform()))
+            .collect::<Vec<_>>();
+
+        let mut bvh = Self {
+            nodes: Vec::with_capacity(instances.len() * 2 / MAX_LEAF_SIZE + 1),
+            instances: (0..instances.len() as u32).collect(),
+        };
+        if !instances.is_empty() {
+            bvh.build_node(&bounds, 0, instances.len());
+        }
+        bvh
+    }
+
+    /// Builds the node covering `instances[first..first + count]`, returning its index.
+    fn build_node(&mut self, bounds: &[Aabb], first: usize, count: usize) -> usize {
+        let run = &mut self.instances[first..first + count];
+        let node_bounds = run
+            .iter()
+            .fold(Aabb::empty(), |node_bounds, &i| node_bounds.union(&bounds[i as usize]));
+
+        let index = self.nodes.len();
+        self.nodes.push(Node {
+            bounds: node_bounds,
+            kind: NodeKind::Leaf { first, count },
+        });
+        if count <= MAX_LEAF_SIZE {
+            return index;
+        }
+
+        // Split at the median centre along the axis the centres spread furthest on
+        let centres = Aabb::from_points(run.iter().map(|&i| bounds[i as usize].centre()));
+        let extent = centres.max - centres.min;
+        let axis = if extent.x >= extent.y && extent.x >= extent.z {
+            0
+        } else if extent.y >= extent.z {
+            1
+        } else {
+            2
+        };
+        let half = count / 2;
+        run.select_nth_unstable_by(half, |&a, &b| {
+            let a = bounds[a as usize].centre()[axis];
+            let b = bounds[b as usize].centre()[axis];
+            a.partial_cmp(&b).unwrap_or(std::cmp::Ordering::Equal)
+        });
+
+        let left = self.build_node(bounds, first, half);
+        let right = self.build_node(bounds, first + half, count - half);
+        self.nodes[index].kind = NodeKind::Interior { left, right };
+        index
+    }
+
+    /// Calls `hit` for every instance whose bounds the ray enters closer than the closest
+    /// hit so far. `hit` returns the distance it found the instance at, if it hit at all.
+    fn traverse<F: FnMut(u32, f32) -> Option<f32>>(&self, ray: &Ray, mut hit: F) {
+        if self.nodes.is_empty() {
+            return;
+        }
+
+        let mut closest = f32::INFINITY;
+        let mut stack = vec![0];
+        while let Some(node) = stack.pop() {
+            let node = &self.nodes[node];
+            match ray.intersect_aabb(&node.bounds) {
+                Some(distance) if distance < closest => {}
+                _ => continue,
+            }
+            match node.kind {
+                NodeKind::Leaf { first, count } => {
+                    for &instance in self.instances[first..first + count].iter() {
+                        if let Some(distance) = hit(instance, closest) {
+                            closest = closest.min(distance);
+                        }
+                    }
+                }
+                NodeKind::Interior { left, right } => {
+                    stack.push(right);
+                    stack.push(left);
+                }
+            }
+        }
+    }
+}
+
+/// Finds the closest triangle of any instance of `model` along `ray`. `bvh` must have been
+/// built from the same `instances`.
+pub fn raycast(ray: &Ray, model: &Model, instances: &[Instance], bvh: &InstanceBvh) -> Option<Hit> {
+    profile_scope!("raycast");
+    let ray = Ray {
+        origin: ray.origin,
+        direction: ray.direction.normalize(),
+    };
+    let mut closest: Option<Hit> = None;
+
+    bvh.traverse(&ray, |index, max_distance| {
+        let inverse = instances[index as usize].transform().invert()?;
+        let local_ray = ray.transformed(&inverse);
+        // Distances along the local ray are the same as along the world one, as the local
+        // direction is the world one carried through the inverse transform
+        match local_ray.intersect_aabb(&model.bounds) {
+            Some(distance) if distance < max_distance => {}
+            _ => return None,
+        }
+
+        let mut instance_hit = None;
+        for (mesh_index, mesh) in model.meshes.iter().enumerate() {
+            if local_ray.intersect_aabb(&mesh.bounds).is_none() {
+                continue;
+            }
+            for triangle in mesh.indices.chunks_exact(3) {
+                let triangle = [
+                    Point3::from(mesh.positions[triangle[0] as usize]),
+                    Point3::from(mesh.positions[triangle[1] as usize]),
+                    Point3::from(mesh.positions[triangle[2] as usize]),
+                ];
+                if let Some((distance, normal)) = local_ray.intersect_triangle(triangle) {
+                    let best = instance_hit.map_or(max_distance, |(best, _, _)| best);
+                    if distance < best {
+                        instance_hit = Some((distance, normal, mesh_index as u32));
+                    }
+                }
+            }
+        }
+
+        let (distance, normal, mesh) = instance_hit?;
+        // Normals carried through the inverse transpose stay perpendicular to the surface
+        closest = Some(Hit {
+            point: ray.at(distance),
+            normal: inverse.transpose().transform_vector(normal).normalize(),
+            instance: index,
+            mesh,
+            distance,
+        });
+        Some(distance)
+    });
+
+    closest
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use cgmath::{Quaternion, Vector3};
+
+    fn ray(origin: [f32; 3], direction: [f32; 3]) -> Ray {
+        Ray {
+            origin: origin.into(),
+            direction: direction.into(),
+        }
+    }
+
+    fn unit_box() -> Aabb {
+        Aabb {
+            min: Point3::new(-1.0, -1.0, -1.0),
+            max: Point3::new(1.0, 1.0, 1.0),
+        }
+    }
+
+    #[test]
+    fn aabb_hits_and_misses() {
+        let aabb = unit_box();
+        assert_eq!(ray([-5.0, 0.0, 0.0], [1.0, 0.0, 0.0]).intersect_aabb(&aabb), Some(4.0));
+        assert_eq!(ray([0.0, 5.0, 0.0], [0.0, -2.0, 0.0]).intersect_aabb(&aabb), Some(2.0));
+        assert_eq!(ray([0.0, 0.0, 0.0], [0.0, 0.0, 1.0]).intersect_aabb(&aabb), Some(0.0));
+        assert_eq!(ray([-5.0, 0.0, 0.0], [-1.0, 0.0, 0.0]).intersect_aabb(&aabb), None);
+        assert_eq!(ray([-5.0, 2.0, 0.0], [1.0, 0.0, 0.0]).intersect_aabb(&aabb), None);
+    }
+
+    #[test]
+    fn triangle_hits_either_side() {
+        let triangle = [
+            Point3::new(0.0, 0.0, 0.0),
+            Point3::new(1.0, 0.0, 0.0),
+            Point3::new(0.0, 1.0, 0.0),
+        ];
+        let (distance, normal) = ray([0.25, 0.25, 2.0], [0.0, 0.0, -1.0])
+            .intersect_triangle(triangle)
+            .unwrap();
+        assert!((distance - 2.0).abs() < 1e-6);
+        assert_eq!(normal, Vector3::unit_z());
+
+        let (distance, normal) = ray([0.25, 0.25, -3.0], [0.0, 0.0, 1.0])
+            .intersect_triangle(triangle)
+            .unwrap();
+        assert!((distance - 3.0).abs() < 1e-6);
+        assert_eq!(normal, -Vector3::unit_z());
+    }
+
+    #[test]
+    fn triangle_misses() {
+        let triangle = [
+            Point3::new(0.0, 0.0, 0.0),
+            Point3::new(1.0, 0.0, 0.0),
+            Point3::new(0.0, 1.0, 0.0),
+        ];
+        // Outside the edges, behind the origin and parallel to the plane
+        assert!(ray([0.75, 0.75, 1.0], [0.0, 0.0, -1.0]).intersect_triangle(triangle).is_none());
+        assert!(ray([0.25, 0.25, 1.0], [0.0, 0.0, 1.0]).intersect_triangle(triangle).is_none());
+        assert!(ray([0.25, 0.25, 1.0], [1.0, 0.0, 0.0]).intersect_triangle(triangle).is_none());
+    }
+
+    /// A row of instances along x, three units apart.
+    fn row(count: usize) -> Vec<Instance> {
+        (0..count)
+            .map(|i| Instance {
+                position: Vector3::new(i as f32 * 3.0, 0.0, 0.0),
+                rotation: Quaternion::new(1.0, 0.0, 0.0, 0.0),
+                highlighted: false,
+            })
+            .collect()
+    }
+
+    /// Every instance the BVH offers for `ray`, when none of them are hit.
+    fn candidates(bvh: &InstanceBvh, ray: &Ray) -> Vec<u32> {
+        let mut candidates = Vec::new();
+        bvh.traverse(ray, |instance, _| {
+            candidates.push(instance);
+            None
+        });
+        candidates.sort_unstable();
+        candidates
+    }
+
+    #[test]
+    fn bvh_finds_the_instances_along_a_ray() {
+        let bvh = InstanceBvh::build(&unit_box(), &row(20));
+        // Only whole leaves are culled, so neighbours of the one hit may come along
+        let down = candidates(&bvh, &ray([21.0, 5.0, 0.0], [0.0, -1.0, 0.0]));
+        assert!(down.contains(&7), "{:?}", down);
+        assert!(down.len() <= MAX_LEAF_SIZE, "{:?}", down);
+        assert!(candidates(&bvh, &ray([100.0, 5.0, 0.0], [0.0, -1.0, 0.0])).is_empty());
+        assert_eq!(candidates(&bvh, &ray([-5.0, 0.0, 0.0], [1.0, 0.0, 0.0])).len(), 20);
+    }
+
+    #[test]
+    fn bvh_skips_instances_behind_the_closest_hit() {
+        let bvh = InstanceBvh::build(&unit_box(), &row(20));
+        let mut visited = Vec::new();
+        bvh.traverse(&ray([-5.0, 0.0, 0.0], [1.0, 0.0, 0.0]), |instance, _| {
+            visited.push(instance);
+            Some(instance as f32 * 3.0 + 4.0)
+        });
+        assert!(visited.contains(&0));
+        assert!(visited.len() < 20, "{:?}", visited);
+    }
+
+    #[test]
+    fn empty_bvh() {
+        let bvh = InstanceBvh::build(&unit_box(), &[]);
+        assert!(candidates(&bvh, &ray([0.0, 0.0, 0.0], [1.0, 0.0, 0.0])).is_empty());
+    }
+}
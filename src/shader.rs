@@ -0,0 +1,252 @@
+use std::collections::HashMap;
+use std::path::PathBuf;
+use std::time::{Duration, Instant, SystemTime};
+
+use anyhow::*;
+
+use crate::preprocessor::{self, Defines};
+use crate::shader_interface::{Attribute, AttributeFormat, Binding, BindingType, SHADERS};
+use crate::shader_validation;
+
+/// Shaders compiled into the binary, used when not loading from disk.
+const EMBEDDED_SHADERS: &[(&str, &str)] = &[
+    ("shader.wgsl", include_str!("shaders/shader.wgsl")),
+    ("light.wgsl", include_str!("shaders/light.wgsl")),
+    ("skybox.wgsl", include_str!("shaders/skybox.wgsl")),
+    ("equirect.wgsl", include_str!("shaders/equirect.wgsl")),
+    ("irradiance.wgsl", include_str!("shaders/irradiance.wgsl")),
+    ("prefilter.wgsl", include_str!("shaders/prefilter.wgsl")),
+    ("downsample.wgsl", include_str!("shaders/downsample.wgsl")),
+    ("brdf_lut.wgsl", include_str!("shaders/brdf_lut.wgsl")),
+    ("hud.wgsl", include_str!("shaders/hud.wgsl")),
+    ("debug_line.wgsl", include_str!("shaders/debug_line.wgsl")),
+    ("picking.wgsl", include_str!("shaders/picking.wgsl")),
+    ("outline.wgsl", include_str!("shaders/outline.wgsl")),
+    ("wireframe.wgsl", include_str!("shaders/wireframe.wgsl")),
+    (
+        "include/uniforms.wgsl",
+        include_str!("shaders/include/uniforms.wgsl"),
+    ),
+    ("include/light.wgsl", include_str!("shaders/include/light.wgsl")),
+    (
+        "include/material.wgsl",
+        include_str!("shaders/include/material.wgsl"),
+    ),
+    (
+        "include/cube_face.wgsl",
+        include_str!("shaders/include/cube_face.wgsl"),
+    ),
+    (
+        "include/hammersley.wgsl",
+        include_str!("shaders/include/hammersley.wgsl"),
+    ),
+    (
+        "include/tonemap.wgsl",
+        include_str!("shaders/include/tonemap.wgsl"),
+    ),
+];
+
+/// Hands out WGSL sources, either the ones baked in at compile time or, in development
+/// mode, ones read from disk and watched for changes.
+pub struct ShaderLibrary {
+    source_dir: Option<PathBuf>,
+    /// When each file read from disk was last modified, or `None` if it couldn't be read.
+    modified: HashMap<PathBuf, Option<SystemTime>>,
+    last_poll: Instant,
+}
+
+impl ShaderLibrary {
+    const POLL_INTERVAL: Duration = Duration::from_millis(250);
+
+    pub fn embedded() -> Self {
+        Self {
+            source_dir: None,
+            modified: HashMap::new(),
+            last_poll: Instant::now(),
+        }
+    }
+
+    /// Loads shaders from `dir` and tracks their modification times.
+    pub fn watch<P: Into<PathBuf>>(dir: P) -> Self {
+        let dir = dir.into();
+        log::info!("Watching {:?} for shader changes", dir);
+        Self {
+            source_dir: Some(dir),
+            modified: HashMap::new(),
+            last_poll: Instant::now(),
+        }
+    }
+
+    /// Loads `name`, expanding includes and applying `defines` to pick the variant.
+    pub fn load<'a>(
+        &mut self,
+        name: &'a str,
+        defines: &Defines,
+    ) -> Result<wgpu::ShaderModuleDescriptor<'a>> {
+        let source_dir = &self.source_dir;
+        let modified = &mut self.modified;
+        let source = preprocessor::preprocess(name, defines, |file| match source_dir {
+            Some(dir) => {
+                let path = dir.join(file);
+                // Noted even when the read fails, so the file is only retried once it changes
+                let time = std::fs::metadata(&path).and_then(|m| m.modified()).ok();
+                modified.insert(path.clone(), time);
+                std::fs::read_to_string(&path)
+                    .with_context(|| format!("Could not read shader {:?}", path))
+            }
+            None => EMBEDDED_SHADERS
+                .iter()
+                .find(|(embedded, _)| *embedded == file)
+                .map(|(_, source)| source.to_string())
+                .with_context(|| format!("No embedded shader named {}", file)),
+        })?;
+
+        validate(name, &source)?;
+
+        Ok(wgpu::ShaderModuleDescriptor {
+            label: Some(name),
+            source: wgpu::ShaderSource::Wgsl(source.into()),
+            flags: wgpu::ShaderFlags::all(),
+        })
+    }
+
+    /// Returns true when any shader, or anything it included, has changed on disk since
+    /// it was read, or has appeared since it couldn't be.
+    pub fn poll_changes(&mut self) -> bool {
+        if self.source_dir.is_none() || self.last_poll.elapsed() < Self::POLL_INTERVAL {
+            return false;
+        }
+        self.last_poll = Instant::now();
+
+        self.modified.iter().any(|(path, loaded)| {
+            let modified = std::fs::metadata(path).and_then(|m| m.modified()).ok();
+            match (modified, loaded) {
+                (Some(modified), Some(loaded)) => modified > *loaded,
+                (Some(_), None) => true,
+                (None, _) => false,
+            }
+        })
+    }
+}
+
+/// Parses and validates WGSL up front, and checks its bindings and vertex inputs against
+/// the layouts built from `shader_interface`, so a broken shader is an error rather than
+/// a panic inside wgpu.
+pub fn validate(name: &str, source: &str) -> Result<()> {
+    let interface = SHADERS
+        .iter()
+        .find(|interface| interface.name == name)
+        .with_context(|| format!("{} has no entry in shader_interface", name))?;
+    shader_validation::validate(source, interface).with_context(|| format!("{} is invalid", name))
+}
+
+/// Builds wgpu vertex attributes from a `shader_interface` table, packed in table order.
+pub const fn vertex_attributes<const N: usize>(table: &[Attribute]) -> [wgpu::VertexAttribute; N] {
+    let mut attributes = [wgpu::VertexAttribute {
+        format: wgpu::VertexFormat::Float32,
+        offset: 0,
+        shader_location: 0,
+    }; N];
+
+    let mut offset = 0;
+    let mut i = 0;
+    while i < N {
+        let attribute = table[i];
+        attributes[i] = wgpu::VertexAttribute {
+            format: match attribute.format {
+                AttributeFormat::Float32x2 => wgpu::VertexFormat::Float32x2,
+                AttributeFormat::Float32x3 => wgpu::VertexFormat::Float32x3,
+                AttributeFormat::Float32x4 => wgpu::VertexFormat::Float32x4,
+            },
+            offset,
+            shader_location: attribute.location,
+        };
+        offset += attribute.format.size();
+        i += 1;
+    }
+
+    attributes
+}
+
+/// Creates a bind group layout from a `shader_interface` table.
+pub fn create_bind_group_layout(
+    device: &wgpu::Device,
+    label: &str,
+    table: &[Binding],
+) -> wgpu::BindGroupLayout {
+    let texture = |view_dimension, filterable| wgpu::BindingType::Texture {
+        sample_type: wgpu::TextureSampleType::Float { filterable },
+        view_dimension,
+        multisampled: false,
+    };
+
+    let entries = table
+        .iter()
+        .map(|binding| wgpu::BindGroupLayoutEntry {
+            binding: binding.binding,
+            visibility: match binding.ty {
+                BindingType::UniformBuffer => wgpu::ShaderStage::VERTEX | wgpu::ShaderStage::FRAGMENT,
+                _ => wgpu::ShaderStage::FRAGMENT,
+            },
+            ty: match binding.ty {
+                BindingType::UniformBuffer => wgpu::BindingType::Buffer {
+                    ty: wgpu::BufferBindingType::Uniform,
+                    has_dynamic_offset: false,
+                    min_binding_size: None,
+                },
+                BindingType::Texture2d => texture(wgpu::TextureViewDimension::D2, true),
+                BindingType::UnfilterableTexture2d => {
+                    texture(wgpu::TextureViewDimension::D2, false)
+                }
+                BindingType::TextureCube => texture(wgpu::TextureViewDimension::Cube, true),
+                BindingType::Sampler => wgpu::BindingType::Sampler {
+                    filtering: true,
+                    comparison: false,
+                },
+            },
+            count: None,
+        })
+        .collect::<Vec<_>>();
+
+    device.create_bind_group_layout(&wgpu::BindGroupLayoutDescriptor {
+        label: Some(label),
+        entries: &entries,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn embedded_shaders_load() {
+        let mut library = ShaderLibrary::embedded();
+        for shader in SHADERS.iter() {
+            if let Err(e) = library.load(shader.name, &Defines::new()) {
+                panic!("{}: {:#}", shader.name, e);
+            }
+        }
+    }
+
+    #[test]
+    fn rejects_bindings_missing_from_the_layouts() {
+        let source = preprocessor::preprocess("light.wgsl", &Defines::new(), |file| {
+            Ok(EMBEDDED_SHADERS.iter().find(|(name, _)| *name == file).unwrap().1.to_string())
+        })
+        .unwrap();
+        validate("light.wgsl", &source).unwrap();
+
+        let extra = format!("{}\n[[group(3), binding(0)]]\nvar s_extra: sampler;\n", source);
+        let error = format!("{:#}", validate("light.wgsl", &extra).unwrap_err());
+        assert!(error.contains("not in the Rust bind group layouts"), "{}", error);
+    }
+
+    #[test]
+    fn view_modes_are_checked_at_build_time() {
+        for mode in crate::view_mode::ViewMode::ALL.iter() {
+            if let Some(define) = mode.define() {
+                assert!(crate::shader_interface::VIEW_MODE_DEFINES.contains(&define), "{}", define);
+            }
+        }
+    }
+}
@@ -0,0 +1,244 @@
+//! What each shader expects from the Rust side: the vertex buffers it reads and the
+//! layout of each of its bind groups.
+//!
+//! The renderer builds its vertex and bind group layouts from these tables, and every
+//! shader is checked against them, by build.rs and again whenever one is loaded, so the
+//! two can't drift apart silently. This module is also compiled into `build.rs`, so it
+//! must not depend on anything outside `std`.
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AttributeFormat {
+    Float32x2,
+    Float32x3,
+    Float32x4,
+}
+
+impl AttributeFormat {
+    pub const fn size(self) -> u64 {
+        match self {
+            AttributeFormat::Float32x2 => 8,
+            AttributeFormat::Float32x3 => 12,
+            AttributeFormat::Float32x4 => 16,
+        }
+    }
+}
+
+/// A vertex attribute. Attributes in a buffer are tightly packed, in table order.
+#[derive(Debug, Clone, Copy)]
+pub struct Attribute {
+    pub location: u32,
+    pub format: AttributeFormat,
+}
+
+const fn attribute(location: u32, format: AttributeFormat) -> Attribute {
+    Attribute { location, format }
+}
+
+/// Size of one vertex of a buffer laid out as `table`. Each vertex struct checks its size
+/// against this at compile time, as an array length that fails to type check otherwise.
+#[allow(dead_code)] // Not used by build.rs
+pub const fn stride(table: &[Attribute]) -> usize {
+    let mut stride = 0;
+    let mut i = 0;
+    while i < table.len() {
+        stride += table[i].format.size() as usize;
+        i += 1;
+    }
+    stride
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BindingType {
+    UniformBuffer,
+    Texture2d,
+    UnfilterableTexture2d,
+    TextureCube,
+    Sampler,
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct Binding {
+    pub binding: u32,
+    pub ty: BindingType,
+}
+
+const fn binding(binding: u32, ty: BindingType) -> Binding {
+    Binding { binding, ty }
+}
+
+pub struct ShaderInterface {
+    pub name: &'static str,
+    pub vertex_buffers: &'static [&'static [Attribute]],
+    pub bind_groups: &'static [&'static [Binding]],
+    /// What the renderer may define when loading the shader, in groups of which at most
+    /// one is defined at a time. build.rs validates every combination.
+    pub defines: &'static [&'static [&'static str]],
+}
+
+use AttributeFormat::*;
+use BindingType::*;
+
+pub const MODEL_VERTEX: &[Attribute] = &[
+    attribute(0, Float32x3),
+    attribute(1, Float32x2),
+    attribute(2, Float32x3),
+    attribute(3, Float32x3),
+    attribute(4, Float32x3),
+];
+
+pub const INSTANCE_RAW: &[Attribute] = &[
+    attribute(5, Float32x4),
+    attribute(6, Float32x4),
+    attribute(7, Float32x4),
+    attribute(8, Float32x4),
+    attribute(9, Float32x3),
+    attribute(10, Float32x3),
+    attribute(11, Float32x3),
+];
+
+pub const HUD_GLYPH: &[Attribute] = &[
+    attribute(0, Float32x4),
+    attribute(1, Float32x4),
+    attribute(2, Float32x4),
+];
+
+pub const DEBUG_LINE_VERTEX: &[Attribute] = &[attribute(0, Float32x3), attribute(1, Float32x4)];
+
+pub const WIREFRAME_VERTEX: &[Attribute] = &[attribute(0, Float32x3), attribute(1, Float32x3)];
+
+pub const TEXTURE_GROUP: &[Binding] = &[
+    binding(0, Texture2d),
+    binding(1, Sampler),
+    binding(2, Texture2d),
+    binding(3, Sampler),
+    binding(4, Texture2d),
+    binding(5, Sampler),
+    binding(6, UniformBuffer),
+];
+
+pub const UNIFORM_GROUP: &[Binding] = &[binding(0, UniformBuffer)];
+
+pub const LIGHT_GROUP: &[Binding] = &[binding(0, UniformBuffer)];
+
+pub const ENVIRONMENT_GROUP: &[Binding] = &[
+    binding(0, TextureCube),
+    binding(1, TextureCube),
+    binding(2, Texture2d),
+    binding(3, Sampler),
+];
+
+pub const SKYBOX_GROUP: &[Binding] = &[binding(0, TextureCube), binding(1, Sampler)];
+
+pub const EQUIRECT_GROUP: &[Binding] = &[
+    binding(0, UnfilterableTexture2d),
+    binding(1, UniformBuffer),
+];
+
+pub const CUBE_FACE_GROUP: &[Binding] = &[
+    binding(0, TextureCube),
+    binding(1, UniformBuffer),
+    binding(2, Sampler),
+];
+
+pub const HUD_GROUP: &[Binding] = &[
+    binding(0, UniformBuffer),
+    binding(1, Texture2d),
+    binding(2, Sampler),
+];
+
+pub const PICK_GROUP: &[Binding] = &[binding(0, UniformBuffer)];
+
+pub const OUTLINE_GROUP: &[Binding] = &[binding(0, UniformBuffer)];
+
+/// The model shader's view modes, besides the shaded one.
+pub const VIEW_MODE_DEFINES: &[&str] = &[
+    "VIEW_WIREFRAME",
+    "VIEW_NORMALS",
+    "VIEW_TANGENTS",
+    "VIEW_BITANGENTS",
+    "VIEW_UV_CHECKER",
+    "VIEW_NORMAL_MAP",
+    "VIEW_DEPTH",
+    "VIEW_LIGHTING",
+];
+
+pub const SHADERS: &[ShaderInterface] = &[
+    ShaderInterface {
+        name: "shader.wgsl",
+        vertex_buffers: &[MODEL_VERTEX, INSTANCE_RAW],
+        bind_groups: &[TEXTURE_GROUP, UNIFORM_GROUP, LIGHT_GROUP, ENVIRONMENT_GROUP],
+        defines: &[&["NORMAL_MAPPING"], VIEW_MODE_DEFINES],
+    },
+    ShaderInterface {
+        name: "light.wgsl",
+        vertex_buffers: &[MODEL_VERTEX],
+        bind_groups: &[UNIFORM_GROUP, LIGHT_GROUP],
+        defines: &[],
+    },
+    ShaderInterface {
+        name: "skybox.wgsl",
+        vertex_buffers: &[],
+        bind_groups: &[UNIFORM_GROUP, SKYBOX_GROUP],
+        defines: &[&["HDR"]],
+    },
+    ShaderInterface {
+        name: "equirect.wgsl",
+        vertex_buffers: &[],
+        bind_groups: &[EQUIRECT_GROUP],
+        defines: &[],
+    },
+    ShaderInterface {
+        name: "irradiance.wgsl",
+        vertex_buffers: &[],
+        bind_groups: &[CUBE_FACE_GROUP],
+        defines: &[],
+    },
+    ShaderInterface {
+        name: "prefilter.wgsl",
+        vertex_buffers: &[],
+        bind_groups: &[CUBE_FACE_GROUP],
+        defines: &[],
+    },
+    ShaderInterface {
+        name: "downsample.wgsl",
+        vertex_buffers: &[],
+        bind_groups: &[CUBE_FACE_GROUP],
+        defines: &[],
+    },
+    ShaderInterface {
+        name: "brdf_lut.wgsl",
+        vertex_buffers: &[],
+        bind_groups: &[],
+        defines: &[],
+    },
+    ShaderInterface {
+        name: "hud.wgsl",
+        vertex_buffers: &[HUD_GLYPH],
+        bind_groups: &[HUD_GROUP],
+        defines: &[],
+    },
+    ShaderInterface {
+        name: "debug_line.wgsl",
+        vertex_buffers: &[DEBUG_LINE_VERTEX],
+        bind_groups: &[UNIFORM_GROUP],
+        defines: &[],
+    },
+    ShaderInterface {
+        name: "picking.wgsl",
+        vertex_buffers: &[MODEL_VERTEX, INSTANCE_RAW],
+        bind_groups: &[UNIFORM_GROUP, PICK_GROUP, TEXTURE_GROUP],
+        defines: &[],
+    },
+    ShaderInterface {
+        name: "outline.wgsl",
+        vertex_buffers: &[MODEL_VERTEX, INSTANCE_RAW],
+        bind_groups: &[UNIFORM_GROUP, OUTLINE_GROUP, TEXTURE_GROUP],
+        defines: &[],
+    },
+    ShaderInterface {
+        name: "wireframe.wgsl",
+        vertex_buffers: &[WIREFRAME_VERTEX, INSTANCE_RAW],
+        bind_groups: &[UNIFORM_GROUP],
+        defines: &[],
+    },
+];
@@ -0,0 +1,127 @@
+//! Validates expanded WGSL with naga, then checks what it declares against its
+//! `shader_interface` entry. The build script runs this over every shader variant, and the
+//! renderer over every shader it loads, so one edited while running is caught before a
+//! pipeline is built from it. Only depends on `std`, `anyhow`, `naga` and
+//! `shader_interface`, as `build.rs` compiles it too.
+
+use anyhow::*;
+
+use crate::shader_interface::{AttributeFormat, BindingType, ShaderInterface};
+
+/// Fails when `source` doesn't validate, or uses a binding or vertex input `interface`
+/// doesn't provide.
+pub fn validate(source: &str, interface: &ShaderInterface) -> Result<()> {
+    let module = naga::front::wgsl::parse_str(source)
+        .map_err(|e| anyhow!("{}", e.emit_to_string(source)))?;
+    naga::valid::Validator::new(
+        naga::valid::ValidationFlags::all(),
+        naga::valid::Capabilities::empty(),
+    )
+    .validate(&module)?;
+
+    for stage in [naga::ShaderStage::Vertex, naga::ShaderStage::Fragment].iter() {
+        if !module.entry_points.iter().any(|ep| ep.stage == *stage && ep.name == "main") {
+            bail!("No {:?} entry point named main", stage);
+        }
+    }
+
+    // Every resource the shader declares has to be in the matching bind group layout
+    for (_, global) in module.global_variables.iter() {
+        let binding = match &global.binding {
+            Some(binding) => binding,
+            None => continue,
+        };
+        let name = global.name.as_deref().unwrap_or("<unnamed>");
+
+        let expected = interface
+            .bind_groups
+            .get(binding.group as usize)
+            .and_then(|group| group.iter().find(|b| b.binding == binding.binding))
+            .with_context(|| {
+                format!(
+                    "{} at group {} binding {} is not in the Rust bind group layouts",
+                    name, binding.group, binding.binding
+                )
+            })?;
+
+        let inner = &module.types[global.ty].inner;
+        let float_image = |dimension| match inner {
+            naga::TypeInner::Image {
+                dim,
+                arrayed: false,
+                class: naga::ImageClass::Sampled { kind: naga::ScalarKind::Float, multi: false },
+            } => *dim == dimension,
+            _ => false,
+        };
+        let matches = match expected.ty {
+            BindingType::UniformBuffer => {
+                matches!(inner, naga::TypeInner::Struct { .. })
+                    && global.class == naga::StorageClass::Uniform
+            }
+            BindingType::Texture2d | BindingType::UnfilterableTexture2d => {
+                float_image(naga::ImageDimension::D2)
+            }
+            BindingType::TextureCube => float_image(naga::ImageDimension::Cube),
+            BindingType::Sampler => matches!(inner, naga::TypeInner::Sampler { comparison: false }),
+        };
+        if !matches {
+            bail!(
+                "{} at group {} binding {} does not match the Rust side, which expects {:?}",
+                name, binding.group, binding.binding, expected.ty
+            );
+        }
+    }
+
+    // And every vertex input has to come from one of the vertex buffers
+    let vertex = module
+        .entry_points
+        .iter()
+        .find(|ep| ep.stage == naga::ShaderStage::Vertex)
+        .context("No vertex entry point")?;
+    let mut inputs = Vec::new();
+    for argument in &vertex.function.arguments {
+        match &module.types[argument.ty].inner {
+            naga::TypeInner::Struct { members, .. } => {
+                inputs.extend(members.iter().map(|m| (m.name.clone(), m.ty, m.binding.clone())))
+            }
+            _ => inputs.push((argument.name.clone(), argument.ty, argument.binding.clone())),
+        }
+    }
+
+    for (name, ty, binding) in inputs {
+        let location = match binding {
+            Some(naga::Binding::Location { location, .. }) => location,
+            _ => continue,
+        };
+        let name = name.as_deref().unwrap_or("<unnamed>");
+
+        let attribute = interface
+            .vertex_buffers
+            .iter()
+            .flat_map(|buffer| buffer.iter())
+            .find(|attribute| attribute.location == location)
+            .with_context(|| {
+                format!("Vertex input {} at location {} is not in any vertex buffer", name, location)
+            })?;
+
+        let size = match &module.types[ty].inner {
+            naga::TypeInner::Vector { size, kind: naga::ScalarKind::Float, width: 4 } => {
+                Some(*size as u8)
+            }
+            _ => None,
+        };
+        let expected = match attribute.format {
+            AttributeFormat::Float32x2 => 2,
+            AttributeFormat::Float32x3 => 3,
+            AttributeFormat::Float32x4 => 4,
+        };
+        if size != Some(expected) {
+            bail!(
+                "Vertex input {} at location {} does not match the Rust side, which provides {:?}",
+                name, location, attribute.format
+            );
+        }
+    }
+
+    Ok(())
+}
@@ -0,0 +1,63 @@
+// Integrates the split sum specular BRDF into a lookup texture, indexed by (n dot v, roughness).
+
+#include "include/hammersley.wgsl"
+
+struct VertexOutput {
+    [[builtin(position)]] clip_position: vec4<f32>;
+    [[location(0)]] tex_coords: vec2<f32>;
+};
+
+[[stage(vertex)]]
+fn main([[builtin(vertex_index)]] vertex_index: u32) -> VertexOutput {
+    let x = f32((vertex_index << 1u) & 2u) * 2.0 - 1.0;
+    let y = f32(vertex_index & 2u) * 2.0 - 1.0;
+
+    var out: VertexOutput;
+    out.clip_position = vec4<f32>(x, y, 0.0, 1.0);
+    out.tex_coords = vec2<f32>(x * 0.5 + 0.5, 0.5 - y * 0.5);
+    return out;
+}
+
+fn geometry_schlick_ggx(n_dot_v: f32, roughness: f32) -> f32 {
+    // k is remapped for image based lighting
+    let k = (roughness * roughness) / 2.0;
+    return n_dot_v / (n_dot_v * (1.0 - k) + k);
+}
+
+[[stage(fragment)]]
+fn main(in: VertexOutput) -> [[location(0)]] vec4<f32> {
+    let pi = 3.14159265359;
+    let n_dot_v = max(in.tex_coords.x, 0.001);
+    let roughness = in.tex_coords.y;
+    let a = roughness * roughness;
+
+    let view = vec3<f32>(sqrt(1.0 - n_dot_v * n_dot_v), 0.0, n_dot_v);
+
+    let sample_count = 512u;
+    var scale: f32 = 0.0;
+    var bias: f32 = 0.0;
+    for (var i: u32 = 0u; i < sample_count; i = i + 1u) {
+        let xi = hammersley(i, sample_count);
+
+        // GGX importance sample around +Z
+        let phi = 2.0 * pi * xi.x;
+        let cos_theta = sqrt((1.0 - xi.y) / (1.0 + (a * a - 1.0) * xi.y));
+        let sin_theta = sqrt(1.0 - cos_theta * cos_theta);
+        let h = vec3<f32>(cos(phi) * sin_theta, sin(phi) * sin_theta, cos_theta);
+        let l = normalize(2.0 * dot(view, h) * h - view);
+
+        let n_dot_l = max(l.z, 0.0);
+        let n_dot_h = max(h.z, 0.0);
+        let v_dot_h = max(dot(view, h), 0.0);
+
+        if (n_dot_l > 0.0) {
+            let g = geometry_schlick_ggx(n_dot_v, roughness) * geometry_schlick_ggx(n_dot_l, roughness);
+            let g_vis = (g * v_dot_h) / max(n_dot_h * n_dot_v, 0.0001);
+            let fc = pow(1.0 - v_dot_h, 5.0);
+            scale = scale + (1.0 - fc) * g_vis;
+            bias = bias + fc * g_vis;
+        }
+    }
+
+    return vec4<f32>(scale / f32(sample_count), bias / f32(sample_count), 0.0, 1.0);
+}
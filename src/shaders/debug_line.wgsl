@@ -0,0 +1,24 @@
+#include "include/uniforms.wgsl"
+
+struct VertexInput {
+    [[location(0)]] position: vec3<f32>;
+    [[location(1)]] colour: vec4<f32>;
+};
+
+struct VertexOutput {
+    [[builtin(position)]] clip_position: vec4<f32>;
+    [[location(0)]] colour: vec4<f32>;
+};
+
+[[stage(vertex)]]
+fn main(in: VertexInput) -> VertexOutput {
+    var out: VertexOutput;
+    out.clip_position = uniforms.view_proj * vec4<f32>(in.position, 1.0);
+    out.colour = in.colour;
+    return out;
+}
+
+[[stage(fragment)]]
+fn main(in: VertexOutput) -> [[location(0)]] vec4<f32> {
+    return in.colour;
+}
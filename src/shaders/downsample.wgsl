@@ -0,0 +1,16 @@
+// Copies a cube map into one face of another at the same or half the size. Drawing each
+// mip level from a view of just the level above builds a mip chain, as linear filtering
+// averages the four texels under each one written.
+
+#include "include/cube_face.wgsl"
+
+[[group(0), binding(0)]]
+var t_source: texture_cube<f32>;
+[[group(0), binding(2)]]
+var s_source: sampler;
+
+[[stage(fragment)]]
+fn main(in: VertexOutput) -> [[location(0)]] vec4<f32> {
+    let dir = normalize(face_direction(face.index, in.face_coords));
+    return vec4<f32>(textureSampleLevel(t_source, s_source, dir, 0.0).rgb, 1.0);
+}
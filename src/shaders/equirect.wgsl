@@ -0,0 +1,22 @@
+// Projects an equirectangular HDR image onto one face of a cube map.
+
+#include "include/cube_face.wgsl"
+
+[[group(0), binding(0)]]
+var t_equirect: texture_2d<f32>;
+
+[[stage(fragment)]]
+fn main(in: VertexOutput) -> [[location(0)]] vec4<f32> {
+    let dir = normalize(face_direction(face.index, in.face_coords));
+
+    let pi = 3.14159265359;
+    let u = atan2(dir.z, dir.x) / (2.0 * pi) + 0.5;
+    let v = 0.5 - asin(dir.y) / pi;
+
+    let size = textureDimensions(t_equirect);
+    let texel = vec2<i32>(
+        clamp(i32(u * f32(size.x)), 0, size.x - 1),
+        clamp(i32(v * f32(size.y)), 0, size.y - 1),
+    );
+    return vec4<f32>(textureLoad(t_equirect, texel, 0).rgb, 1.0);
+}
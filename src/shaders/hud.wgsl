@@ -0,0 +1,47 @@
+[[block]]
+struct Screen {
+    size: vec2<f32>;
+};
+[[group(0), binding(0)]]
+var<uniform> screen: Screen;
+[[group(0), binding(1)]]
+var t_font: texture_2d<f32>;
+[[group(0), binding(2)]]
+var s_font: sampler;
+
+struct GlyphInput {
+    // x, y, width and height in pixels from the top left of the window
+    [[location(0)]] rect: vec4<f32>;
+    [[location(1)]] uv_rect: vec4<f32>;
+    [[location(2)]] colour: vec4<f32>;
+};
+
+struct VertexOutput {
+    [[builtin(position)]] clip_position: vec4<f32>;
+    [[location(0)]] uv: vec2<f32>;
+    [[location(1)]] colour: vec4<f32>;
+};
+
+[[stage(vertex)]]
+fn main([[builtin(vertex_index)]] vertex_index: u32, glyph: GlyphInput) -> VertexOutput {
+    // Quad corners as a 4 vertex triangle strip
+    let corner = vec2<f32>(f32(vertex_index & 1u), f32(vertex_index >> 1u));
+    let pixel = glyph.rect.xy + corner * glyph.rect.zw;
+
+    var out: VertexOutput;
+    out.clip_position = vec4<f32>(
+        pixel.x / screen.size.x * 2.0 - 1.0,
+        1.0 - pixel.y / screen.size.y * 2.0,
+        0.0,
+        1.0,
+    );
+    out.uv = glyph.uv_rect.xy + corner * glyph.uv_rect.zw;
+    out.colour = glyph.colour;
+    return out;
+}
+
+[[stage(fragment)]]
+fn main(in: VertexOutput) -> [[location(0)]] vec4<f32> {
+    let coverage = textureSample(t_font, s_font, in.uv).r;
+    return vec4<f32>(in.colour.rgb, in.colour.a * coverage);
+}
@@ -0,0 +1,47 @@
+// Shared vertex stage for shaders that render a full screen triangle into one face of a
+// cube map. The face being rendered comes from a uniform at group 0, binding 1.
+
+[[block]]
+struct Face {
+    index: u32;
+    roughness: f32;
+};
+[[group(0), binding(1)]]
+var<uniform> face: Face;
+
+struct VertexOutput {
+    [[builtin(position)]] clip_position: vec4<f32>;
+    [[location(0)]] face_coords: vec2<f32>;
+};
+
+[[stage(vertex)]]
+fn main([[builtin(vertex_index)]] vertex_index: u32) -> VertexOutput {
+    // A single triangle covering the whole target
+    let x = f32((vertex_index << 1u) & 2u) * 2.0 - 1.0;
+    let y = f32(vertex_index & 2u) * 2.0 - 1.0;
+
+    var out: VertexOutput;
+    out.clip_position = vec4<f32>(x, y, 0.0, 1.0);
+    out.face_coords = vec2<f32>(x, -y);
+    return out;
+}
+
+// Direction through a point on a face, ordered +X, -X, +Y, -Y, +Z, -Z
+fn face_direction(index: u32, uv: vec2<f32>) -> vec3<f32> {
+    if (index == 0u) {
+        return vec3<f32>(1.0, -uv.y, -uv.x);
+    }
+    if (index == 1u) {
+        return vec3<f32>(-1.0, -uv.y, uv.x);
+    }
+    if (index == 2u) {
+        return vec3<f32>(uv.x, 1.0, uv.y);
+    }
+    if (index == 3u) {
+        return vec3<f32>(uv.x, -1.0, -uv.y);
+    }
+    if (index == 4u) {
+        return vec3<f32>(uv.x, -uv.y, 1.0);
+    }
+    return vec3<f32>(-uv.x, -uv.y, -1.0);
+}
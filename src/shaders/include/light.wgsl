@@ -0,0 +1,13 @@
+// The scene light, bound at LIGHT_GROUP.
+
+#ifndef LIGHT_GROUP
+#define LIGHT_GROUP 1
+#endif
+
+[[block]]
+struct Light {
+    position: vec3<f32>;
+    colour: vec3<f32>;
+};
+[[group(LIGHT_GROUP), binding(0)]]
+var<uniform> light: Light;
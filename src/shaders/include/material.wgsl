@@ -0,0 +1,39 @@
+// A mesh's material, bound at MATERIAL_GROUP: its maps and the scalar properties from the
+// MTL file.
+
+#ifndef MATERIAL_GROUP
+#define MATERIAL_GROUP 0
+#endif
+
+[[group(MATERIAL_GROUP), binding(0)]]
+var t_diffuse: texture_2d<f32>;
+[[group(MATERIAL_GROUP), binding(1)]]
+var s_diffuse: sampler;
+
+[[group(MATERIAL_GROUP), binding(2)]]
+var t_normal: texture_2d<f32>;
+[[group(MATERIAL_GROUP), binding(3)]]
+var s_normal: sampler;
+
+[[group(MATERIAL_GROUP), binding(4)]]
+var t_specular: texture_2d<f32>;
+[[group(MATERIAL_GROUP), binding(5)]]
+var s_specular: sampler;
+
+// Ambient is unused, as the environment lights everything instead
+[[block]]
+struct Material {
+    ambient: vec4<f32>;
+    diffuse: vec4<f32>;
+    specular: vec4<f32>;
+    shininess: f32;
+    dissolve: f32;
+    alpha_cutoff: f32;
+};
+[[group(MATERIAL_GROUP), binding(6)]]
+var<uniform> material: Material;
+
+// Whether the material cuts out the surface at these coordinates
+fn alpha_tested(tex_coords: vec2<f32>) -> bool {
+    return textureSample(t_diffuse, s_diffuse, tex_coords).a * material.dissolve < material.alpha_cutoff;
+}
@@ -0,0 +1,6 @@
+// Maps HDR colours into the displayable range, shared so an HDR sky and the scene agree.
+
+// Simple Reinhard, per channel
+fn tonemap(colour: vec3<f32>) -> vec3<f32> {
+    return colour / (colour + vec3<f32>(1.0));
+}
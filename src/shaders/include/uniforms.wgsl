@@ -0,0 +1,13 @@
+// Camera uniforms, bound at UNIFORMS_GROUP.
+
+#ifndef UNIFORMS_GROUP
+#define UNIFORMS_GROUP 0
+#endif
+
+[[block]]
+struct Uniforms {
+    view_pos: vec4<f32>;
+    view_proj: mat4x4<f32>;
+};
+[[group(UNIFORMS_GROUP), binding(0)]]
+var<uniform> uniforms: Uniforms;
@@ -0,0 +1,36 @@
+// Convolves an environment cube map into a diffuse irradiance cube map.
+
+#include "include/cube_face.wgsl"
+
+[[group(0), binding(0)]]
+var t_environment: texture_cube<f32>;
+[[group(0), binding(2)]]
+var s_environment: sampler;
+
+[[stage(fragment)]]
+fn main(in: VertexOutput) -> [[location(0)]] vec4<f32> {
+    let pi = 3.14159265359;
+    let normal = normalize(face_direction(face.index, in.face_coords));
+
+    var up: vec3<f32> = vec3<f32>(0.0, 1.0, 0.0);
+    if (abs(normal.y) > 0.999) {
+        up = vec3<f32>(0.0, 0.0, 1.0);
+    }
+    let right = normalize(cross(up, normal));
+    up = cross(normal, right);
+
+    let sample_delta = 0.025;
+    var irradiance: vec3<f32> = vec3<f32>(0.0);
+    var sample_count: f32 = 0.0;
+    for (var phi: f32 = 0.0; phi < 2.0 * pi; phi = phi + sample_delta) {
+        for (var theta: f32 = 0.0; theta < 0.5 * pi; theta = theta + sample_delta) {
+            let tangent_sample = vec3<f32>(sin(theta) * cos(phi), sin(theta) * sin(phi), cos(theta));
+            let sample_dir = tangent_sample.x * right + tangent_sample.y * up + tangent_sample.z * normal;
+            let radiance = textureSampleLevel(t_environment, s_environment, sample_dir, 0.0).rgb;
+            irradiance = irradiance + radiance * cos(theta) * sin(theta);
+            sample_count = sample_count + 1.0;
+        }
+    }
+
+    return vec4<f32>(pi * irradiance / sample_count, 1.0);
+}
@@ -1,18 +1,7 @@
-[[block]]
-struct Uniforms {
-    view_pos: vec4<f32>;
-    view_proj: mat4x4<f32>;
-};
-[[group(0), binding(0)]]
-var<uniform> uniforms: Uniforms;
-
-[[block]]
-struct Light {
-    position: vec3<f32>;
-    colour: vec3<f32>;
-};
-[[group(1), binding(0)]]
-var<uniform> light: Light;
+#define UNIFORMS_GROUP 0
+#define LIGHT_GROUP 1
+#include "include/uniforms.wgsl"
+#include "include/light.wgsl"
 
 struct VertexInput {
     [[location(0)]] position: vec3<f32>;
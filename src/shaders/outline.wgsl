@@ -0,0 +1,56 @@
+// Draws instances as a flat colour, optionally scaled up about their origin. Drawn once
+// unscaled to mark them in the stencil buffer, then again scaled up where they aren't
+// marked, leaving only the rim as an outline. Both follow the cut outs of masked
+// materials.
+
+#define MATERIAL_GROUP 2
+#include "include/uniforms.wgsl"
+#include "include/material.wgsl"
+
+[[block]]
+struct Outline {
+    colour: vec4<f32>;
+    scale: f32;
+};
+[[group(1), binding(0)]]
+var<uniform> outline: Outline;
+
+struct VertexInput {
+    [[location(0)]] position: vec3<f32>;
+    [[location(1)]] tex_coords: vec2<f32>;
+};
+
+struct InstanceInput {
+    [[location(5)]] model_matrix_0: vec4<f32>;
+    [[location(6)]] model_matrix_1: vec4<f32>;
+    [[location(7)]] model_matrix_2: vec4<f32>;
+    [[location(8)]] model_matrix_3: vec4<f32>;
+};
+
+struct VertexOutput {
+    [[builtin(position)]] clip_position: vec4<f32>;
+    [[location(0)]] tex_coords: vec2<f32>;
+};
+
+[[stage(vertex)]]
+fn main(model: VertexInput, instance: InstanceInput) -> VertexOutput {
+    let model_matrix = mat4x4<f32>(
+        instance.model_matrix_0,
+        instance.model_matrix_1,
+        instance.model_matrix_2,
+        instance.model_matrix_3,
+    );
+
+    var out: VertexOutput;
+    out.clip_position = uniforms.view_proj * model_matrix * vec4<f32>(model.position * outline.scale, 1.0);
+    out.tex_coords = model.tex_coords;
+    return out;
+}
+
+[[stage(fragment)]]
+fn main(in: VertexOutput) -> [[location(0)]] vec4<f32> {
+    if (alpha_tested(in.tex_coords)) {
+        discard;
+    }
+    return outline.colour;
+}
@@ -0,0 +1,60 @@
+// Writes an ID for whatever is under each pixel: the instance index plus one in red, so
+// 0 means nothing was drawn there, and the mesh index in green.
+
+#define MATERIAL_GROUP 2
+#include "include/uniforms.wgsl"
+#include "include/material.wgsl"
+
+[[block]]
+struct PickMesh {
+    index: u32;
+};
+[[group(1), binding(0)]]
+var<uniform> pick_mesh: PickMesh;
+
+struct VertexInput {
+    [[location(0)]] position: vec3<f32>;
+    [[location(1)]] tex_coords: vec2<f32>;
+};
+
+struct InstanceInput {
+    [[location(5)]] model_matrix_0: vec4<f32>;
+    [[location(6)]] model_matrix_1: vec4<f32>;
+    [[location(7)]] model_matrix_2: vec4<f32>;
+    [[location(8)]] model_matrix_3: vec4<f32>;
+};
+
+struct VertexOutput {
+    [[builtin(position)]] clip_position: vec4<f32>;
+    [[location(0), interpolate(flat)]] id: vec2<u32>;
+    [[location(1)]] tex_coords: vec2<f32>;
+};
+
+[[stage(vertex)]]
+fn main(
+    model: VertexInput,
+    instance: InstanceInput,
+    [[builtin(instance_index)]] instance_index: u32,
+) -> VertexOutput {
+    let model_matrix = mat4x4<f32>(
+        instance.model_matrix_0,
+        instance.model_matrix_1,
+        instance.model_matrix_2,
+        instance.model_matrix_3,
+    );
+
+    var out: VertexOutput;
+    out.clip_position = uniforms.view_proj * model_matrix * vec4<f32>(model.position, 1.0);
+    out.id = vec2<u32>(instance_index + 1u, pick_mesh.index);
+    out.tex_coords = model.tex_coords;
+    return out;
+}
+
+[[stage(fragment)]]
+fn main(in: VertexOutput) -> [[location(0)]] vec2<u32> {
+    // Cut out parts of masked materials can't be picked
+    if (alpha_tested(in.tex_coords)) {
+        discard;
+    }
+    return in.id;
+}
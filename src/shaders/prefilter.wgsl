@@ -0,0 +1,73 @@
+// Prefilters an environment cube map for one roughness level, using GGX importance sampling.
+// Each sample reads the source mip level whose texels cover about the solid angle the
+// sample stands for, which keeps bright spots from turning into speckles.
+
+#include "include/cube_face.wgsl"
+#include "include/hammersley.wgsl"
+
+[[group(0), binding(0)]]
+var t_environment: texture_cube<f32>;
+[[group(0), binding(2)]]
+var s_environment: sampler;
+
+fn distribution_ggx(n_dot_h: f32, roughness: f32) -> f32 {
+    let pi = 3.14159265359;
+    let a = roughness * roughness;
+    let a2 = a * a;
+    let denominator = n_dot_h * n_dot_h * (a2 - 1.0) + 1.0;
+    return a2 / (pi * denominator * denominator);
+}
+
+fn importance_sample_ggx(xi: vec2<f32>, normal: vec3<f32>, roughness: f32) -> vec3<f32> {
+    let pi = 3.14159265359;
+    let a = roughness * roughness;
+
+    let phi = 2.0 * pi * xi.x;
+    let cos_theta = sqrt((1.0 - xi.y) / (1.0 + (a * a - 1.0) * xi.y));
+    let sin_theta = sqrt(1.0 - cos_theta * cos_theta);
+    let h = vec3<f32>(cos(phi) * sin_theta, sin(phi) * sin_theta, cos_theta);
+
+    var up: vec3<f32> = vec3<f32>(1.0, 0.0, 0.0);
+    if (abs(normal.z) < 0.999) {
+        up = vec3<f32>(0.0, 0.0, 1.0);
+    }
+    let tangent = normalize(cross(up, normal));
+    let bitangent = cross(normal, tangent);
+    return normalize(tangent * h.x + bitangent * h.y + normal * h.z);
+}
+
+[[stage(fragment)]]
+fn main(in: VertexOutput) -> [[location(0)]] vec4<f32> {
+    let normal = normalize(face_direction(face.index, in.face_coords));
+    // Assume the view direction matches the normal, as in the split sum approximation
+    let view = normal;
+
+    let pi = 3.14159265359;
+    let sample_count = 512u;
+    let resolution = f32(textureDimensions(t_environment).x);
+    let texel_solid_angle = 4.0 * pi / (6.0 * resolution * resolution);
+    var prefiltered: vec3<f32> = vec3<f32>(0.0);
+    var total_weight: f32 = 0.0;
+    for (var i: u32 = 0u; i < sample_count; i = i + 1u) {
+        let xi = hammersley(i, sample_count);
+        let h = importance_sample_ggx(xi, normal, face.roughness);
+        let l = normalize(2.0 * dot(view, h) * h - view);
+
+        let n_dot_l = dot(normal, l);
+        if (n_dot_l > 0.0) {
+            // With the view along the normal, the PDF of l is D * n_dot_h / (4 * h_dot_v)
+            // which reduces to D / 4
+            let n_dot_h = max(dot(normal, h), 0.0);
+            let pdf = distribution_ggx(n_dot_h, face.roughness) / 4.0 + 0.0001;
+            let sample_solid_angle = 1.0 / (f32(sample_count) * pdf + 0.0001);
+            var lod: f32 = 0.0;
+            if (face.roughness > 0.0) {
+                lod = max(0.5 * log2(sample_solid_angle / texel_solid_angle), 0.0);
+            }
+            prefiltered = prefiltered + textureSampleLevel(t_environment, s_environment, l, lod).rgb * n_dot_l;
+            total_weight = total_weight + n_dot_l;
+        }
+    }
+
+    return vec4<f32>(prefiltered / max(total_weight, 0.0001), 1.0);
+}
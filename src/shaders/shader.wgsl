@@ -1,20 +1,11 @@
 // Vertex shader
 
-[[block]]
-struct Uniforms {
-    view_pos: vec4<f32>;
-    view_proj: mat4x4<f32>;
-};
-[[group(1), binding(0)]]
-var<uniform> uniforms: Uniforms;
-
-[[block]]
-struct Light {
-    position: vec3<f32>;
-    colour: vec3<f32>;
-};
-[[group(2), binding(0)]]
-var<uniform> light: Light;
+#define UNIFORMS_GROUP 1
+#define LIGHT_GROUP 2
+#include "include/uniforms.wgsl"
+#include "include/light.wgsl"
+#include "include/material.wgsl"
+#include "include/tonemap.wgsl"
 
 struct VertexInput {
     [[location(0)]] position: vec3<f32>;
@@ -40,6 +31,10 @@ struct VertexOutput {
     [[location(1)]] tangent_position: vec3<f32>;
     [[location(2)]] tangent_light_position: vec3<f32>;
     [[location(3)]] tangent_view_position: vec3<f32>;
+    [[location(4)]] world_position: vec3<f32>;
+    [[location(5)]] world_tangent: vec3<f32>;
+    [[location(6)]] world_bitangent: vec3<f32>;
+    [[location(7)]] world_normal: vec3<f32>;
 };
 
 [[stage(vertex)]]
@@ -75,30 +70,45 @@ fn main(model: VertexInput, instance: InstanceInput) -> VertexOutput {
     out.tangent_position = tangent_matrix * world_position.xyz;
     out.tangent_view_position = tangent_matrix * uniforms.view_pos.xyz;
     out.tangent_light_position = tangent_matrix * light.position;
+    out.world_position = world_position.xyz;
+    out.world_tangent = world_tangent;
+    out.world_bitangent = world_bitangent;
+    out.world_normal = world_normal;
     return out;
 }
 
 // Fragment shader
 
-[[group(0), binding(0)]]
-var t_diffuse: texture_2d<f32>;
-[[group(0), binding(1)]]
-var s_diffuse: sampler;
-
-[[group(0), binding(2)]]
-var t_normal: texture_2d<f32>;
-[[group(0), binding(3)]]
-var s_normal: sampler;
+[[group(3), binding(0)]]
+var t_irradiance: texture_cube<f32>;
+[[group(3), binding(1)]]
+var t_prefiltered: texture_cube<f32>;
+[[group(3), binding(2)]]
+var t_brdf_lut: texture_2d<f32>;
+[[group(3), binding(3)]]
+var s_environment: sampler;
+
+let MAX_PREFILTERED_LOD: f32 = 4.0;
+// Distance shown as black by the depth view, the camera's far plane
+let DEPTH_VIEW_FAR: f32 = 100.0;
+// Squares along each side of a texture in the UV checker view
+let UV_CHECKER_SQUARES: f32 = 8.0;
 
 [[stage(fragment)]]
 fn main(in: VertexOutput) -> [[location(0)]] vec4<f32> {
-    let object_colour: vec4<f32> = textureSample(t_diffuse, s_diffuse, in.tex_coords);
-    let object_normal: vec4<f32> = textureSample(t_normal, s_normal, in.tex_coords);
-
-    let ambient_magnitude = 0.1f;
-    let ambient_colour = light.colour * ambient_magnitude;
+    let texture_colour: vec4<f32> = textureSample(t_diffuse, s_diffuse, in.tex_coords);
+    let object_colour = vec4<f32>(
+        texture_colour.rgb * material.diffuse.rgb,
+        texture_colour.a * material.dissolve,
+    );
+    let specular_map = textureSample(t_specular, s_specular, in.tex_coords).rgb;
 
+#ifdef NORMAL_MAPPING
+    let object_normal: vec4<f32> = textureSample(t_normal, s_normal, in.tex_coords);
     let tangent_normal = object_normal.xyz * 2.0 - 1.0;
+#else
+    let tangent_normal = vec3<f32>(0.0, 0.0, 1.0);
+#endif
 
     let light_dir = normalize(in.tangent_light_position - in.tangent_position);
     let view_dir = normalize(in.tangent_view_position - in.tangent_position);
@@ -109,10 +119,71 @@ fn main(in: VertexOutput) -> [[location(0)]] vec4<f32> {
 
     let half_dir = normalize(view_dir + light_dir);
 
-    let specular_strength = pow(max(dot(tangent_normal, half_dir), 0.0), 32.0);
-    let specular_colour = specular_strength * light.colour;
-
-    let result = (ambient_colour + diffuse_colour + specular_colour) * object_colour.xyz;
+    let shininess = material.shininess;
+    let specular_strength = pow(max(dot(tangent_normal, half_dir), 0.0), shininess);
+    let specular_colour = specular_strength * light.colour * material.specular.rgb * specular_map;
+
+    // Image based ambient lighting, using the split sum approximation
+    let world_normal = normalize(mat3x3<f32>(
+        normalize(in.world_tangent),
+        normalize(in.world_bitangent),
+        normalize(in.world_normal),
+    ) * tangent_normal);
+    let world_view_dir = normalize(uniforms.view_pos.xyz - in.world_position);
+    let reflection = reflect(-world_view_dir, world_normal);
+    let n_dot_v = max(dot(world_normal, world_view_dir), 0.0);
+    // Map the Blinn-Phong exponent onto a roughness for the prefiltered lookups
+    let roughness = clamp(sqrt(2.0 / (shininess + 2.0)), 0.0, 1.0);
+
+    let f0 = vec3<f32>(0.04);
+    let fresnel = f0 + (max(vec3<f32>(1.0 - roughness), f0) - f0) * pow(1.0 - n_dot_v, 5.0);
+    let irradiance = textureSample(t_irradiance, s_environment, world_normal).rgb;
+    let prefiltered = textureSampleLevel(t_prefiltered, s_environment, reflection, roughness * MAX_PREFILTERED_LOD).rgb;
+    let brdf = textureSample(t_brdf_lut, s_environment, vec2<f32>(n_dot_v, roughness)).rg;
+    let ambient_colour = (vec3<f32>(1.0) - fresnel) * irradiance * object_colour.xyz
+        + prefiltered * (fresnel * brdf.x + brdf.y) * material.specular.rgb * specular_map;
+
+    // Tone mapped as the skybox is, so the two match
+    var result: vec3<f32> = tonemap(ambient_colour + (diffuse_colour + specular_colour) * object_colour.xyz);
+
+    // Debug view modes, at most one of which is defined, replace the shaded colour
+#ifdef VIEW_WIREFRAME
+    // Drawn with lines rather than filled triangles
+    result = vec3<f32>(1.0);
+#endif
+#ifdef VIEW_NORMALS
+    result = world_normal * 0.5 + 0.5;
+#endif
+#ifdef VIEW_TANGENTS
+    result = normalize(in.world_tangent) * 0.5 + 0.5;
+#endif
+#ifdef VIEW_BITANGENTS
+    result = normalize(in.world_bitangent) * 0.5 + 0.5;
+#endif
+#ifdef VIEW_UV_CHECKER
+    let square = floor(in.tex_coords * UV_CHECKER_SQUARES);
+    let checker = fract((square.x + square.y) * 0.5) * 2.0;
+    // Tinted by the coordinates themselves, so stretching and flips show up too
+    result = mix(vec3<f32>(0.2), vec3<f32>(fract(in.tex_coords), 1.0), vec3<f32>(checker));
+#endif
+#ifdef VIEW_NORMAL_MAP
+    result = textureSample(t_normal, s_normal, in.tex_coords).rgb;
+#endif
+#ifdef VIEW_DEPTH
+    let view_distance = length(uniforms.view_pos.xyz - in.world_position);
+    result = vec3<f32>(1.0 - clamp(view_distance / DEPTH_VIEW_FAR, 0.0, 1.0));
+#endif
+#ifdef VIEW_LIGHTING
+    // Shaded as if every surface were white
+    result = tonemap((vec3<f32>(1.0) - fresnel) * irradiance
+        + prefiltered * (fresnel * brdf.x + brdf.y) * material.specular.rgb * specular_map
+        + diffuse_colour + specular_colour);
+#endif
+
+    // Alpha tested last, as textures can't be sampled after a discard
+    if (object_colour.a < material.alpha_cutoff) {
+        discard;
+    }
 
     return vec4<f32>(result, object_colour.a);
 }
\ No newline at end of file
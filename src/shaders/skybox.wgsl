@@ -0,0 +1,41 @@
+#define UNIFORMS_GROUP 0
+#include "include/uniforms.wgsl"
+#include "include/tonemap.wgsl"
+
+[[group(1), binding(0)]]
+var t_sky: texture_cube<f32>;
+[[group(1), binding(1)]]
+var s_sky: sampler;
+
+struct VertexOutput {
+    [[builtin(position)]] clip_position: vec4<f32>;
+    [[location(0)]] direction: vec3<f32>;
+};
+
+[[stage(vertex)]]
+fn main([[builtin(vertex_index)]] vertex_index: u32) -> VertexOutput {
+    // Unit cube as a 14 vertex triangle strip, picked out of the bit masks 0x287a, 0x02af and 0x31e3
+    let x = f32((10362u >> vertex_index) & 1u) * 2.0 - 1.0;
+    let y = f32((687u >> vertex_index) & 1u) * 2.0 - 1.0;
+    let z = f32((12771u >> vertex_index) & 1u) * 2.0 - 1.0;
+    let direction = vec3<f32>(x, y, z);
+
+    let clip = uniforms.view_proj * vec4<f32>(uniforms.view_pos.xyz + direction, 1.0);
+
+    var out: VertexOutput;
+    // Pin the sky to the far plane so it only fills what the scene left empty
+    out.clip_position = clip.xyww;
+    out.direction = direction;
+    return out;
+}
+
+[[stage(fragment)]]
+fn main(in: VertexOutput) -> [[location(0)]] vec4<f32> {
+    let colour = textureSample(t_sky, s_sky, in.direction).rgb;
+#ifdef HDR
+    // An HDR sky would otherwise clip, an LDR one is already in range
+    return vec4<f32>(tonemap(colour), 1.0);
+#else
+    return vec4<f32>(colour, 1.0);
+#endif
+}
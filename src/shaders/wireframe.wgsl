@@ -0,0 +1,50 @@
+// Wireframe for adapters without line polygon mode. Triangles are drawn filled from an
+// unindexed copy of the mesh, where each corner carries its own barycentric coordinate,
+// and only the fragments close to an edge are kept.
+
+#include "include/uniforms.wgsl"
+
+// Edge thickness in pixels
+let EDGE_WIDTH: f32 = 1.0;
+
+struct VertexInput {
+    [[location(0)]] position: vec3<f32>;
+    [[location(1)]] barycentric: vec3<f32>;
+};
+
+struct InstanceInput {
+    [[location(5)]] model_matrix_0: vec4<f32>;
+    [[location(6)]] model_matrix_1: vec4<f32>;
+    [[location(7)]] model_matrix_2: vec4<f32>;
+    [[location(8)]] model_matrix_3: vec4<f32>;
+};
+
+struct VertexOutput {
+    [[builtin(position)]] clip_position: vec4<f32>;
+    [[location(0)]] barycentric: vec3<f32>;
+};
+
+[[stage(vertex)]]
+fn main(model: VertexInput, instance: InstanceInput) -> VertexOutput {
+    let model_matrix = mat4x4<f32>(
+        instance.model_matrix_0,
+        instance.model_matrix_1,
+        instance.model_matrix_2,
+        instance.model_matrix_3,
+    );
+
+    var out: VertexOutput;
+    out.clip_position = uniforms.view_proj * model_matrix * vec4<f32>(model.position, 1.0);
+    out.barycentric = model.barycentric;
+    return out;
+}
+
+[[stage(fragment)]]
+fn main(in: VertexOutput) -> [[location(0)]] vec4<f32> {
+    // How far each coordinate changes across a pixel, so edges stay the same width on screen
+    let edge = in.barycentric / (fwidth(in.barycentric) * EDGE_WIDTH);
+    if (min(min(edge.x, edge.y), edge.z) > 1.0) {
+        discard;
+    }
+    return vec4<f32>(1.0);
+}
@@ -0,0 +1,186 @@
+use std::path::Path;
+
+use anyhow::*;
+
+use crate::preprocessor::Defines;
+use crate::shader::{self, ShaderLibrary};
+use crate::shader_interface;
+use crate::texture::Texture;
+
+/// What the frame is cleared to before the scene is drawn.
+pub enum Background {
+    Colour(wgpu::Color),
+    Skybox(Skybox),
+}
+
+impl Background {
+    pub fn clear_colour(&self) -> wgpu::Color {
+        match self {
+            Background::Colour(colour) => *colour,
+            Background::Skybox(_) => wgpu::Color::BLACK,
+        }
+    }
+}
+
+pub struct Skybox {
+    pub texture: Texture,
+    bind_group: wgpu::BindGroup,
+    pipeline: wgpu::RenderPipeline,
+}
+
+impl Skybox {
+    const FACE_SIZE: u32 = 512;
+    const FACE_NAMES: [&'static str; 6] = ["px", "nx", "py", "ny", "pz", "nz"];
+
+    /// Loads a skybox from either an equirectangular HDR image, or a directory holding
+    /// six face images named px, nx, py, ny, pz and nz.
+    pub fn load<P: AsRef<Path>>(
+        device: &wgpu::Device,
+        queue: &wgpu::Queue,
+        uniform_bind_group_layout: &wgpu::BindGroupLayout,
+        colour_format: wgpu::TextureFormat,
+        sample_count: u32,
+        path: P,
+    ) -> Result<Self> {
+        let path = path.as_ref();
+        // Only the equirectangular images are HDR, the faces are 8 bit
+        let hdr = !path.is_dir();
+        let texture = if path.is_dir() {
+            let mut faces = Vec::with_capacity(6);
+            for name in Self::FACE_NAMES.iter() {
+                let face = ["png", "jpg", "jpeg"]
+                    .iter()
+                    .map(|ext| path.join(name).with_extension(ext))
+                    .find(|face| face.exists())
+                    .with_context(|| format!("Skybox {:?} has no {} face", path, name))?;
+                faces.push(face);
+            }
+            let faces: [_; 6] = [
+                &faces[0], &faces[1], &faces[2], &faces[3], &faces[4], &faces[5],
+            ];
+            Texture::load_cube_faces(device, queue, &faces, &path.to_string_lossy())?
+        } else {
+            Texture::load_equirectangular(device, queue, path, Self::FACE_SIZE)?
+        };
+        Self::new(
+            device,
+            uniform_bind_group_layout,
+            colour_format,
+            sample_count,
+            texture,
+            hdr,
+        )
+    }
+
+    /// Draws `texture`, tone mapped like the scene when it's `hdr`, and as is otherwise.
+    pub fn new(
+        device: &wgpu::Device,
+        uniform_bind_group_layout: &wgpu::BindGroupLayout,
+        colour_format: wgpu::TextureFormat,
+        sample_count: u32,
+        texture: Texture,
+        hdr: bool,
+    ) -> Result<Self> {
+        let bind_group_layout = shader::create_bind_group_layout(
+            device,
+            "Skybox bind group layout",
+            shader_interface::SKYBOX_GROUP,
+        );
+
+        let bind_group = device.create_bind_group(&wgpu::BindGroupDescriptor {
+            label: Some("Skybox bind group"),
+            layout: &bind_group_layout,
+            entries: &[
+                wgpu::BindGroupEntry {
+                    binding: 0,
+                    resource: wgpu::BindingResource::TextureView(&texture.view),
+                },
+                wgpu::BindGroupEntry {
+                    binding: 1,
+                    resource: wgpu::BindingResource::Sampler(&texture.sampler),
+                },
+            ],
+        });
+
+        let layout = device.create_pipeline_layout(&wgpu::PipelineLayoutDescriptor {
+            label: Some("Skybox pipeline layout"),
+            bind_group_layouts: &[uniform_bind_group_layout, &bind_group_layout],
+            push_constant_ranges: &[],
+        });
+
+        let defines = if hdr { Defines::new().with("HDR") } else { Defines::new() };
+        let shader = device.create_shader_module(
+            &ShaderLibrary::embedded()
+                .load("skybox.wgsl", &defines)?,
+        );
+
+        let pipeline = device.create_render_pipeline(&wgpu::RenderPipelineDescriptor {
+            label: Some("Skybox Pipeline"),
+            layout: Some(&layout),
+            vertex: wgpu::VertexState {
+                module: &shader,
+                entry_point: "main",
+                buffers: &[],
+            },
+            fragment: Some(wgpu::FragmentState {
+                module: &shader,
+                entry_point: "main",
+                targets: &[wgpu::ColorTargetState {
+                    format: colour_format,
+                    blend: Some(wgpu::BlendState {
+                        color: wgpu::BlendComponent::REPLACE,
+                        alpha: wgpu::BlendComponent::REPLACE,
+                    }),
+                    write_mask: wgpu::ColorWrite::ALL,
+                }],
+            }),
+            primitive: wgpu::PrimitiveState {
+                topology: wgpu::PrimitiveTopology::TriangleStrip,
+                strip_index_format: None,
+                front_face: wgpu::FrontFace::Ccw,
+                cull_mode: None,
+                polygon_mode: wgpu::PolygonMode::Fill,
+                clamp_depth: false,
+                conservative: false,
+            },
+            // Drawn at depth 1.0 after the opaque pass, so only test against what is there
+            depth_stencil: Some(wgpu::DepthStencilState {
+                format: Texture::DEPTH_FORMAT,
+                depth_write_enabled: false,
+                depth_compare: wgpu::CompareFunction::LessEqual,
+                stencil: wgpu::StencilState::default(),
+                bias: wgpu::DepthBiasState::default(),
+            }),
+            multisample: wgpu::MultisampleState {
+                count: sample_count,
+                mask: !0,
+                alpha_to_coverage_enabled: false,
+            },
+        });
+
+        Ok(Self {
+            texture,
+            bind_group,
+            pipeline,
+        })
+    }
+}
+
+pub trait DrawSkybox<'a, 'b>
+where
+    'b: 'a,
+{
+    fn draw_skybox(&mut self, skybox: &'b Skybox, uniforms: &'b wgpu::BindGroup);
+}
+
+impl<'a, 'b> DrawSkybox<'a, 'b> for wgpu::RenderPass<'a>
+where
+    'b: 'a,
+{
+    fn draw_skybox(&mut self, skybox: &'b Skybox, uniforms: &'b wgpu::BindGroup) {
+        self.set_pipeline(&skybox.pipeline);
+        self.set_bind_group(0, uniforms, &[]);
+        self.set_bind_group(1, &skybox.bind_group, &[]);
+        self.draw(0..14, 0..1);
+    }
+}
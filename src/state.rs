@@ -1,17 +1,77 @@
 use crate::camera::CameraController;
 use crate::instance::InstanceRaw;
 use crate::uniform::Uniforms;
-use crate::{instance::Instance, light::Light};
+use crate::instance::{Instance, InstanceGrid};
+use crate::light::Light;
 use cgmath::*;
+use crate::adapter::{self, AdapterOptions};
+use crate::assets::AssetCache;
+use crate::error::{FrameError, RendererError, Result};
+use std::ops::Range;
+use std::path::{Path, PathBuf};
+use std::rc::Rc;
+use std::sync::{Arc, Mutex};
+use std::time::Instant;
 
 use wgpu::util::DeviceExt;
-use winit::{event::WindowEvent, window::Window};
+use winit::{
+    dpi::PhysicalPosition,
+    event::{ElementState, Event, KeyboardInput, MouseButton, VirtualKeyCode, WindowEvent},
+    window::Window,
+};
 
 use crate::camera::Camera;
+use crate::debug_draw::{self, DebugDraw};
+use crate::device_loss;
+#[cfg(feature = "debug-ui")]
+use crate::debug_ui::{self, DebugUi};
+use crate::gpu_timer::GpuTimer;
+use crate::hud::{FrameStats, Hud};
+use crate::ibl::EnvironmentMaps;
+use crate::loader::LoadHandle;
 use crate::model::{self, DrawLight};
-use crate::model::{DrawModel, Model};
+use crate::model::{DrawModel, Model, ModelData};
+use crate::outline::Outline;
+use crate::picking::{Pick, Picker};
+use crate::raycast::{self, Hit, InstanceBvh, Ray};
+use crate::preprocessor::Defines;
+use crate::shader::{self, ShaderLibrary};
+use crate::shader_interface;
+use crate::skybox::{Background, DrawSkybox, Skybox};
 use crate::texture::{self, Texture};
 use crate::vertex::Vertex;
+use crate::view_mode::{BarycentricWireframe, ViewMode};
+
+fn next_present_mode(present_mode: wgpu::PresentMode) -> wgpu::PresentMode {
+    match present_mode {
+        wgpu::PresentMode::Fifo => wgpu::PresentMode::Mailbox,
+        wgpu::PresentMode::Mailbox => wgpu::PresentMode::Immediate,
+        wgpu::PresentMode::Immediate => wgpu::PresentMode::Fifo,
+    }
+}
+
+/// Runs of consecutive highlighted instances, so each can be outlined in one draw.
+fn highlighted_ranges(instances: &[Instance]) -> Vec<Range<u32>> {
+    let mut ranges: Vec<Range<u32>> = Vec::new();
+    for (index, _) in instances
+        .iter()
+        .enumerate()
+        .filter(|(_, instance)| instance.highlighted)
+    {
+        let index = index as u32;
+        match ranges.last_mut() {
+            Some(range) if range.end == index => range.end += 1,
+            _ => ranges.push(index..index + 1),
+        }
+    }
+    ranges
+}
+
+/// The model shader built for opaque and masked materials, and again for blended ones.
+struct ModelPipelines {
+    opaque: wgpu::RenderPipeline,
+    blended: wgpu::RenderPipeline,
+}
 
 fn rgb_to_normalized(r: u8, g: u8, b: u8) -> wgpu::Color {
     // Wish this could be const, but cant do fp arithmatic in const fn
@@ -23,118 +83,206 @@ fn rgb_to_normalized(r: u8, g: u8, b: u8) -> wgpu::Color {
     }
 }
 
-const INSTANCES_PER_ROW: u32 = 100;
-const INSTANCE_DISPLACEMENT: cgmath::Vector3<f32> = cgmath::Vector3::new(
-    INSTANCES_PER_ROW as f32 * 0.5,
-    0.0,
-    INSTANCES_PER_ROW as f32 * 0.5,
-);
+/// Start up settings for the renderer.
+#[derive(Clone)]
+pub struct Options {
+    /// The OBJ file to render, or the bundled cube when not set.
+    pub model: Option<PathBuf>,
+    /// Skybox to load, or a flat clear colour when not set.
+    pub skybox: Option<PathBuf>,
+    pub backends: wgpu::BackendBit,
+    pub adapter: AdapterOptions,
+    pub present_mode: wgpu::PresentMode,
+    pub sample_count: u32,
+    pub watch_shaders: bool,
+}
+
+impl Default for Options {
+    fn default() -> Self {
+        Self {
+            model: None,
+            skybox: Some(resources_dir().join("skybox/sky.hdr")),
+            backends: wgpu::BackendBit::PRIMARY,
+            adapter: AdapterOptions::default(),
+            present_mode: wgpu::PresentMode::Fifo,
+            sample_count: 1,
+            watch_shaders: false,
+        }
+    }
+}
+
+fn resources_dir() -> PathBuf {
+    Path::new(env!("OUT_DIR")).join("resources")
+}
+
+/// How many frames had to be recovered from each kind of failure.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct RecoveryStats {
+    pub outdated: u64,
+    pub timeouts: u64,
+    pub swap_chain_lost: u64,
+    pub device_lost: u64,
+}
+
+/// Give up when the device is lost this many times without a frame rendering in between.
+const MAX_DEVICE_RECREATIONS: u32 = 3;
+
+/// The CPU side of the scene, carried over to a recreated device rather than loaded again.
+struct Retained {
+    assets: AssetCache,
+    model_data: ModelData,
+    model_load: Option<LoadHandle<ModelData>>,
+}
 
 pub struct State {
-    surface: wgpu::Surface,
+    // Kept across device recreation, wgpu retires the old swap chain when a new one is
+    // created for the same surface
+    gpu_instance: Rc<wgpu::Instance>,
+    surface: Rc<wgpu::Surface>,
     device: wgpu::Device,
     queue: wgpu::Queue,
     sc_desc: wgpu::SwapChainDescriptor,
     swap_chain: wgpu::SwapChain,
     pub size: winit::dpi::PhysicalSize<u32>,
-    bg_color: wgpu::Color,
-    render_pipeline: wgpu::RenderPipeline,
+    options: Options,
+    /// The first error wgpu reported since the last frame, which is either a bug or the
+    /// device being lost.
+    device_error: Arc<Mutex<Option<String>>>,
+    /// Set when wgpu panicked over the device outside of [`render`](Self::render), which
+    /// then leaves recovering to the next frame.
+    device_lost: bool,
+    /// Set by [`simulate_device_loss`](Self::simulate_device_loss) for the next frame.
+    simulate_device_loss: bool,
+    pub recovery_stats: RecoveryStats,
+    device_recreations: u32,
+    sample_count: u32,
+    msaa_framebuffer: Option<Texture>,
+    background: Background,
+    environment: EnvironmentMaps,
+    shaders: ShaderLibrary,
+    /// Textures and materials, kept for anything else loaded later to share.
+    assets: AssetCache,
+    texture_bind_group_layout: wgpu::BindGroupLayout,
+    uniform_bind_group_layout: wgpu::BindGroupLayout,
+    render_pipeline_layout: wgpu::PipelineLayout,
+    model_pipelines: ModelPipelines,
+    normal_mapping: bool,
+    view_mode: ViewMode,
+    /// Draws the wireframe view when the adapter can't draw lines for polygons.
+    wireframe_fallback: Option<BarycentricWireframe>,
     camera: Camera,
     uniforms: Uniforms,
     uniform_buffer: wgpu::Buffer,
     uniform_bind_group: wgpu::BindGroup,
     camera_controller: CameraController,
+    instance_grid: InstanceGrid,
     instances: Vec<Instance>,
     instance_buffer: wgpu::Buffer,
+    /// The instances again, furthest from the camera first, for drawing blended meshes.
+    sorted_instance_buffer: wgpu::Buffer,
+    instance_bvh: InstanceBvh,
     depth_texture: Texture,
+    /// A placeholder until `model_load` finishes.
     obj_model: Model,
+    /// What `obj_model` was uploaded from, to upload again if the device is lost.
+    model_data: ModelData,
+    model_path: PathBuf,
+    model_load: Option<LoadHandle<ModelData>>,
     light: Light,
+    light_orbit: bool,
     light_buffer: wgpu::Buffer,
     light_bind_group: wgpu::BindGroup,
+    light_pipeline_layout: wgpu::PipelineLayout,
     light_render_pipeline: wgpu::RenderPipeline,
+    hud: Hud,
+    debug_draw: DebugDraw,
+    picker: Picker,
+    cursor_position: Option<PhysicalPosition<f64>>,
+    outline: Outline,
+    /// Draw light positions, instance bounds and the camera frustum frozen when they were
+    /// turned on.
+    debug_geometry: Option<Matrix4<f32>>,
+    #[cfg(feature = "debug-ui")]
+    debug_ui: DebugUi,
+    gpu_timer: GpuTimer,
+    pub frame_stats: FrameStats,
+    frame_start: Instant,
 }
 
 impl State {
-    pub async fn new(window: &Window) -> Self {
-        let size = window.inner_size();
-
-        let instance = wgpu::Instance::new(wgpu::BackendBit::PRIMARY);
+    pub async fn new(window: &Window, options: &Options) -> Result<Self> {
+        let instance = wgpu::Instance::new(options.backends);
         let surface = unsafe { instance.create_surface(window) };
-        let adapter = instance
-            .request_adapter(&wgpu::RequestAdapterOptions {
-                compatible_surface: Some(&surface),
-                power_preference: wgpu::PowerPreference::HighPerformance,
-            })
-            .await
-            .expect("Could not create adapter instance!");
+
+        Self::create(
+            Rc::new(instance),
+            Rc::new(surface),
+            window.inner_size(),
+            window.scale_factor(),
+            options,
+            None,
+        )
+        .await
+    }
+
+    /// Creates a device and everything on it. The model is loaded from scratch, unless
+    /// `retained` has it from a device that was lost.
+    async fn create(
+        gpu_instance: Rc<wgpu::Instance>,
+        surface: Rc<wgpu::Surface>,
+        size: winit::dpi::PhysicalSize<u32>,
+        scale_factor: f64,
+        options: &Options,
+        retained: Option<Retained>,
+    ) -> Result<Self> {
+        profile_scope!("State::create");
+
+        let adapter = adapter::select(&gpu_instance, &surface, options.backends, &options.adapter)
+            .map_err(RendererError::Adapter)?;
 
         let (device, queue) = adapter
             .request_device(
                 &wgpu::DeviceDescriptor {
-                    features: wgpu::Features::empty(),
+                    // Optional features are only used when the adapter has them
+                    features: adapter.features()
+                        & (GpuTimer::FEATURES | wgpu::Features::NON_FILL_POLYGON_MODE),
                     limits: wgpu::Limits::default(),
                     label: None,
                 },
                 None,
             )
             .await
-            .expect("Could not get device from adapter!");
+            .map_err(RendererError::Device)?;
+
+        let device_error = Arc::new(Mutex::new(None));
+        device.on_uncaptured_error({
+            let device_error = Arc::clone(&device_error);
+            // Told apart from the device being lost before the next frame
+            move |error| {
+                log::warn!("wgpu error: {}", error);
+                device_error.lock().unwrap().get_or_insert(error.to_string());
+            }
+        });
 
         let sc_desc = wgpu::SwapChainDescriptor {
             usage: wgpu::TextureUsage::RENDER_ATTACHMENT,
             format: adapter
                 .get_swap_chain_preferred_format(&surface)
-                .expect("Could not get swapchain format!"),
+                .ok_or(RendererError::IncompatibleSurface)?,
             width: size.width,
             height: size.height,
-            present_mode: wgpu::PresentMode::Fifo,
+            present_mode: options.present_mode,
         };
         let swap_chain = device.create_swap_chain(&surface, &sc_desc);
 
-        let texture_bind_group_layout =
-            device.create_bind_group_layout(&wgpu::BindGroupLayoutDescriptor {
-                label: Some("Texure bind group layout"),
-                entries: &[
-                    wgpu::BindGroupLayoutEntry {
-                        binding: 0,
-                        visibility: wgpu::ShaderStage::FRAGMENT,
-                        ty: wgpu::BindingType::Texture {
-                            sample_type: wgpu::TextureSampleType::Float { filterable: false },
-                            view_dimension: wgpu::TextureViewDimension::D2,
-                            multisampled: false,
-                        },
-                        count: None,
-                    },
-                    wgpu::BindGroupLayoutEntry {
-                        binding: 1,
-                        visibility: wgpu::ShaderStage::FRAGMENT,
-                        ty: wgpu::BindingType::Sampler {
-                            filtering: true,
-                            comparison: false,
-                        },
-                        count: None,
-                    },
-                    wgpu::BindGroupLayoutEntry {
-                        binding: 2,
-                        visibility: wgpu::ShaderStage::FRAGMENT,
-                        ty: wgpu::BindingType::Texture {
-                            sample_type: wgpu::TextureSampleType::Float { filterable: true },
-                            view_dimension: wgpu::TextureViewDimension::D2,
-                            multisampled: false,
-                        },
-                        count: None,
-                    },
-                    wgpu::BindGroupLayoutEntry {
-                        binding: 3,
-                        visibility: wgpu::ShaderStage::FRAGMENT,
-                        ty: wgpu::BindingType::Sampler {
-                            filtering: true,
-                            comparison: false,
-                        },
-                        count: None,
-                    },
-                ],
-            });
+        let sample_count = options.sample_count;
+        let msaa_framebuffer = Texture::create_msaa_framebuffer(&device, &sc_desc, sample_count);
+
+        let texture_bind_group_layout = shader::create_bind_group_layout(
+            &device,
+            "Texure bind group layout",
+            shader_interface::TEXTURE_GROUP,
+        );
 
         let camera = Camera {
             eye: (0.0, 1.0, 2.0).into(),
@@ -157,20 +305,11 @@ impl State {
             usage: wgpu::BufferUsage::UNIFORM | wgpu::BufferUsage::COPY_DST,
         });
 
-        let uniform_bind_group_layout =
-            device.create_bind_group_layout(&wgpu::BindGroupLayoutDescriptor {
-                label: Some("Uniform Bind Group Layout"),
-                entries: &[wgpu::BindGroupLayoutEntry {
-                    binding: 0,
-                    visibility: wgpu::ShaderStage::VERTEX | wgpu::ShaderStage::FRAGMENT,
-                    ty: wgpu::BindingType::Buffer {
-                        ty: wgpu::BufferBindingType::Uniform,
-                        has_dynamic_offset: false,
-                        min_binding_size: None,
-                    },
-                    count: None,
-                }],
-            });
+        let uniform_bind_group_layout = shader::create_bind_group_layout(
+            &device,
+            "Uniform Bind Group Layout",
+            shader_interface::UNIFORM_GROUP,
+        );
 
         let uniform_bind_group = device.create_bind_group(&wgpu::BindGroupDescriptor {
             label: Some("Uniform Bind Group"),
@@ -181,9 +320,6 @@ impl State {
             }],
         });
 
-        // Cornflour blue, because I 'member XNA
-        let bg_color = rgb_to_normalized(100, 149, 237);
-
         let light = Light {
             position: [2.0, 2.0, 2.0],
             _padding: 0.0,
@@ -196,20 +332,11 @@ impl State {
             usage: wgpu::BufferUsage::UNIFORM | wgpu::BufferUsage::COPY_DST,
         });
 
-        let light_bind_group_layout =
-            device.create_bind_group_layout(&wgpu::BindGroupLayoutDescriptor {
-                entries: &[wgpu::BindGroupLayoutEntry {
-                    binding: 0,
-                    visibility: wgpu::ShaderStage::VERTEX | wgpu::ShaderStage::FRAGMENT,
-                    ty: wgpu::BindingType::Buffer {
-                        ty: wgpu::BufferBindingType::Uniform,
-                        has_dynamic_offset: false,
-                        min_binding_size: None,
-                    },
-                    count: None,
-                }],
-                label: None,
-            });
+        let light_bind_group_layout = shader::create_bind_group_layout(
+            &device,
+            "Light Bind Group Layout",
+            shader_interface::LIGHT_GROUP,
+        );
 
         let light_bind_group = device.create_bind_group(&wgpu::BindGroupDescriptor {
             layout: &light_bind_group_layout,
@@ -220,6 +347,8 @@ impl State {
             label: None,
         });
 
+        let environment_bind_group_layout = EnvironmentMaps::create_bind_group_layout(&device);
+
         let render_pipeline_layout =
             device.create_pipeline_layout(&wgpu::PipelineLayoutDescriptor {
                 label: Some("Render Pipeline Layout"),
@@ -227,148 +356,396 @@ impl State {
                     &texture_bind_group_layout,
                     &uniform_bind_group_layout,
                     &light_bind_group_layout,
+                    &environment_bind_group_layout,
                 ],
                 push_constant_ranges: &[],
             });
 
-        let resources_dir = std::path::Path::new(env!("OUT_DIR")).join("resources");
-        let obj_model = Model::load(
+        // Cornflour blue, because I 'member XNA
+        let clear_colour = rgb_to_normalized(100, 149, 237);
+        let background = match &options.skybox {
+            Some(path) => match Skybox::load(
+                &device,
+                &queue,
+                &uniform_bind_group_layout,
+                sc_desc.format,
+                sample_count,
+                path,
+            ) {
+                Ok(skybox) => Background::Skybox(skybox),
+                Err(e) => {
+                    log::warn!("Could not load skybox, falling back to a clear colour: {}", e);
+                    Background::Colour(clear_colour)
+                }
+            },
+            None => Background::Colour(clear_colour),
+        };
+        let environment =
+            Self::create_environment(&device, &queue, &environment_bind_group_layout, &background)
+                .map_err(RendererError::Shader)?;
+
+        let model_path = options
+            .model
+            .clone()
+            .unwrap_or_else(|| resources_dir().join("cube/cube.obj"));
+        let (mut assets, model_data, model_load) = match retained {
+            Some(Retained {
+                mut assets,
+                model_data,
+                model_load,
+            }) => {
+                assets
+                    .reupload(&device, &queue)
+                    .map_err(|source| RendererError::Model {
+                        path: model_path.clone(),
+                        source,
+                    })?;
+                (assets, model_data, model_load)
+            }
+            None => {
+                let model_load = LoadHandle::spawn(&model_path.display().to_string(), {
+                    let model_path = model_path.clone();
+                    move |progress| ModelData::load(&model_path, progress)
+                });
+                // Drawn until the model has loaded
+                (AssetCache::new(), ModelData::placeholder(), Some(model_load))
+            }
+        };
+        let obj_model = Model::from_data(
             &device,
             &queue,
             &texture_bind_group_layout,
-            resources_dir.join("cube/cube.obj"),
+            &mut assets,
+            &model_data,
         )
-        .unwrap();
-
-        const SPACE_BETWEEN: f32 = 3.0;
-        let instances = (0..INSTANCES_PER_ROW)
-            .flat_map(|z| {
-                (0..INSTANCES_PER_ROW).map(move |x| {
-                    let x = SPACE_BETWEEN * (x as f32 - INSTANCES_PER_ROW as f32 / 2.0);
-                    let z = SPACE_BETWEEN * (z as f32 - INSTANCES_PER_ROW as f32 / 2.0);
-
-                    let position = cgmath::Vector3 {
-                        x: x as f32,
-                        y: 0.0,
-                        z: z as f32,
-                    } - INSTANCE_DISPLACEMENT;
-
-                    let rotation = if position.is_zero() {
-                        cgmath::Quaternion::from_axis_angle(
-                            cgmath::Vector3::unit_z(),
-                            cgmath::Deg(0.0),
-                        )
-                    } else {
-                        cgmath::Quaternion::from_axis_angle(
-                            position.clone().normalize(),
-                            cgmath::Deg(45.0),
-                        )
-                    };
+        .map_err(|source| RendererError::Model {
+            path: model_path.clone(),
+            source,
+        })?;
 
-                    Instance { position, rotation }
-                })
-            })
-            .collect::<Vec<_>>();
+        let instance_grid = InstanceGrid::default();
+        let instances = instance_grid.instances();
 
-        let instance_data = instances.iter().map(Instance::to_raw).collect::<Vec<_>>();
+        let instance_buffer = Self::create_instance_buffer(&device, &instances);
+        let sorted_instance_buffer = Self::create_sorted_instance_buffer(&device, instances.len());
 
-        let instance_buffer = device.create_buffer_init(&wgpu::util::BufferInitDescriptor {
-            label: Some("Instance Buffer"),
-            contents: bytemuck::cast_slice(instance_data.as_slice()),
-            usage: wgpu::BufferUsage::VERTEX,
-        });
+        let instance_bvh = InstanceBvh::build(&obj_model.bounds, &instances);
 
-        let depth_texture = Texture::create_depth_texture(&device, &sc_desc, "Depth Texture");
+        let depth_texture =
+            Texture::create_depth_texture(&device, &sc_desc, sample_count, "Depth Texture");
 
-        let render_pipeline = {
-            let shader = wgpu::ShaderModuleDescriptor {
-                label: Some("Normal Shader"),
-                source: wgpu::ShaderSource::Wgsl(include_str!("shaders/shader.wgsl").into()),
-                flags: wgpu::ShaderFlags::all(),
-            };
-            State::create_render_pipeline(
-                &device,
-                &render_pipeline_layout,
-                sc_desc.format,
-                Some(texture::Texture::DEPTH_FORMAT),
-                &[model::ModelVertex::desc(), InstanceRaw::desc()],
-                shader,
+        let mut shaders = if options.watch_shaders {
+            ShaderLibrary::watch(Path::new(env!("CARGO_MANIFEST_DIR")).join("src/shaders"))
+        } else {
+            ShaderLibrary::embedded()
+        };
+
+        let normal_mapping = true;
+        let view_mode = ViewMode::Shaded;
+        let model_pipelines = Self::create_model_pipelines(
+            &device,
+            &render_pipeline_layout,
+            sc_desc.format,
+            sample_count,
+            &mut shaders,
+            normal_mapping,
+            view_mode,
+        )
+        .map_err(RendererError::Shader)?;
+        let wireframe_fallback = if device
+            .features()
+            .contains(wgpu::Features::NON_FILL_POLYGON_MODE)
+        {
+            None
+        } else {
+            Some(
+                BarycentricWireframe::new(
+                    &device,
+                    &uniform_bind_group_layout,
+                    sc_desc.format,
+                    sample_count,
+                    &obj_model,
+                )
+                .map_err(RendererError::Shader)?,
             )
         };
 
-        let light_render_pipeline = {
-            let layout = device.create_pipeline_layout(&wgpu::PipelineLayoutDescriptor {
+        let light_pipeline_layout =
+            device.create_pipeline_layout(&wgpu::PipelineLayoutDescriptor {
                 label: Some("Light pipeline layout desc"),
                 bind_group_layouts: &[&uniform_bind_group_layout, &light_bind_group_layout],
                 push_constant_ranges: &[],
             });
-            let shader = wgpu::ShaderModuleDescriptor {
-                label: Some("Light Shader"),
-                source: wgpu::ShaderSource::Wgsl(include_str!("shaders/light.wgsl").into()),
-                flags: wgpu::ShaderFlags::all(),
-            };
+        let light_render_pipeline = Self::create_light_pipeline(
+            &device,
+            &light_pipeline_layout,
+            sc_desc.format,
+            sample_count,
+            &mut shaders,
+        )
+        .map_err(RendererError::Shader)?;
 
-            Self::create_render_pipeline(
-                &device,
-                &layout,
-                sc_desc.format,
-                Some(texture::Texture::DEPTH_FORMAT),
-                &[model::ModelVertex::desc()],
-                shader,
-            )
-        };
+        let hud = Hud::new(&device, &queue, sc_desc.format).map_err(RendererError::Shader)?;
+        let picker = Picker::new(&device, &uniform_bind_group_layout, &texture_bind_group_layout)
+            .map_err(RendererError::Shader)?;
+        let outline = Outline::new(
+            &device,
+            &uniform_bind_group_layout,
+            &texture_bind_group_layout,
+            sc_desc.format,
+            sample_count,
+        )
+        .map_err(RendererError::Shader)?;
+        let debug_draw = DebugDraw::new(
+            &device,
+            &uniform_bind_group_layout,
+            sc_desc.format,
+            sample_count,
+        )
+        .map_err(RendererError::Shader)?;
+        #[cfg(feature = "debug-ui")]
+        let debug_ui = DebugUi::new(&device, sc_desc.format, size, scale_factor);
+        #[cfg(not(feature = "debug-ui"))]
+        let _ = scale_factor;
+        let gpu_timer = GpuTimer::new(&device, &queue);
 
-        Self {
+        Ok(Self {
+            gpu_instance,
             surface,
             device,
             queue,
             sc_desc,
             swap_chain,
             size,
-            bg_color,
-            render_pipeline,
+            options: options.clone(),
+            device_error,
+            device_lost: false,
+            simulate_device_loss: false,
+            recovery_stats: RecoveryStats::default(),
+            device_recreations: 0,
+            sample_count,
+            msaa_framebuffer,
+            background,
+            environment,
+            shaders,
+            assets,
+            texture_bind_group_layout,
+            uniform_bind_group_layout,
+            render_pipeline_layout,
+            model_pipelines,
+            normal_mapping,
+            view_mode,
+            wireframe_fallback,
             camera,
             uniforms,
             uniform_buffer,
             uniform_bind_group,
             camera_controller,
+            instance_grid,
             instances,
             instance_buffer,
+            sorted_instance_buffer,
+            instance_bvh,
             depth_texture,
             obj_model,
+            model_data,
+            model_path,
+            model_load,
             light,
+            light_orbit: true,
             light_buffer,
             light_bind_group,
+            light_pipeline_layout,
             light_render_pipeline,
+            hud,
+            debug_draw,
+            debug_geometry: None,
+            picker,
+            cursor_position: None,
+            outline,
+            #[cfg(feature = "debug-ui")]
+            debug_ui,
+            gpu_timer,
+            frame_stats: FrameStats::default(),
+            frame_start: Instant::now(),
+        })
+    }
+
+    fn create_instance_buffer(device: &wgpu::Device, instances: &[Instance]) -> wgpu::Buffer {
+        let instance_data = instances.iter().map(Instance::to_raw).collect::<Vec<_>>();
+        device.create_buffer_init(&wgpu::util::BufferInitDescriptor {
+            label: Some("Instance Buffer"),
+            contents: bytemuck::cast_slice(instance_data.as_slice()),
+            usage: wgpu::BufferUsage::VERTEX,
+        })
+    }
+
+    fn create_sorted_instance_buffer(device: &wgpu::Device, instances: usize) -> wgpu::Buffer {
+        device.create_buffer(&wgpu::BufferDescriptor {
+            label: Some("Sorted Instance Buffer"),
+            size: (instances * std::mem::size_of::<InstanceRaw>()) as wgpu::BufferAddress,
+            usage: wgpu::BufferUsage::VERTEX | wgpu::BufferUsage::COPY_DST,
+            mapped_at_creation: false,
+        })
+    }
+
+    fn create_model_pipelines(
+        device: &wgpu::Device,
+        layout: &wgpu::PipelineLayout,
+        colour_format: wgpu::TextureFormat,
+        sample_count: u32,
+        shaders: &mut ShaderLibrary,
+        normal_mapping: bool,
+        view_mode: ViewMode,
+    ) -> anyhow::Result<ModelPipelines> {
+        let mut defines = Defines::new();
+        if normal_mapping {
+            defines = defines.with("NORMAL_MAPPING");
+        }
+        if let Some(define) = view_mode.define() {
+            defines = defines.with(define);
+        }
+        let polygon_mode = if view_mode == ViewMode::Wireframe
+            && device
+                .features()
+                .contains(wgpu::Features::NON_FILL_POLYGON_MODE)
+        {
+            wgpu::PolygonMode::Line
+        } else {
+            wgpu::PolygonMode::Fill
+        };
+
+        let shader = device.create_shader_module(&shaders.load("shader.wgsl", &defines)?);
+        let create_pipeline = |blended| {
+            Self::create_render_pipeline(
+                device,
+                layout,
+                colour_format,
+                Some(texture::Texture::DEPTH_FORMAT),
+                sample_count,
+                polygon_mode,
+                blended,
+                &[model::ModelVertex::desc(), InstanceRaw::desc()],
+                &shader,
+            )
+        };
+
+        Ok(ModelPipelines {
+            opaque: create_pipeline(false),
+            blended: create_pipeline(true),
+        })
+    }
+
+    fn create_light_pipeline(
+        device: &wgpu::Device,
+        layout: &wgpu::PipelineLayout,
+        colour_format: wgpu::TextureFormat,
+        sample_count: u32,
+        shaders: &mut ShaderLibrary,
+    ) -> anyhow::Result<wgpu::RenderPipeline> {
+        let shader = device.create_shader_module(&shaders.load("light.wgsl", &Defines::new())?);
+        Ok(Self::create_render_pipeline(
+            device,
+            layout,
+            colour_format,
+            Some(texture::Texture::DEPTH_FORMAT),
+            sample_count,
+            wgpu::PolygonMode::Fill,
+            false,
+            &[model::ModelVertex::desc()],
+            &shader,
+        ))
+    }
+
+    /// Rebuilds the pipelines from freshly loaded shaders. If either fails to compile the
+    /// error is logged and the current pipelines are kept.
+    fn reload_shaders(&mut self) {
+        let model_pipelines = Self::create_model_pipelines(
+            &self.device,
+            &self.render_pipeline_layout,
+            self.sc_desc.format,
+            self.sample_count,
+            &mut self.shaders,
+            self.normal_mapping,
+            self.view_mode,
+        );
+        let light_render_pipeline = Self::create_light_pipeline(
+            &self.device,
+            &self.light_pipeline_layout,
+            self.sc_desc.format,
+            self.sample_count,
+            &mut self.shaders,
+        );
+
+        match (model_pipelines, light_render_pipeline) {
+            (Ok(model_pipelines), Ok(light_render_pipeline)) => {
+                self.model_pipelines = model_pipelines;
+                self.light_render_pipeline = light_render_pipeline;
+                log::info!("Reloaded shaders");
+            }
+            (Err(e), _) | (_, Err(e)) => {
+                log::error!("Shader reload failed, keeping the previous pipelines: {:?}", e)
+            }
         }
     }
 
+    fn create_environment(
+        device: &wgpu::Device,
+        queue: &wgpu::Queue,
+        layout: &wgpu::BindGroupLayout,
+        background: &Background,
+    ) -> anyhow::Result<EnvironmentMaps> {
+        match background {
+            Background::Skybox(skybox) => {
+                EnvironmentMaps::generate(device, queue, layout, &skybox.texture)
+            }
+            // Without an environment, fall back to a dim constant ambient term
+            Background::Colour(_) => EnvironmentMaps::from_colour(
+                device,
+                queue,
+                layout,
+                wgpu::Color {
+                    r: 0.1,
+                    g: 0.1,
+                    b: 0.1,
+                    a: 1.0,
+                },
+            ),
+        }
+    }
+
+    #[allow(clippy::too_many_arguments)]
     fn create_render_pipeline(
         device: &wgpu::Device,
         layout: &wgpu::PipelineLayout,
         colour_format: wgpu::TextureFormat,
         depth_format: Option<wgpu::TextureFormat>,
+        sample_count: u32,
+        polygon_mode: wgpu::PolygonMode,
+        blended: bool,
         vertex_layouts: &[wgpu::VertexBufferLayout],
-        shader: wgpu::ShaderModuleDescriptor,
+        shader: &wgpu::ShaderModule,
     ) -> wgpu::RenderPipeline {
-        let shader = device.create_shader_module(&shader);
-
-        return device.create_render_pipeline(&wgpu::RenderPipelineDescriptor {
+        device.create_render_pipeline(&wgpu::RenderPipelineDescriptor {
             label: Some("Render Pipeline"),
             layout: Some(layout),
             vertex: wgpu::VertexState {
-                module: &shader,
+                module: shader,
                 entry_point: "main",
                 buffers: vertex_layouts,
             },
             fragment: Some(wgpu::FragmentState {
-                module: &shader,
+                module: shader,
                 entry_point: "main",
                 targets: &[wgpu::ColorTargetState {
                     format: colour_format,
-                    blend: Some(wgpu::BlendState {
-                        color: wgpu::BlendComponent::REPLACE,
-                        alpha: wgpu::BlendComponent::REPLACE,
+                    blend: Some(if blended {
+                        wgpu::BlendState::ALPHA_BLENDING
+                    } else {
+                        wgpu::BlendState {
+                            color: wgpu::BlendComponent::REPLACE,
+                            alpha: wgpu::BlendComponent::REPLACE,
+                        }
                     }),
                     write_mask: wgpu::ColorWrite::ALL,
                 }],
@@ -378,59 +755,594 @@ impl State {
                 strip_index_format: None,
                 front_face: wgpu::FrontFace::Ccw,
                 cull_mode: None,
-                polygon_mode: wgpu::PolygonMode::Fill,
+                polygon_mode,
                 clamp_depth: false,
                 conservative: false,
             },
+            // Blended surfaces are tested against what's opaque but don't hide each other
             depth_stencil: depth_format.map(|format| wgpu::DepthStencilState {
                 format,
-                depth_write_enabled: true,
+                depth_write_enabled: !blended,
                 depth_compare: wgpu::CompareFunction::LessEqual,
                 stencil: wgpu::StencilState::default(),
                 bias: wgpu::DepthBiasState::default(),
             }),
             multisample: wgpu::MultisampleState {
-                count: 1,
+                count: sample_count,
                 mask: !0,
                 alpha_to_coverage_enabled: false,
             },
-        });
+        })
     }
 
     pub fn resize(&mut self, new_size: winit::dpi::PhysicalSize<u32>) {
+        self.guard(|state| state.resize_swap_chain(new_size));
+    }
+
+    fn resize_swap_chain(&mut self, new_size: winit::dpi::PhysicalSize<u32>) {
         self.size = new_size;
         self.sc_desc.width = self.size.width;
         self.sc_desc.height = self.size.height;
         self.swap_chain = self.device.create_swap_chain(&self.surface, &self.sc_desc);
-        self.depth_texture =
-            Texture::create_depth_texture(&self.device, &self.sc_desc, "Depth Texture");
+        self.depth_texture = Texture::create_depth_texture(
+            &self.device,
+            &self.sc_desc,
+            self.sample_count,
+            "Depth Texture",
+        );
+        self.msaa_framebuffer =
+            Texture::create_msaa_framebuffer(&self.device, &self.sc_desc, self.sample_count);
         self.camera.aspect = self.sc_desc.width as f32 / self.sc_desc.height as f32
     }
 
+    /// Lays the model instances out again, replacing the instance buffer.
+    pub fn set_instance_grid(&mut self, instance_grid: InstanceGrid) {
+        self.instance_grid = instance_grid;
+        self.instances = instance_grid.instances();
+        self.instance_buffer = Self::create_instance_buffer(&self.device, &self.instances);
+        self.sorted_instance_buffer =
+            Self::create_sorted_instance_buffer(&self.device, self.instances.len());
+        self.instance_bvh = InstanceBvh::build(&self.obj_model.bounds, &self.instances);
+    }
+
+    /// Gets rendering going again after [`render`](Self::render) failed. A lost device is
+    /// replaced, along with everything created from it, keeping the camera, light,
+    /// highlights and settings. The model and its textures are uploaded again from the CPU
+    /// side, and a model still loading carries on. Running out of memory, wgpu reporting a
+    /// bug, or the device being lost again straight after being recreated, can't be
+    /// recovered from.
+    pub async fn recover(&mut self, window: &Window, error: FrameError) -> Result<()> {
+        match error {
+            FrameError::SwapChain(wgpu::SwapChainError::Timeout) => {
+                self.recovery_stats.timeouts += 1;
+                log::warn!("Timed out acquiring a frame, skipping it");
+            }
+            FrameError::SwapChain(wgpu::SwapChainError::Outdated) => {
+                self.recovery_stats.outdated += 1;
+                log::debug!("Swap chain is outdated, recreating it");
+                self.resize(window.inner_size());
+            }
+            FrameError::SwapChain(wgpu::SwapChainError::Lost) => {
+                self.recovery_stats.swap_chain_lost += 1;
+                log::warn!("Swap chain was lost, recreating it");
+                self.resize(window.inner_size());
+            }
+            FrameError::SwapChain(wgpu::SwapChainError::OutOfMemory) => {
+                return Err(RendererError::OutOfMemory)
+            }
+            FrameError::Wgpu(error) => return Err(RendererError::Wgpu(anyhow::Error::msg(error))),
+            FrameError::DeviceLost => {
+                if self.device_recreations >= MAX_DEVICE_RECREATIONS {
+                    return Err(RendererError::DeviceLost);
+                }
+
+                self.recovery_stats.device_lost += 1;
+                log::warn!("Device was lost, recreating it and all GPU resources");
+
+                let retained = Retained {
+                    assets: std::mem::take(&mut self.assets),
+                    model_data: std::mem::replace(&mut self.model_data, ModelData::placeholder()),
+                    model_load: self.model_load.take(),
+                };
+                let mut state = Self::create(
+                    Rc::clone(&self.gpu_instance),
+                    Rc::clone(&self.surface),
+                    window.inner_size(),
+                    window.scale_factor(),
+                    &self.options,
+                    Some(retained),
+                )
+                .await?;
+                std::mem::swap(&mut state.camera, &mut self.camera);
+                std::mem::swap(&mut state.camera_controller, &mut self.camera_controller);
+                state.light = self.light;
+                state.light_orbit = self.light_orbit;
+                state.debug_geometry = self.debug_geometry;
+                if let (Background::Colour(colour), Background::Colour(old_colour)) =
+                    (&mut state.background, &self.background)
+                {
+                    *colour = *old_colour;
+                }
+                if state.instance_grid != self.instance_grid {
+                    state.set_instance_grid(self.instance_grid);
+                }
+                for (instance, old) in state.instances.iter_mut().zip(self.instances.iter()) {
+                    instance.highlighted = old.highlighted;
+                }
+                state.recovery_stats = self.recovery_stats;
+                state.device_recreations = self.device_recreations + 1;
+                if state.normal_mapping != self.normal_mapping {
+                    state.set_normal_mapping(self.normal_mapping);
+                }
+                if state.view_mode != self.view_mode {
+                    state.set_view_mode(self.view_mode);
+                }
+                if self.hud.is_visible() {
+                    state.hud.toggle();
+                }
+                #[cfg(feature = "debug-ui")]
+                if self.debug_ui.is_visible() {
+                    state.debug_ui.toggle();
+                }
+                // Dropping a device waits for it, which wgpu 0.9 panics over once it's lost,
+                // so the old one is deliberately leaked. Its memory went with it, and the
+                // rest of the old state is freed as usual.
+                let lost = std::mem::replace(self, state);
+                let _ = device_loss::catch(move || {
+                    let State { device, .. } = lost;
+                    std::mem::forget(device);
+                });
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Has the next frame fail as though wgpu panicked over a lost device, to try out
+    /// [`recover`](Self::recover). The old device is leaked, as a lost one would be.
+    pub fn simulate_device_loss(&mut self) {
+        log::warn!("Simulating device loss");
+        self.simulate_device_loss = true;
+    }
+
+    /// Runs `call` on behalf of anything other than [`render`](Self::render) that touches
+    /// the GPU. Gives `None` when wgpu panicked over a lost device, which the next frame
+    /// then recovers from.
+    fn guard<T>(&mut self, call: impl FnOnce(&mut Self) -> T) -> Option<T> {
+        match device_loss::catch(|| call(self)) {
+            Ok(value) => Some(value),
+            Err(_) => {
+                self.device_lost = true;
+                None
+            }
+        }
+    }
+
+    /// Gives the debug UI the first look at every event. Returns true when it took the
+    /// event, which then shouldn't be passed to [`input`](Self::input).
+    #[cfg(feature = "debug-ui")]
+    pub fn ui_input(&mut self, event: &Event<()>) -> bool {
+        self.debug_ui.handle_event(event)
+    }
+
+    #[cfg(not(feature = "debug-ui"))]
+    pub fn ui_input(&mut self, _event: &Event<()>) -> bool {
+        false
+    }
+
     pub fn input(&mut self, event: &WindowEvent) -> bool {
+        // Taken, so the camera doesn't move on an event that lost the device
+        self.guard(|state| state.handle_input(event)).unwrap_or(true)
+    }
+
+    fn handle_input(&mut self, event: &WindowEvent) -> bool {
+        match event {
+            WindowEvent::CursorMoved { position, .. } => self.cursor_position = Some(*position),
+            WindowEvent::CursorLeft { .. } => self.cursor_position = None,
+            WindowEvent::MouseInput {
+                state: ElementState::Pressed,
+                button: MouseButton::Left,
+                ..
+            } => {
+                if let Some(position) = self.cursor_position {
+                    self.clear_highlights();
+                    match self.pick(position.x as u32, position.y as u32) {
+                        Some(pick) => {
+                            self.set_highlighted(pick.instance as usize, true);
+                            log::info!(
+                                "Selected instance {} (mesh {}) at {:?}",
+                                pick.instance,
+                                pick.mesh,
+                                self.instances[pick.instance as usize].position
+                            );
+                        }
+                        None => log::info!("Nothing to select there"),
+                    }
+                    if let Some(hit) = self.raycast(position.x as f32, position.y as f32) {
+                        log::info!(
+                            "Ray hit instance {} (mesh {}) at {:?}, normal {:?}, {:.2} away",
+                            hit.instance,
+                            hit.mesh,
+                            hit.point,
+                            hit.normal,
+                            hit.distance
+                        );
+                    }
+                    return true;
+                }
+            }
+            _ => {}
+        }
+
+        if let WindowEvent::KeyboardInput {
+            input:
+                KeyboardInput {
+                    state: ElementState::Pressed,
+                    virtual_keycode: Some(key),
+                    ..
+                },
+            ..
+        } = event
+        {
+            match key {
+                VirtualKeyCode::N => {
+                    self.set_normal_mapping(!self.normal_mapping);
+                    return true;
+                }
+                VirtualKeyCode::V => {
+                    self.set_view_mode(self.view_mode.next());
+                    return true;
+                }
+                VirtualKeyCode::F1 => {
+                    self.hud.toggle();
+                    return true;
+                }
+                #[cfg(feature = "debug-ui")]
+                VirtualKeyCode::F2 => {
+                    self.debug_ui.toggle();
+                    return true;
+                }
+                VirtualKeyCode::G => {
+                    self.debug_geometry = match self.debug_geometry {
+                        Some(_) => None,
+                        None => Some(self.camera.build_view_projection_matrix()),
+                    };
+                    return true;
+                }
+                VirtualKeyCode::P => {
+                    self.set_present_mode(next_present_mode(self.present_mode()));
+                    return true;
+                }
+                _ => {}
+            }
+        }
+
         self.camera_controller.process_inputs(event)
     }
 
-    pub fn update(&mut self) {
-        let old_position: cgmath::Vector3<_> = self.light.position.into();
-        self.light.position =
-            (cgmath::Quaternion::from_axis_angle((0.0, 1.0, 0.0).into(), cgmath::Deg(1.0))
-                * old_position)
-                .into();
+    /// Finds the instance and mesh drawn at pixel `(x, y)`, as of the last update.
+    pub fn pick(&mut self, x: u32, y: u32) -> Option<Pick> {
+        self.guard(|state| {
+            state.picker.pick(
+                &state.device,
+                &state.queue,
+                &state.uniform_bind_group,
+                &state.obj_model,
+                &state.instance_buffer,
+                state.instances.len() as u32,
+                state.size,
+                (x, y),
+            )
+        })
+        .flatten()
+    }
+
+    pub fn instances(&self) -> &[Instance] {
+        &self.instances
+    }
+
+    /// Outlines instance `index`, or stops outlining it.
+    pub fn set_highlighted(&mut self, index: usize, highlighted: bool) {
+        if let Some(instance) = self.instances.get_mut(index) {
+            instance.highlighted = highlighted;
+        }
+    }
+
+    pub fn clear_highlights(&mut self) {
+        for instance in self.instances.iter_mut() {
+            instance.highlighted = false;
+        }
+    }
+
+    /// Casts a ray from the camera through pixel `(x, y)`, without touching the GPU.
+    pub fn raycast(&self, x: f32, y: f32) -> Option<Hit> {
+        let ray = self
+            .camera
+            .screen_ray(x, y, self.size.width, self.size.height)?;
+        self.cast_ray(&ray)
+    }
+
+    /// The closest instance surface along a world space `ray`.
+    pub fn cast_ray(&self, ray: &Ray) -> Option<Hit> {
+        raycast::raycast(ray, &self.obj_model, &self.instances, &self.instance_bvh)
+    }
+
+    /// The present mode asked for, which may not be the one in effect if the surface
+    /// doesn't support it.
+    pub fn present_mode(&self) -> wgpu::PresentMode {
+        self.sc_desc.present_mode
+    }
+
+    /// Recreates the swap chain with `present_mode`. wgpu can't tell us which modes the
+    /// surface supports, it falls back to Fifo with a warning when `present_mode` isn't one.
+    pub fn set_present_mode(&mut self, present_mode: wgpu::PresentMode) {
+        log::info!("Switching to {:?} presentation", present_mode);
+        self.options.present_mode = present_mode;
+        self.sc_desc.present_mode = present_mode;
+        self.swap_chain = self.device.create_swap_chain(&self.surface, &self.sc_desc);
+    }
+
+    /// Switches between the normal mapped and the plain variant of the model shader.
+    pub fn set_normal_mapping(&mut self, enabled: bool) {
+        match Self::create_model_pipelines(
+            &self.device,
+            &self.render_pipeline_layout,
+            self.sc_desc.format,
+            self.sample_count,
+            &mut self.shaders,
+            enabled,
+            self.view_mode,
+        ) {
+            Ok(model_pipelines) => {
+                self.model_pipelines = model_pipelines;
+                self.normal_mapping = enabled;
+            }
+            Err(e) => log::error!("Could not switch normal mapping: {:?}", e),
+        }
+    }
+
+    pub fn set_view_mode(&mut self, view_mode: ViewMode) {
+        match Self::create_model_pipelines(
+            &self.device,
+            &self.render_pipeline_layout,
+            self.sc_desc.format,
+            self.sample_count,
+            &mut self.shaders,
+            self.normal_mapping,
+            view_mode,
+        ) {
+            Ok(model_pipelines) => {
+                log::info!("Switching to the {} view", view_mode.name());
+                self.model_pipelines = model_pipelines;
+                self.view_mode = view_mode;
+            }
+            Err(e) => log::error!("Could not switch to the {} view: {:?}", view_mode.name(), e),
+        }
+    }
+
+    /// Swaps the placeholder for the model once it has loaded.
+    fn poll_model_load(&mut self) -> Result<()> {
+        let result = match self.model_load.as_mut().and_then(LoadHandle::poll) {
+            Some(result) => result,
+            None => return Ok(()),
+        };
+        self.model_load = None;
+
+        let model = result.and_then(|data| {
+            let model = Model::from_data(
+                &self.device,
+                &self.queue,
+                &self.texture_bind_group_layout,
+                &mut self.assets,
+                &data,
+            )?;
+            Ok((model, data))
+        });
+        let (model, mut data) = model.map_err(|source| RendererError::Model {
+            path: self.model_path.clone(),
+            source,
+        })?;
+        // Uploaded now, and decoded again from their files should the device be lost
+        data.images.clear();
+        self.model_data = data;
+        self.set_model(model)
+    }
+
+    fn set_model(&mut self, model: Model) -> Result<()> {
+        self.obj_model = model;
+        self.instance_bvh = InstanceBvh::build(&self.obj_model.bounds, &self.instances);
+        if self.wireframe_fallback.is_some() {
+            self.wireframe_fallback = Some(
+                BarycentricWireframe::new(
+                    &self.device,
+                    &self.uniform_bind_group_layout,
+                    self.sc_desc.format,
+                    self.sample_count,
+                    &self.obj_model,
+                )
+                .map_err(RendererError::Shader)?,
+            );
+        }
+        // Whatever only the replaced model used
+        self.assets.evict_unused();
+        log::debug!("Assets after loading the model: {:?}", self.assets.stats());
+        Ok(())
+    }
+
+    /// Whether the model is still loading, with the placeholder drawn in its place.
+    pub fn is_loading(&self) -> bool {
+        self.model_load.is_some()
+    }
+
+    /// Moves the scene on a frame. Fails when the model couldn't be loaded, as `new` did
+    /// before loading moved to a worker thread.
+    pub fn update(&mut self) -> Result<()> {
+        self.guard(Self::update_scene).unwrap_or(Ok(()))
+    }
+
+    fn update_scene(&mut self) -> Result<()> {
+        profile_scope!("State::update");
+        self.frame_start = Instant::now();
+        self.debug_draw.clear();
+
+        if self.shaders.poll_changes() {
+            self.reload_shaders();
+        }
+        self.poll_model_load()?;
+
+        #[cfg(feature = "debug-ui")]
+        {
+            let clear_colour = match &mut self.background {
+                Background::Colour(colour) => Some(colour),
+                Background::Skybox(_) => None,
+            };
+            let mut view_mode = self.view_mode;
+            let grid_changed = self.debug_ui.update(debug_ui::Scene {
+                camera: &mut self.camera,
+                light: &mut self.light,
+                light_orbit: &mut self.light_orbit,
+                clear_colour,
+                instance_grid: &mut self.instance_grid,
+                view_mode: &mut view_mode,
+                assets: &mut self.assets,
+                lose_device: &mut self.simulate_device_loss,
+            });
+            if grid_changed {
+                self.set_instance_grid(self.instance_grid);
+            }
+            if view_mode != self.view_mode {
+                self.set_view_mode(view_mode);
+            }
+        }
+
+        if self.light_orbit {
+            let old_position: cgmath::Vector3<_> = self.light.position.into();
+            self.light.position =
+                (cgmath::Quaternion::from_axis_angle((0.0, 1.0, 0.0).into(), cgmath::Deg(1.0))
+                    * old_position)
+                    .into();
+        }
         self.queue
             .write_buffer(&self.light_buffer, 0, bytemuck::cast_slice(&[self.light]));
 
         self.camera_controller.update_camera(&mut self.camera);
         self.uniforms.update_view_proj(&self.camera);
+        if self.obj_model.has_blended_meshes() {
+            self.sort_instances();
+        }
+
+        if let Some(frustum) = self.debug_geometry {
+            self.draw_debug_geometry(&frustum);
+        }
         self.queue.write_buffer(
             &self.uniform_buffer,
             0,
             bytemuck::cast_slice(&[self.uniforms]),
         );
+        Ok(())
+    }
+
+    /// Fills the sorted instance buffer, furthest from the camera first, so blended meshes
+    /// are drawn over whatever is behind them. Meshes within an instance aren't sorted.
+    fn sort_instances(&mut self) {
+        profile_scope!("sort instances");
+        let eye = self.camera.eye.to_vec();
+        let mut sorted = self.instances.iter().collect::<Vec<_>>();
+        sorted.sort_by(|a, b| {
+            let a = (a.position - eye).magnitude2();
+            let b = (b.position - eye).magnitude2();
+            b.partial_cmp(&a).unwrap_or(std::cmp::Ordering::Equal)
+        });
+        let instance_data = sorted.iter().map(|instance| instance.to_raw()).collect::<Vec<_>>();
+        self.queue.write_buffer(
+            &self.sorted_instance_buffer,
+            0,
+            bytemuck::cast_slice(&instance_data),
+        );
+    }
+
+    fn draw_debug_geometry(&mut self, frustum: &Matrix4<f32>) {
+        let light_position = Point3::from(self.light.position);
+        self.debug_draw.sphere(light_position, 0.3, debug_draw::YELLOW);
+        for instance in self.instances.iter() {
+            let transform = instance.transform();
+            self.debug_draw
+                .oriented_box(&self.obj_model.bounds, &transform, debug_draw::GREEN);
+            // Selected instances also get the world space box the raycast tests them with
+            if instance.highlighted {
+                self.debug_draw
+                    .aabb(&self.obj_model.bounds.transformed(&transform), debug_draw::RED);
+            }
+        }
+        self.debug_draw.frustum(frustum, debug_draw::WHITE);
+
+        // Axes stay visible through the scene
+        self.debug_draw.set_depth_test(false);
+        self.debug_draw.axes(&Matrix4::from_translation(light_position.to_vec()), 0.5);
+        self.debug_draw.axes(&Matrix4::identity(), 1.0);
+    }
+
+    pub fn render(&mut self) -> Result<(), FrameError> {
+        profile_scope!("State::render");
+
+        // Nothing is acquired or submitted on a device already known to be lost, and one
+        // lost part way through the frame is caught as wgpu panics
+        self.check_device()?;
+        let simulate_device_loss = std::mem::take(&mut self.simulate_device_loss);
+        let frame = {
+            profile_scope!("acquire frame");
+            device_loss::catch(|| {
+                if simulate_device_loss {
+                    panic!("Simulated device loss");
+                }
+                self.swap_chain.get_current_frame()
+            })??
+            .output
+        };
+        let commands = device_loss::catch(|| self.render_frame(&frame));
+        let submitted = commands.and_then(|commands| {
+            profile_scope!("submit");
+            device_loss::catch(|| self.queue.submit(std::iter::once(commands)))
+        });
+        // Presents the frame, which has to happen here even when recording or submitting
+        // failed, as dropping it anywhere else would panic outside of `device_loss::catch`
+        let presented = device_loss::catch(|| drop(frame));
+        submitted?;
+        presented?;
+        self.gpu_timer.end_frame();
+        self.hud.record_frame(self.frame_start.elapsed());
+        self.check_device()?;
+
+        self.device_recreations = 0;
+        Ok(())
+    }
+
+    /// Fails when the device was lost since the last frame, or wgpu has reported an error.
+    /// A lost device is only one cause of those, so it's probed with an empty submission,
+    /// which panics when the device is gone.
+    fn check_device(&mut self) -> Result<(), FrameError> {
+        if self.device_lost {
+            return Err(FrameError::DeviceLost);
+        }
+        let error = match self.device_error.lock().unwrap().take() {
+            Some(error) => error,
+            None => return Ok(()),
+        };
+        device_loss::catch(|| {
+            let encoder = self
+                .device
+                .create_command_encoder(&wgpu::CommandEncoderDescriptor {
+                    label: Some("Device check encoder"),
+                });
+            self.queue.submit(std::iter::once(encoder.finish()));
+            self.device.poll(wgpu::Maintain::Wait);
+        })?;
+        Err(FrameError::Wgpu(error))
     }
 
-    pub fn render(&mut self) -> Result<(), wgpu::SwapChainError> {
-        let frame = self.swap_chain.get_current_frame()?.output;
+    /// Records the frame into `frame`, giving the commands to submit.
+    fn render_frame(&mut self, frame: &wgpu::SwapChainTexture) -> wgpu::CommandBuffer {
+        self.gpu_timer.begin_frame(&self.device);
 
         let mut encoder = self
             .device
@@ -442,10 +1354,13 @@ impl State {
             let mut render_pass = encoder.begin_render_pass(&wgpu::RenderPassDescriptor {
                 label: Some("Frame render pass"),
                 color_attachments: &[wgpu::RenderPassColorAttachment {
-                    view: &frame.view,
-                    resolve_target: None,
+                    view: self
+                        .msaa_framebuffer
+                        .as_ref()
+                        .map_or(&frame.view, |framebuffer| &framebuffer.view),
+                    resolve_target: self.msaa_framebuffer.as_ref().map(|_| &frame.view),
                     ops: wgpu::Operations {
-                        load: wgpu::LoadOp::Clear(self.bg_color),
+                        load: wgpu::LoadOp::Clear(self.background.clear_colour()),
                         store: true,
                     },
                 }],
@@ -455,29 +1370,161 @@ impl State {
                         load: wgpu::LoadOp::Clear(1.0),
                         store: true,
                     }),
-                    stencil_ops: None,
+                    stencil_ops: Some(wgpu::Operations {
+                        load: wgpu::LoadOp::Clear(0),
+                        store: false,
+                    }),
                 }),
             });
 
+            let meshes = self.obj_model.meshes.len() as u32;
+            let model_triangles = self
+                .obj_model
+                .meshes
+                .iter()
+                .map(|mesh| mesh.num_elements as u64 / 3)
+                .sum();
+            let (blended_meshes, blended_triangles) = self
+                .obj_model
+                .meshes
+                .iter()
+                .filter(|mesh| self.obj_model.is_blended(mesh))
+                .fold((0, 0), |(meshes, triangles), mesh| {
+                    (meshes + 1, triangles + mesh.num_elements as u64 / 3)
+                });
+            let wireframe = self
+                .wireframe_fallback
+                .as_ref()
+                .filter(|_| self.view_mode == ViewMode::Wireframe);
+            let mut stats = FrameStats::default();
+
             render_pass.set_vertex_buffer(1, self.instance_buffer.slice(..));
 
+            self.gpu_timer.start(&mut render_pass, "light");
             render_pass.set_pipeline(&self.light_render_pipeline);
             render_pass.draw_light_model(
                 &self.obj_model,
                 &self.uniform_bind_group,
                 &self.light_bind_group,
             );
+            stats.record(meshes, 1, model_triangles);
+            self.gpu_timer.end(&mut render_pass);
 
-            render_pass.set_pipeline(&self.render_pipeline);
-            render_pass.draw_model_instanced(
-                &self.obj_model,
-                0..self.instances.len() as u32,
+            self.gpu_timer.start(&mut render_pass, "model");
+            match wireframe {
+                Some(wireframe) => {
+                    wireframe.draw(
+                        &mut render_pass,
+                        &self.instance_buffer,
+                        &self.uniform_bind_group,
+                        self.instances.len() as u32,
+                    );
+                    stats.record(meshes, self.instances.len() as u32, model_triangles);
+                }
+                None => {
+                    render_pass.set_pipeline(&self.model_pipelines.opaque);
+                    render_pass.set_bind_group(3, &self.environment.bind_group, &[]);
+                    render_pass.draw_model_layer_instanced(
+                        &self.obj_model,
+                        false,
+                        0..self.instances.len() as u32,
+                        &self.uniform_bind_group,
+                        &self.light_bind_group,
+                    );
+                    stats.record(
+                        meshes - blended_meshes,
+                        self.instances.len() as u32,
+                        model_triangles - blended_triangles,
+                    );
+                }
+            }
+            self.gpu_timer.end(&mut render_pass);
+
+            if let Background::Skybox(skybox) = &self.background {
+                self.gpu_timer.start(&mut render_pass, "skybox");
+                render_pass.draw_skybox(skybox, &self.uniform_bind_group);
+                stats.record(1, 1, 12);
+                self.gpu_timer.end(&mut render_pass);
+            }
+
+            // Blended meshes go over everything opaque, including the sky
+            if wireframe.is_none() && blended_meshes > 0 {
+                self.gpu_timer.start(&mut render_pass, "blended");
+                render_pass.set_vertex_buffer(1, self.sorted_instance_buffer.slice(..));
+                render_pass.set_pipeline(&self.model_pipelines.blended);
+                render_pass.set_bind_group(3, &self.environment.bind_group, &[]);
+                render_pass.draw_model_layer_instanced(
+                    &self.obj_model,
+                    true,
+                    0..self.instances.len() as u32,
+                    &self.uniform_bind_group,
+                    &self.light_bind_group,
+                );
+                stats.record(blended_meshes, self.instances.len() as u32, blended_triangles);
+                self.gpu_timer.end(&mut render_pass);
+            }
+
+            let highlighted = highlighted_ranges(&self.instances);
+            if !highlighted.is_empty() {
+                self.gpu_timer.start(&mut render_pass, "outline");
+                self.outline.draw(
+                    &mut render_pass,
+                    &self.obj_model,
+                    &self.instance_buffer,
+                    &self.uniform_bind_group,
+                    &highlighted,
+                );
+                let instances = highlighted.iter().map(|range| range.len() as u32).sum();
+                stats.record(meshes * 2 * highlighted.len() as u32, instances, model_triangles);
+                self.gpu_timer.end(&mut render_pass);
+            }
+
+            self.gpu_timer.start(&mut render_pass, "debug");
+            self.debug_draw.flush(
+                &self.device,
+                &self.queue,
+                &mut render_pass,
                 &self.uniform_bind_group,
-                &self.light_bind_group,
             );
+            self.gpu_timer.end(&mut render_pass);
+
+            stats.loading = self
+                .model_load
+                .as_ref()
+                .map(|load| (load.name().to_string(), load.progress()));
+            stats.gpu_time = self.gpu_timer.frame().summary();
+            stats.gpu_passes = self
+                .gpu_timer
+                .scopes()
+                .filter_map(|(label, stats)| Some((label, stats.summary()?)))
+                .collect();
+            self.frame_stats = stats;
         }
 
-        self.queue.submit(std::iter::once(encoder.finish()));
-        Ok(())
+        self.gpu_timer.start(&mut encoder, "hud");
+        self.hud.render(
+            &self.device,
+            &self.queue,
+            &mut encoder,
+            &frame.view,
+            self.size,
+            &self.frame_stats,
+        );
+        self.gpu_timer.end(&mut encoder);
+
+        #[cfg(feature = "debug-ui")]
+        {
+            self.gpu_timer.start(&mut encoder, "ui");
+            self.debug_ui.render(
+                &self.device,
+                &self.queue,
+                &mut encoder,
+                &frame.view,
+                self.size,
+            );
+            self.gpu_timer.end(&mut encoder);
+        }
+        self.gpu_timer.resolve(&mut encoder);
+        encoder.finish()
     }
 }
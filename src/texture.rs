@@ -3,6 +3,10 @@ use std::path::Path;
 use anyhow::*;
 use image::GenericImageView;
 
+use crate::preprocessor::Defines;
+use crate::shader::{self, ShaderLibrary};
+use crate::shader_interface;
+
 pub struct Texture {
     pub texture: wgpu::Texture,
     pub view: wgpu::TextureView,
@@ -10,11 +14,14 @@ pub struct Texture {
 }
 
 impl Texture {
-    pub const DEPTH_FORMAT: wgpu::TextureFormat = wgpu::TextureFormat::Depth32Float;
+    /// Depth with a stencil aspect, which marks selected instances for their outline.
+    pub const DEPTH_FORMAT: wgpu::TextureFormat = wgpu::TextureFormat::Depth24PlusStencil8;
+    pub const HDR_CUBE_FORMAT: wgpu::TextureFormat = wgpu::TextureFormat::Rgba16Float;
 
     pub fn create_depth_texture(
         device: &wgpu::Device,
         sc_desc: &wgpu::SwapChainDescriptor,
+        sample_count: u32,
         label: &str,
     ) -> Self {
         let size = wgpu::Extent3d {
@@ -27,7 +34,7 @@ impl Texture {
             label: Some(label),
             size,
             mip_level_count: 1,
-            sample_count: 1,
+            sample_count,
             dimension: wgpu::TextureDimension::D2,
             format: Self::DEPTH_FORMAT,
             usage: wgpu::TextureUsage::RENDER_ATTACHMENT | wgpu::TextureUsage::SAMPLED,
@@ -58,28 +65,54 @@ impl Texture {
         }
     }
 
-    pub fn from_bytes(
+    /// The colour target rendered into when multisampling, resolved into the swap chain
+    /// frame. There is nothing to create when `sample_count` is 1.
+    pub fn create_msaa_framebuffer(
         device: &wgpu::Device,
-        queue: &wgpu::Queue,
-        bytes: &[u8],
-        label: &str,
-        is_normal_map: bool,
-    ) -> Result<Self> {
-        let img = image::load_from_memory(bytes)?;
-        Self::from_image(device, queue, &img, Some(label), is_normal_map)
+        sc_desc: &wgpu::SwapChainDescriptor,
+        sample_count: u32,
+    ) -> Option<Self> {
+        if sample_count <= 1 {
+            return None;
+        }
+
+        let texture = device.create_texture(&wgpu::TextureDescriptor {
+            label: Some("Multisampled framebuffer"),
+            size: wgpu::Extent3d {
+                width: sc_desc.width,
+                height: sc_desc.height,
+                depth_or_array_layers: 1,
+            },
+            mip_level_count: 1,
+            sample_count,
+            dimension: wgpu::TextureDimension::D2,
+            format: sc_desc.format,
+            usage: wgpu::TextureUsage::RENDER_ATTACHMENT,
+        });
+        let view = texture.create_view(&wgpu::TextureViewDescriptor::default());
+        let sampler = device.create_sampler(&wgpu::SamplerDescriptor::default());
+
+        Some(Self {
+            texture,
+            view,
+            sampler,
+        })
     }
 
-    pub fn load<P: AsRef<Path>>(
+    /// A single texel of `colour`, standing in for a texture a material doesn't have.
+    pub fn from_colour(
         device: &wgpu::Device,
         queue: &wgpu::Queue,
-        path: P,
+        colour: [u8; 4],
+        label: &str,
         is_normal_map: bool,
     ) -> Result<Self> {
-        let path_copy = path.as_ref().to_path_buf();
-        let label = path_copy.to_str();
-
-        let img = image::open(path)?;
-        Self::from_image(device, queue, &img, label, is_normal_map)
+        let img = image::DynamicImage::ImageRgba8(image::RgbaImage::from_pixel(
+            1,
+            1,
+            image::Rgba(colour),
+        ));
+        Self::from_image(device, queue, &img, Some(label), is_normal_map)
     }
 
     pub fn from_image(
@@ -89,6 +122,8 @@ impl Texture {
         label: Option<&str>,
         is_normal_map: bool,
     ) -> Result<Self> {
+        profile_scope!("upload texture");
+
         let rgba = img.to_rgba8();
 
         let dimensions = img.dimensions();
@@ -146,4 +181,308 @@ impl Texture {
             sampler,
         })
     }
+
+    pub fn create_cube(
+        device: &wgpu::Device,
+        size: u32,
+        mip_level_count: u32,
+        format: wgpu::TextureFormat,
+        usage: wgpu::TextureUsage,
+        label: Option<&str>,
+    ) -> Self {
+        let texture = device.create_texture(&wgpu::TextureDescriptor {
+            label,
+            size: wgpu::Extent3d {
+                width: size,
+                height: size,
+                depth_or_array_layers: 6,
+            },
+            mip_level_count,
+            sample_count: 1,
+            dimension: wgpu::TextureDimension::D2,
+            format,
+            usage,
+        });
+
+        let view = texture.create_view(&wgpu::TextureViewDescriptor {
+            label,
+            dimension: Some(wgpu::TextureViewDimension::Cube),
+            ..Default::default()
+        });
+
+        let sampler = device.create_sampler(&wgpu::SamplerDescriptor {
+            label: Some("Cube Sampler"),
+            address_mode_u: wgpu::AddressMode::ClampToEdge,
+            address_mode_v: wgpu::AddressMode::ClampToEdge,
+            address_mode_w: wgpu::AddressMode::ClampToEdge,
+            mag_filter: wgpu::FilterMode::Linear,
+            min_filter: wgpu::FilterMode::Linear,
+            mipmap_filter: wgpu::FilterMode::Linear,
+            ..Default::default()
+        });
+
+        Self {
+            texture,
+            view,
+            sampler,
+        }
+    }
+
+    /// A view of a single face (and mip level) of a cube texture, for rendering into.
+    pub fn cube_face_view(&self, face: u32, mip_level: u32) -> wgpu::TextureView {
+        use std::num::NonZeroU32;
+        self.texture.create_view(&wgpu::TextureViewDescriptor {
+            label: Some("Cube face view"),
+            dimension: Some(wgpu::TextureViewDimension::D2),
+            base_mip_level: mip_level,
+            mip_level_count: NonZeroU32::new(1),
+            base_array_layer: face,
+            array_layer_count: NonZeroU32::new(1),
+            ..Default::default()
+        })
+    }
+
+    /// A cube view of a single mip level of a cube texture, for sampling while rendering
+    /// into another level.
+    pub fn cube_mip_view(&self, mip_level: u32) -> wgpu::TextureView {
+        use std::num::NonZeroU32;
+        self.texture.create_view(&wgpu::TextureViewDescriptor {
+            label: Some("Cube mip view"),
+            dimension: Some(wgpu::TextureViewDimension::Cube),
+            base_mip_level: mip_level,
+            mip_level_count: NonZeroU32::new(1),
+            ..Default::default()
+        })
+    }
+
+    /// Loads a cube map from six LDR images, ordered +X, -X, +Y, -Y, +Z, -Z.
+    pub fn load_cube_faces<P: AsRef<Path>>(
+        device: &wgpu::Device,
+        queue: &wgpu::Queue,
+        paths: &[P; 6],
+        label: &str,
+    ) -> Result<Self> {
+        let faces = paths
+            .iter()
+            .map(|path| image::open(path).map(|img| img.to_rgba8()))
+            .collect::<Result<Vec<_>, _>>()?;
+
+        let size = faces[0].width();
+        if faces
+            .iter()
+            .any(|face| face.width() != size || face.height() != size)
+        {
+            bail!("Cube map faces for {} must be square and equally sized", label);
+        }
+
+        let cube = Self::create_cube(
+            device,
+            size,
+            1,
+            wgpu::TextureFormat::Rgba8UnormSrgb,
+            wgpu::TextureUsage::SAMPLED | wgpu::TextureUsage::COPY_DST,
+            Some(label),
+        );
+
+        use std::num::NonZeroU32;
+        for (layer, face) in faces.iter().enumerate() {
+            queue.write_texture(
+                wgpu::ImageCopyTexture {
+                    texture: &cube.texture,
+                    mip_level: 0,
+                    origin: wgpu::Origin3d {
+                        x: 0,
+                        y: 0,
+                        z: layer as u32,
+                    },
+                },
+                face,
+                wgpu::ImageDataLayout {
+                    offset: 0,
+                    bytes_per_row: NonZeroU32::new(4 * size),
+                    rows_per_image: NonZeroU32::new(size),
+                },
+                wgpu::Extent3d {
+                    width: size,
+                    height: size,
+                    depth_or_array_layers: 1,
+                },
+            );
+        }
+
+        Ok(cube)
+    }
+
+    /// Loads an equirectangular Radiance HDR image and projects it onto a cube map on the GPU.
+    pub fn load_equirectangular<P: AsRef<Path>>(
+        device: &wgpu::Device,
+        queue: &wgpu::Queue,
+        path: P,
+        face_size: u32,
+    ) -> Result<Self> {
+        use image::codecs::hdr::HdrDecoder;
+        use std::num::NonZeroU32;
+
+        let label = path.as_ref().to_string_lossy().into_owned();
+        let file = std::io::BufReader::new(std::fs::File::open(path.as_ref())?);
+        let decoder = HdrDecoder::new(file)?;
+        let meta = decoder.metadata();
+        let pixels = decoder
+            .read_image_hdr()?
+            .into_iter()
+            .flat_map(|image::Rgb([r, g, b])| [r, g, b, 1.0])
+            .collect::<Vec<f32>>();
+
+        let src_size = wgpu::Extent3d {
+            width: meta.width,
+            height: meta.height,
+            depth_or_array_layers: 1,
+        };
+        let src = device.create_texture(&wgpu::TextureDescriptor {
+            label: Some(&label),
+            size: src_size,
+            mip_level_count: 1,
+            sample_count: 1,
+            dimension: wgpu::TextureDimension::D2,
+            format: wgpu::TextureFormat::Rgba32Float,
+            usage: wgpu::TextureUsage::SAMPLED | wgpu::TextureUsage::COPY_DST,
+        });
+        queue.write_texture(
+            wgpu::ImageCopyTexture {
+                texture: &src,
+                mip_level: 0,
+                origin: wgpu::Origin3d::ZERO,
+            },
+            bytemuck::cast_slice(&pixels),
+            wgpu::ImageDataLayout {
+                offset: 0,
+                bytes_per_row: NonZeroU32::new(16 * meta.width),
+                rows_per_image: NonZeroU32::new(meta.height),
+            },
+            src_size,
+        );
+        let src_view = src.create_view(&wgpu::TextureViewDescriptor::default());
+
+        let cube = Self::create_cube(
+            device,
+            face_size,
+            1,
+            Self::HDR_CUBE_FORMAT,
+            wgpu::TextureUsage::SAMPLED | wgpu::TextureUsage::RENDER_ATTACHMENT,
+            Some(&label),
+        );
+
+        let bind_group_layout = shader::create_bind_group_layout(
+            device,
+            "Equirectangular bind group layout",
+            shader_interface::EQUIRECT_GROUP,
+        );
+
+        let layout = device.create_pipeline_layout(&wgpu::PipelineLayoutDescriptor {
+            label: Some("Equirectangular pipeline layout"),
+            bind_group_layouts: &[&bind_group_layout],
+            push_constant_ranges: &[],
+        });
+        let pipeline = cube_face_pipeline(
+            device,
+            &layout,
+            Self::HDR_CUBE_FORMAT,
+            ShaderLibrary::embedded().load("equirect.wgsl", &Defines::new())?,
+        );
+
+        let mut encoder = device.create_command_encoder(&wgpu::CommandEncoderDescriptor {
+            label: Some("Equirectangular Encoder"),
+        });
+        for face in 0..6 {
+            let face_buffer = face_uniform_buffer(device, face, 0.0);
+            let bind_group = device.create_bind_group(&wgpu::BindGroupDescriptor {
+                label: None,
+                layout: &bind_group_layout,
+                entries: &[
+                    wgpu::BindGroupEntry {
+                        binding: 0,
+                        resource: wgpu::BindingResource::TextureView(&src_view),
+                    },
+                    wgpu::BindGroupEntry {
+                        binding: 1,
+                        resource: face_buffer.as_entire_binding(),
+                    },
+                ],
+            });
+
+            let view = cube.cube_face_view(face, 0);
+            let mut pass = encoder.begin_render_pass(&wgpu::RenderPassDescriptor {
+                label: Some("Equirectangular face pass"),
+                color_attachments: &[wgpu::RenderPassColorAttachment {
+                    view: &view,
+                    resolve_target: None,
+                    ops: wgpu::Operations {
+                        load: wgpu::LoadOp::Clear(wgpu::Color::BLACK),
+                        store: true,
+                    },
+                }],
+                depth_stencil_attachment: None,
+            });
+            pass.set_pipeline(&pipeline);
+            pass.set_bind_group(0, &bind_group, &[]);
+            pass.draw(0..3, 0..1);
+        }
+        queue.submit(std::iter::once(encoder.finish()));
+
+        Ok(cube)
+    }
+}
+
+/// Uniform handed to the cube face shaders, selecting which face is being rendered.
+#[repr(C)]
+#[derive(Debug, Clone, Copy, bytemuck::Pod, bytemuck::Zeroable)]
+pub(crate) struct FaceUniform {
+    face: u32,
+    roughness: f32,
+    _padding: [f32; 2],
+}
+
+pub(crate) fn face_uniform_buffer(device: &wgpu::Device, face: u32, roughness: f32) -> wgpu::Buffer {
+    use wgpu::util::DeviceExt;
+    device.create_buffer_init(&wgpu::util::BufferInitDescriptor {
+        label: Some("Cube face uniform buffer"),
+        contents: bytemuck::bytes_of(&FaceUniform {
+            face,
+            roughness,
+            _padding: [0.0; 2],
+        }),
+        usage: wgpu::BufferUsage::UNIFORM,
+    })
+}
+
+/// Builds a pipeline that draws a single full screen triangle into one face of a cube map.
+pub(crate) fn cube_face_pipeline(
+    device: &wgpu::Device,
+    layout: &wgpu::PipelineLayout,
+    format: wgpu::TextureFormat,
+    shader: wgpu::ShaderModuleDescriptor,
+) -> wgpu::RenderPipeline {
+    let shader = device.create_shader_module(&shader);
+
+    device.create_render_pipeline(&wgpu::RenderPipelineDescriptor {
+        label: Some("Cube face pipeline"),
+        layout: Some(layout),
+        vertex: wgpu::VertexState {
+            module: &shader,
+            entry_point: "main",
+            buffers: &[],
+        },
+        fragment: Some(wgpu::FragmentState {
+            module: &shader,
+            entry_point: "main",
+            targets: &[wgpu::ColorTargetState {
+                format,
+                blend: None,
+                write_mask: wgpu::ColorWrite::ALL,
+            }],
+        }),
+        primitive: wgpu::PrimitiveState::default(),
+        depth_stencil: None,
+        multisample: wgpu::MultisampleState::default(),
+    })
 }
@@ -0,0 +1,209 @@
+//! Debug views of the model. Each mode other than `Shaded` swaps the model shader's
+//! lighting for a visualisation of one of its inputs. Wireframe uses line polygon mode
+//! when the adapter has it, and otherwise an edge test on barycentric coordinates.
+
+use anyhow::Result;
+use wgpu::util::DeviceExt;
+
+use crate::instance::InstanceRaw;
+use crate::model::Model;
+use crate::preprocessor::Defines;
+use crate::shader::{self, ShaderLibrary};
+use crate::shader_interface;
+use crate::texture::Texture;
+use crate::vertex::Vertex;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ViewMode {
+    Shaded,
+    Wireframe,
+    Normals,
+    Tangents,
+    Bitangents,
+    UvChecker,
+    NormalMap,
+    Depth,
+    LightingOnly,
+}
+
+impl ViewMode {
+    pub const ALL: [ViewMode; 9] = [
+        ViewMode::Shaded,
+        ViewMode::Wireframe,
+        ViewMode::Normals,
+        ViewMode::Tangents,
+        ViewMode::Bitangents,
+        ViewMode::UvChecker,
+        ViewMode::NormalMap,
+        ViewMode::Depth,
+        ViewMode::LightingOnly,
+    ];
+
+    pub fn name(self) -> &'static str {
+        match self {
+            ViewMode::Shaded => "Shaded",
+            ViewMode::Wireframe => "Wireframe",
+            ViewMode::Normals => "Normals",
+            ViewMode::Tangents => "Tangents",
+            ViewMode::Bitangents => "Bitangents",
+            ViewMode::UvChecker => "UV checker",
+            ViewMode::NormalMap => "Normal map",
+            ViewMode::Depth => "Depth",
+            ViewMode::LightingOnly => "Lighting only",
+        }
+    }
+
+    /// The model shader toggle that selects this view.
+    pub fn define(self) -> Option<&'static str> {
+        match self {
+            ViewMode::Shaded => None,
+            ViewMode::Wireframe => Some("VIEW_WIREFRAME"),
+            ViewMode::Normals => Some("VIEW_NORMALS"),
+            ViewMode::Tangents => Some("VIEW_TANGENTS"),
+            ViewMode::Bitangents => Some("VIEW_BITANGENTS"),
+            ViewMode::UvChecker => Some("VIEW_UV_CHECKER"),
+            ViewMode::NormalMap => Some("VIEW_NORMAL_MAP"),
+            ViewMode::Depth => Some("VIEW_DEPTH"),
+            ViewMode::LightingOnly => Some("VIEW_LIGHTING"),
+        }
+    }
+
+    pub fn next(self) -> Self {
+        let index = Self::ALL.iter().position(|&mode| mode == self).unwrap_or(0);
+        Self::ALL[(index + 1) % Self::ALL.len()]
+    }
+}
+
+#[repr(C)]
+#[derive(Copy, Clone, Debug, bytemuck::Pod, bytemuck::Zeroable)]
+struct WireframeVertex {
+    position: [f32; 3],
+    barycentric: [f32; 3],
+}
+
+const _: [(); std::mem::size_of::<WireframeVertex>()] =
+    [(); shader_interface::stride(shader_interface::WIREFRAME_VERTEX)];
+
+impl Vertex for WireframeVertex {
+    fn desc<'a>() -> wgpu::VertexBufferLayout<'a> {
+        const ATTRIBUTES: [wgpu::VertexAttribute; shader_interface::WIREFRAME_VERTEX.len()] =
+            shader::vertex_attributes(shader_interface::WIREFRAME_VERTEX);
+
+        wgpu::VertexBufferLayout {
+            array_stride: std::mem::size_of::<WireframeVertex>() as wgpu::BufferAddress,
+            step_mode: wgpu::InputStepMode::Vertex,
+            attributes: &ATTRIBUTES,
+        }
+    }
+}
+
+/// The wireframe fallback. Each mesh is copied without an index buffer, as a corner shared
+/// between triangles needs a different barycentric coordinate in each of them.
+pub struct BarycentricWireframe {
+    pipeline: wgpu::RenderPipeline,
+    /// One vertex buffer and its vertex count per mesh of the model.
+    meshes: Vec<(wgpu::Buffer, u32)>,
+}
+
+impl BarycentricWireframe {
+    pub fn new(
+        device: &wgpu::Device,
+        uniform_bind_group_layout: &wgpu::BindGroupLayout,
+        colour_format: wgpu::TextureFormat,
+        sample_count: u32,
+        model: &Model,
+    ) -> Result<Self> {
+        profile_scope!("BarycentricWireframe::new");
+        let layout = device.create_pipeline_layout(&wgpu::PipelineLayoutDescriptor {
+            label: Some("Wireframe pipeline layout"),
+            bind_group_layouts: &[uniform_bind_group_layout],
+            push_constant_ranges: &[],
+        });
+
+        let shader = device.create_shader_module(
+            &ShaderLibrary::embedded()
+                .load("wireframe.wgsl", &Defines::new())?,
+        );
+
+        let pipeline = device.create_render_pipeline(&wgpu::RenderPipelineDescriptor {
+            label: Some("Wireframe pipeline"),
+            layout: Some(&layout),
+            vertex: wgpu::VertexState {
+                module: &shader,
+                entry_point: "main",
+                buffers: &[WireframeVertex::desc(), InstanceRaw::desc()],
+            },
+            fragment: Some(wgpu::FragmentState {
+                module: &shader,
+                entry_point: "main",
+                targets: &[wgpu::ColorTargetState {
+                    format: colour_format,
+                    blend: None,
+                    write_mask: wgpu::ColorWrite::ALL,
+                }],
+            }),
+            primitive: wgpu::PrimitiveState {
+                topology: wgpu::PrimitiveTopology::TriangleList,
+                strip_index_format: None,
+                front_face: wgpu::FrontFace::Ccw,
+                cull_mode: None,
+                polygon_mode: wgpu::PolygonMode::Fill,
+                clamp_depth: false,
+                conservative: false,
+            },
+            depth_stencil: Some(wgpu::DepthStencilState {
+                format: Texture::DEPTH_FORMAT,
+                depth_write_enabled: true,
+                depth_compare: wgpu::CompareFunction::LessEqual,
+                stencil: wgpu::StencilState::default(),
+                bias: wgpu::DepthBiasState::default(),
+            }),
+            multisample: wgpu::MultisampleState {
+                count: sample_count,
+                mask: !0,
+                alpha_to_coverage_enabled: false,
+            },
+        });
+
+        let corners = [[1.0, 0.0, 0.0], [0.0, 1.0, 0.0], [0.0, 0.0, 1.0]];
+        let meshes = model
+            .meshes
+            .iter()
+            .map(|mesh| {
+                let vertices = mesh
+                    .indices
+                    .iter()
+                    .zip(corners.iter().cycle())
+                    .map(|(&index, &barycentric)| WireframeVertex {
+                        position: mesh.positions[index as usize],
+                        barycentric,
+                    })
+                    .collect::<Vec<_>>();
+                let buffer = device.create_buffer_init(&wgpu::util::BufferInitDescriptor {
+                    label: Some(&format!("{} wireframe vertex buffer", mesh.name)),
+                    contents: bytemuck::cast_slice(&vertices),
+                    usage: wgpu::BufferUsage::VERTEX,
+                });
+                (buffer, vertices.len() as u32)
+            })
+            .collect();
+
+        Ok(Self { pipeline, meshes })
+    }
+
+    pub fn draw<'a>(
+        &'a self,
+        render_pass: &mut wgpu::RenderPass<'a>,
+        instance_buffer: &'a wgpu::Buffer,
+        uniform_bind_group: &'a wgpu::BindGroup,
+        instances: u32,
+    ) {
+        render_pass.set_pipeline(&self.pipeline);
+        render_pass.set_bind_group(0, uniform_bind_group, &[]);
+        render_pass.set_vertex_buffer(1, instance_buffer.slice(..));
+        for (buffer, vertices) in self.meshes.iter() {
+            render_pass.set_vertex_buffer(0, buffer.slice(..));
+            render_pass.draw(0..*vertices, 0..instances);
+        }
+    }
+}